@@ -376,12 +376,11 @@ int ksu_handle_prctl(int option, unsigned long arg2, unsigned long arg3,
 		return 0;
 	}
 
-	// all other cmds are for 'root manager'
-	if (!from_manager) {
+	// all other cmds are for 'root manager' and ksud
+	if (!from_manager && !from_root) {
 		return 0;
 	}
 
-	// we are already manager
 	if (arg2 == CMD_GET_APP_PROFILE) {
 		struct app_profile profile;
 		if (copy_from_user(&profile, arg3, sizeof(profile))) {
@@ -390,11 +389,20 @@ int ksu_handle_prctl(int option, unsigned long arg2, unsigned long arg3,
 		}
 
 		bool success = ksu_get_app_profile(&profile);
-		if (success) {
-			if (copy_to_user(arg3, &profile, sizeof(profile))) {
-				pr_err("copy profile failed\n");
+		if (success && copy_to_user(arg3, &profile, sizeof(profile))) {
+			pr_err("copy profile failed\n");
+			return 0;
+		}
+		// if arg4 is given, tell whether the profile exists there and reply ok,
+		// so that a missing profile is not mistaken for a refused call
+		if (arg4) {
+			if (copy_to_user(arg4, &success, sizeof(success))) {
+				pr_err("prctl copy err, cmd: %lu\n", arg2);
 				return 0;
 			}
+			success = true;
+		}
+		if (success) {
 			if (copy_to_user(result, &reply_ok, sizeof(reply_ok))) {
 				pr_err("prctl reply error, cmd: %lu\n", arg2);
 			}
@@ -0,0 +1,263 @@
+use anyhow::{bail, ensure, Context, Result};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::defs;
+use crate::ksucalls::{AppProfile, KSU_APP_PROFILE_VER};
+use crate::output;
+use crate::profile::Profile;
+
+// keep in sync with kernel/allowlist.c
+const FILE_MAGIC: u32 = 0x7f4b5355;
+const FILE_FORMAT_VERSION: u32 = 3;
+
+// version 1 profiles share the layout of version 2, but the kernel refuses them now
+const MIN_APP_PROFILE_VER: u32 = 1;
+
+fn allowlist_path(file: Option<PathBuf>) -> PathBuf {
+    file.unwrap_or_else(|| defs::resolve(defs::ALLOWLIST_PATH))
+}
+
+/// Decode the allowlist persisted by kernel, unknown profile versions are skipped.
+pub fn read_allowlist<R: Read>(mut reader: R) -> Result<Vec<AppProfile>> {
+    let mut header = [0u8; 4];
+    reader
+        .read_exact(&mut header)
+        .context("Failed to read magic")?;
+    let magic = u32::from_le_bytes(header);
+    ensure!(magic == FILE_MAGIC, "Invalid allowlist magic: {magic:#x}");
+
+    reader
+        .read_exact(&mut header)
+        .context("Failed to read file version")?;
+    let version = u32::from_le_bytes(header);
+    ensure!(
+        version == FILE_FORMAT_VERSION,
+        "Unsupported allowlist file version {version}, expected {FILE_FORMAT_VERSION}"
+    );
+
+    let mut data = Vec::new();
+    reader.read_to_end(&mut data)?;
+    let chunks = data.chunks_exact(AppProfile::SIZE);
+    if !chunks.remainder().is_empty() {
+        log::warn!(
+            "allowlist has {} trailing bytes, ignored",
+            chunks.remainder().len()
+        );
+    }
+
+    let mut profiles = Vec::new();
+    for chunk in chunks {
+        let mut profile = AppProfile::from_bytes(chunk)?;
+        if !(MIN_APP_PROFILE_VER..=KSU_APP_PROFILE_VER).contains(&profile.version) {
+            log::warn!(
+                "skip profile {} with unknown version {}",
+                profile.key(),
+                profile.version
+            );
+            continue;
+        }
+        profile.version = KSU_APP_PROFILE_VER;
+        profiles.push(profile);
+    }
+    Ok(profiles)
+}
+
+/// Encode profiles in the same format as kernel's `do_save_allow_list`.
+pub fn write_allowlist<W: Write>(mut writer: W, profiles: &[AppProfile]) -> Result<()> {
+    writer.write_all(&FILE_MAGIC.to_le_bytes())?;
+    writer.write_all(&FILE_FORMAT_VERSION.to_le_bytes())?;
+    for profile in profiles {
+        writer.write_all(&profile.to_bytes())?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+pub fn load_allowlist(path: &Path) -> Result<Vec<AppProfile>> {
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    read_allowlist(BufReader::new(file))
+}
+
+/// Replace the allowlist at <path>, it is left untouched if anything fails
+pub fn save_allowlist(path: &Path, profiles: &[AppProfile]) -> Result<()> {
+    let dir = path
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let mut tmp = tempfile::NamedTempFile::new_in(dir)
+        .with_context(|| format!("Failed to create {}", path.display()))?;
+    write_allowlist(BufWriter::new(tmp.as_file_mut()), profiles)?;
+    tmp.as_file().sync_all()?;
+    tmp.persist(path)
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+    Ok(())
+}
+
+// same as forbid_system_uid() in kernel/allowlist.c
+fn check_uid(uid: i32) -> Result<()> {
+    const SHELL_UID: i32 = 2000;
+    const SYSTEM_UID: i32 = 1000;
+    ensure!(
+        uid >= SHELL_UID || uid == SYSTEM_UID,
+        "uid lower than 2000 is unsupported: {uid}"
+    );
+    Ok(())
+}
+
+pub fn dump(file: Option<PathBuf>) -> Result<()> {
+    let path = allowlist_path(file);
+    let profiles: Vec<Profile> = load_allowlist(&path)?.iter().map(Profile::from).collect();
+
+    crate::profile::print_profiles(&profiles)
+}
+
+pub fn import(json: PathBuf, file: Option<PathBuf>, merge: bool) -> Result<()> {
+    let content = std::fs::read_to_string(&json)
+        .with_context(|| format!("Failed to read {}", json.display()))?;
+    let mut imported: serde_json::Value =
+        serde_json::from_str(&content).context("Invalid profile json")?;
+    // the result event of `ksud --format json allowlist dump`
+    if let Some(data) = imported.get_mut("data") {
+        imported = data.take();
+    }
+    let imported: Vec<Profile> =
+        serde_json::from_value(imported).context("Invalid profile json")?;
+
+    let path = allowlist_path(file);
+    let mut profiles = if merge && path.exists() {
+        load_allowlist(&path)?
+    } else {
+        Vec::new()
+    };
+
+    for profile in &imported {
+        if profile.key.is_empty() {
+            bail!("profile of uid {} has no key", profile.current_uid);
+        }
+        check_uid(profile.current_uid)?;
+        let app = profile.to_app_profile()?;
+        match profiles
+            .iter_mut()
+            .find(|p| p.current_uid == app.current_uid && p.key() == profile.key)
+        {
+            Some(existing) => *existing = app,
+            None => profiles.push(app),
+        }
+    }
+
+    save_allowlist(&path, &profiles)?;
+    output::progress(format!(
+        "Imported {} profiles to {}",
+        imported.len(),
+        path.display()
+    ));
+    if !defs::has_custom_root() && path == Path::new(defs::ALLOWLIST_PATH) {
+        output::progress("Reboot to apply, kernel overwrites the file when grants change");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ksucalls::DEFAULT_SELINUX_DOMAIN;
+
+    fn root_profile() -> AppProfile {
+        let mut app = AppProfile::new("com.example.root", 10100).unwrap();
+        app.allow_su = true;
+        let config = app.root_config_mut();
+        config.set_template_name("tmpl").unwrap();
+        config.profile.uid = 0;
+        config.profile.gid = 0;
+        config.profile.set_groups(&[1004, 3003]).unwrap();
+        config.profile.capabilities.effective = 1 << 21;
+        config
+            .profile
+            .set_selinux_domain(DEFAULT_SELINUX_DOMAIN)
+            .unwrap();
+        config.profile.namespaces = 2;
+        app
+    }
+
+    fn non_root_profile() -> AppProfile {
+        let mut app = AppProfile::new("com.example.app", 10101).unwrap();
+        app.non_root_config_mut().profile.umount_modules = true;
+        app
+    }
+
+    #[test]
+    fn allowlist_round_trip() {
+        let mut buf = Vec::new();
+        write_allowlist(&mut buf, &[root_profile(), non_root_profile()]).unwrap();
+        assert_eq!(buf.len(), 8 + 2 * AppProfile::SIZE);
+        let profiles = read_allowlist(buf.as_slice()).unwrap();
+        assert_eq!(profiles.len(), 2);
+        assert_eq!(profiles[0].key(), "com.example.root");
+        assert_eq!(profiles[1].current_uid, 10101);
+    }
+
+    #[test]
+    fn allowlist_rejects_unknown_formats() {
+        let mut buf = Vec::new();
+        write_allowlist(&mut buf, &[root_profile()]).unwrap();
+
+        let mut bad_magic = buf.clone();
+        bad_magic[0] ^= 0xff;
+        assert!(read_allowlist(bad_magic.as_slice()).is_err());
+
+        let mut bad_version = buf.clone();
+        bad_version[4..8].copy_from_slice(&(FILE_FORMAT_VERSION + 1).to_le_bytes());
+        assert!(read_allowlist(bad_version.as_slice()).is_err());
+
+        // unknown profile versions are skipped
+        let mut future = buf.clone();
+        future[8..12].copy_from_slice(&(KSU_APP_PROFILE_VER + 1).to_ne_bytes());
+        assert!(read_allowlist(future.as_slice()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn save_replaces_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(".allowlist");
+        save_allowlist(&path, &[root_profile(), non_root_profile()]).unwrap();
+        save_allowlist(&path, &[non_root_profile()]).unwrap();
+        let profiles = load_allowlist(&path).unwrap();
+        assert_eq!(profiles.len(), 1);
+        assert_eq!(profiles[0].key(), "com.example.app");
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn system_uids_are_rejected() {
+        assert!(check_uid(0).is_err());
+        assert!(check_uid(1999).is_err());
+        assert!(check_uid(1000).is_ok());
+        assert!(check_uid(2000).is_ok());
+        assert!(check_uid(10100).is_ok());
+    }
+
+    #[test]
+    fn import_json_dumps() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(".allowlist");
+        let dumped: Vec<Profile> = [root_profile(), non_root_profile()]
+            .iter()
+            .map(Profile::from)
+            .collect();
+        let plain = dir.path().join("plain.json");
+        std::fs::write(&plain, serde_json::to_string(&dumped).unwrap()).unwrap();
+        let event = dir.path().join("event.json");
+        let data = serde_json::json!({ "type": "result", "ok": true, "data": dumped });
+        std::fs::write(&event, data.to_string()).unwrap();
+
+        for json in [plain, event] {
+            import(json, Some(path.clone()), false).unwrap();
+            let profiles = load_allowlist(&path).unwrap();
+            assert_eq!(profiles.len(), 2);
+            assert_eq!(profiles[0].key(), "com.example.root");
+            assert!(profiles[0].allow_su);
+        }
+    }
+}
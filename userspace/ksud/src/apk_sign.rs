@@ -1,6 +1,9 @@
 use anyhow::{ensure, Result};
+use sha2::{Digest, Sha256};
 use std::io::{Read, Seek, SeekFrom};
 
+use crate::output;
+
 pub fn get_apk_signature(apk: &str) -> Result<(u32, String)> {
     let mut buffer = [0u8; 0x10];
     let mut size4 = [0u8; 4];
@@ -22,7 +25,7 @@ pub fn get_apk_signature(apk: &str) -> Result<(u32, String)> {
 
             if u32::from_le_bytes(size4) ^ 0xcafe_babe_u32 == 0xccfb_f1ee_u32 {
                 if i > 0 {
-                    println!("warning: comment length is {i}");
+                    output::progress(format!("warning: comment length is {i}"));
                 }
                 break;
             }
@@ -111,5 +114,5 @@ fn calc_cert_sha256(
     f.read_exact(&mut cert)?;
     *offset += cert_len;
 
-    Ok((cert_len, sha256::digest(&cert)))
+    Ok((cert_len, format!("{:x}", Sha256::digest(&cert))))
 }
@@ -3,7 +3,7 @@ use const_format::concatcp;
 use rust_embed::RustEmbed;
 use std::path::Path;
 
-use crate::{defs::BINARY_DIR, utils};
+use crate::{defs, defs::BINARY_DIR, utils};
 
 pub const RESETPROP_PATH: &str = concatcp!(BINARY_DIR, "resetprop");
 pub const BUSYBOX_PATH: &str = concatcp!(BINARY_DIR, "busybox");
@@ -22,7 +22,8 @@ pub fn ensure_binaries(ignore_if_exist: bool) -> Result<()> {
             continue;
         }
         let asset = Asset::get(&file).ok_or(anyhow::anyhow!("asset not found: {}", file))?;
-        utils::ensure_binary(format!("{BINARY_DIR}{file}"), &asset.data, ignore_if_exist)?
+        let path = defs::resolve(BINARY_DIR).join(file.as_ref());
+        utils::ensure_binary(path, &asset.data, ignore_if_exist)?
     }
     Ok(())
 }
@@ -0,0 +1,609 @@
+use anyhow::{ensure, Context, Result};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{Read, Seek, Write};
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+use crate::ksucalls::KernelInterface;
+use crate::restorecon::{lgetfilecon, lsetfilecon, SYSTEM_CON};
+use crate::utils::{ensure_clean_dir, ensure_dir_exists};
+use crate::{allowlist, defs, ksucalls, module, module_prop, output, packages, unzip};
+
+const BACKUP_FORMAT_VERSION: u32 = 1;
+const MANIFEST_NAME: &str = "backup.json";
+const ALLOWLIST_NAME: &str = "allowlist";
+const REPLACE_DIR_XATTR: &str = "trusted.overlay.opaque";
+
+// files in a module dir which are state of the installation rather than content
+const STATE_FILES: [&str; 5] = [
+    defs::DISABLE_FILE_NAME,
+    defs::INCOMPATIBLE_FILE_NAME,
+    defs::UPDATE_FILE_NAME,
+    defs::REMOVE_FILE_NAME,
+    defs::PREVIOUS_MODULE_PROP,
+];
+
+/// Attributes of a file in a module which can't be kept in zip
+#[derive(Debug, Serialize, Deserialize)]
+struct FileMeta {
+    path: String,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    context: String,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    whiteout: bool,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    replace: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ModuleMeta {
+    id: String,
+    enabled: bool,
+    remove: bool,
+    files: Vec<FileMeta>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Manifest {
+    version: u32,
+    ksud_version: String,
+    created: String,
+    modules: Vec<ModuleMeta>,
+}
+
+fn file_options(mode: u32) -> SimpleFileOptions {
+    SimpleFileOptions::default()
+        .compression_method(CompressionMethod::Deflated)
+        .unix_permissions(mode & 0o7777)
+}
+
+// add everything under <dir> to <zip> at <prefix>, returns attributes of all entries
+fn zip_dir<W: Write + Seek>(
+    zip: &mut ZipWriter<W>,
+    dir: &Path,
+    prefix: &str,
+    skip: &dyn Fn(&Path, &fs::Metadata) -> bool,
+) -> Result<Vec<FileMeta>> {
+    let mut files = Vec::new();
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(current) = pending.pop() {
+        let mut entries = fs::read_dir(&current)?.flatten().collect::<Vec<_>>();
+        entries.sort_by_key(|e| e.file_name());
+        for entry in entries {
+            let path = entry.path();
+            let metadata = fs::symlink_metadata(&path)?;
+            let rel = path.strip_prefix(dir)?;
+            if skip(rel, &metadata) {
+                continue;
+            }
+            let rel = rel.to_string_lossy().to_string();
+            let name = format!("{prefix}{rel}");
+            let file_type = metadata.file_type();
+            let mut meta = FileMeta {
+                path: rel,
+                mode: metadata.mode() & 0o7777,
+                uid: metadata.uid(),
+                gid: metadata.gid(),
+                context: lgetfilecon(&path).unwrap_or_default(),
+                whiteout: false,
+                replace: false,
+            };
+
+            if file_type.is_dir() {
+                meta.replace = extattr::lgetxattr(&path, REPLACE_DIR_XATTR)
+                    .is_ok_and(|v| String::from_utf8_lossy(&v) == "y");
+                zip.add_directory(name, file_options(metadata.mode()))?;
+                pending.push(path);
+            } else if file_type.is_symlink() {
+                let target = fs::read_link(&path)?;
+                zip.add_symlink(
+                    name,
+                    target.to_string_lossy(),
+                    file_options(metadata.mode()),
+                )?;
+            } else if file_type.is_file() {
+                zip.start_file(name, file_options(metadata.mode()))?;
+                std::io::copy(&mut File::open(&path)?, zip)?;
+            } else if file_type.is_char_device() && metadata.rdev() == 0 {
+                meta.whiteout = true;
+            } else {
+                warn!("skip unsupported file {}", path.display());
+                continue;
+            }
+            files.push(meta);
+        }
+    }
+    Ok(files)
+}
+
+// extract entries of <archive> under <prefix> into <dir>, files of the same name are replaced
+fn unzip_dir<R: Read + Seek>(
+    archive: &mut ZipArchive<R>,
+    checked: &unzip::Checked,
+    prefix: &str,
+    dir: &Path,
+) -> Result<()> {
+    let checked = checked.subdir(prefix)?;
+    ensure_dir_exists(dir)?;
+    // extracted aside first, unzip::extract never writes into existing files
+    let tmp = tempfile::tempdir_in(dir)?;
+    unzip::extract(archive, &checked, tmp.path())?;
+    for entry in fs::read_dir(tmp.path())? {
+        let entry = entry?;
+        fs::rename(entry.path(), dir.join(entry.file_name()))?;
+    }
+    Ok(())
+}
+
+// restore attributes which zip doesn't keep
+fn apply_file_meta(dir: &Path, files: &[FileMeta]) -> Result<()> {
+    for meta in files {
+        ensure!(
+            !meta.path.starts_with('/') && !meta.path.split('/').any(|c| c == ".."),
+            "invalid path in backup: {}",
+            meta.path
+        );
+        let path = dir.join(&meta.path);
+        if meta.whiteout {
+            rustix::fs::mknodat(
+                rustix::fs::CWD,
+                &path,
+                rustix::fs::FileType::CharacterDevice,
+                rustix::fs::Mode::from_raw_mode(meta.mode & 0o1777),
+                0,
+            )
+            .with_context(|| format!("Failed to create whiteout {}", path.display()))?;
+        }
+        if meta.replace {
+            extattr::lsetxattr(&path, REPLACE_DIR_XATTR, "y", extattr::Flags::empty())
+                .with_context(|| format!("Failed to mark {} replaced", path.display()))?;
+        }
+        std::os::unix::fs::lchown(&path, Some(meta.uid), Some(meta.gid))?;
+        let context = if meta.context.is_empty() {
+            SYSTEM_CON
+        } else {
+            &meta.context
+        };
+        lsetfilecon(&path, context)?;
+    }
+    Ok(())
+}
+
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+// installation of the exported zip reproduces what the original installation left
+fn export_customize_script(files: &[FileMeta]) -> String {
+    let mut script = String::from(
+        "# generated by ksud module export\n\
+         SKIPUNZIP=1\n\
+         ui_print \"- Extracting module files\"\n\
+         unzip -o \"$ZIPFILE\" -x 'META-INF/*' customize.sh -d \"$MODPATH\" >&2\n",
+    );
+    let mut replace = Vec::new();
+    let mut remove = Vec::new();
+    for meta in files {
+        let path = format!("\"$MODPATH\"/{}", shell_quote(&meta.path));
+        if meta.whiteout {
+            remove.push(format!("/{}", meta.path));
+            continue;
+        }
+        if meta.replace {
+            replace.push(format!("/{}", meta.path));
+        }
+        script.push_str(&format!("chmod {:o} {path}\n", meta.mode));
+        if meta.uid != 0 || meta.gid != 0 {
+            script.push_str(&format!("chown -h {}:{} {path}\n", meta.uid, meta.gid));
+        }
+        if !meta.context.is_empty() && meta.context != SYSTEM_CON {
+            script.push_str(&format!("chcon -h {} {path}\n", meta.context));
+        }
+    }
+    script.push_str(&format!("REPLACE=\"\n{}\n\"\n", replace.join("\n")));
+    script.push_str(&format!("REMOVE=\"\n{}\n\"\n", remove.join("\n")));
+    script
+}
+
+pub fn export_module(id: &str, output: &Path) -> Result<()> {
+    let module_dir = defs::resolve(defs::MODULE_DIR).join(id);
+    ensure!(module_dir.is_dir(), "Module {id} is not installed");
+    ensure!(
+        !module_dir.join(defs::UPDATE_FILE_NAME).exists(),
+        "Module {id} has a pending update, reboot first"
+    );
+
+    let mut zip = ZipWriter::new(File::create(output)?);
+    let files = zip_dir(&mut zip, &module_dir, "", &|rel, metadata| {
+        // partition symlinks are created by the installer again
+        rel.parent() == Some(Path::new(""))
+            && (metadata.is_symlink() || STATE_FILES.iter().any(|f| rel == Path::new(f)))
+    })?;
+    zip.start_file("customize.sh", file_options(0o644))?;
+    zip.write_all(export_customize_script(&files).as_bytes())?;
+    zip.finish()?;
+
+    output::progress(format!("Exported {id} to {}", output.display()));
+    Ok(())
+}
+
+fn backup_dir_files<W: Write + Seek>(
+    zip: &mut ZipWriter<W>,
+    root: &Path,
+    dir: &str,
+    prefix: &str,
+) -> Result<()> {
+    let dir = defs::resolve_in(root, Path::new(dir));
+    if dir.is_dir() {
+        zip_dir(zip, &dir, prefix, &|_, _| false)?;
+    }
+    Ok(())
+}
+
+pub fn create_backup(output: &Path) -> Result<()> {
+    create_backup_in(defs::root(), output)
+}
+
+fn create_backup_in(root: &Path, output: &Path) -> Result<()> {
+    let mut zip = ZipWriter::new(File::create(output)?);
+    let mut modules = Vec::new();
+
+    let modules_dir = defs::resolve_in(root, Path::new(defs::MODULE_DIR));
+    if modules_dir.is_dir() {
+        let mut entries = fs::read_dir(&modules_dir)?.flatten().collect::<Vec<_>>();
+        entries.sort_by_key(|e| e.file_name());
+        for entry in entries {
+            let path = entry.path();
+            let id = entry.file_name().to_string_lossy().to_string();
+            if !path.is_dir() {
+                continue;
+            }
+            // a pending update is what runs after reboot
+            let update_dir = defs::resolve_in(root, Path::new(defs::MODULE_UPDATE_DIR)).join(&id);
+            let source = if path.join(defs::UPDATE_FILE_NAME).exists() && update_dir.is_dir() {
+                update_dir
+            } else {
+                path.clone()
+            };
+            let files = zip_dir(&mut zip, &source, &format!("modules/{id}/"), &|rel, _| {
+                STATE_FILES.iter().any(|f| rel == Path::new(f))
+            })?;
+            // state of the module is kept in manifest instead
+            for (flag, set) in [
+                (
+                    defs::DISABLE_FILE_NAME,
+                    path.join(defs::DISABLE_FILE_NAME).exists(),
+                ),
+                (
+                    defs::REMOVE_FILE_NAME,
+                    path.join(defs::REMOVE_FILE_NAME).exists(),
+                ),
+            ] {
+                if set {
+                    zip.start_file(format!("modules/{id}/{flag}"), file_options(0o644))?;
+                }
+            }
+            output::progress(format!("Backup module {id}"));
+            modules.push(ModuleMeta {
+                enabled: !path.join(defs::DISABLE_FILE_NAME).exists(),
+                remove: path.join(defs::REMOVE_FILE_NAME).exists(),
+                id,
+                files,
+            });
+        }
+    }
+
+    backup_dir_files(
+        &mut zip,
+        root,
+        defs::PROFILE_SELINUX_DIR,
+        "profile/selinux/",
+    )?;
+    backup_dir_files(
+        &mut zip,
+        root,
+        defs::PROFILE_TEMPLATE_DIR,
+        "profile/templates/",
+    )?;
+
+    let allowlist = defs::resolve_in(root, Path::new(defs::ALLOWLIST_PATH));
+    if allowlist.exists() {
+        zip.start_file(ALLOWLIST_NAME, file_options(0o644))?;
+        std::io::copy(&mut File::open(&allowlist)?, &mut zip)?;
+        output::progress("Backup allowlist");
+    }
+
+    let manifest = Manifest {
+        version: BACKUP_FORMAT_VERSION,
+        ksud_version: defs::VERSION_CODE.trim().to_string(),
+        created: chrono::Local::now().to_rfc3339(),
+        modules,
+    };
+    zip.start_file(MANIFEST_NAME, file_options(0o644))?;
+    zip.write_all(serde_json::to_string_pretty(&manifest)?.as_bytes())?;
+    zip.finish()?;
+
+    output::progress(format!("Backup created: {}", output.display()));
+    Ok(())
+}
+
+// uids of apps differ between devices, map them by package name.
+// the profiles are written to the allowlist of <root> if there is no <kernel> to take them.
+fn restore_allowlist<R: Read + Seek>(
+    archive: &mut ZipArchive<R>,
+    root: &Path,
+    kernel: Option<&dyn KernelInterface>,
+) -> Result<()> {
+    let Ok(file) = archive.by_name(ALLOWLIST_NAME) else {
+        return Ok(());
+    };
+    let mut profiles = allowlist::read_allowlist(file)?;
+    match packages::Packages::load_in(root) {
+        Ok(packages) => {
+            for profile in &mut profiles {
+                let user_id = profile.current_uid / packages::PER_USER_RANGE;
+                match packages.uid_of(&profile.key(), user_id) {
+                    Ok(uid) => profile.current_uid = uid,
+                    Err(e) => warn!("{e}, keep uid {}", profile.current_uid),
+                }
+            }
+        }
+        Err(e) => warn!("keep uids of the backup: {e:?}"),
+    }
+
+    let Some(kernel) = kernel else {
+        let allowlist = defs::resolve_in(root, Path::new(defs::ALLOWLIST_PATH));
+        allowlist::save_allowlist(&allowlist, &profiles)?;
+        output::progress(format!("Restored {} app profiles", profiles.len()));
+        return Ok(());
+    };
+    // kernel keeps the allowlist in memory and persists it itself
+    let mut failed = 0;
+    for profile in &profiles {
+        if let Err(e) = kernel.set_app_profile(profile) {
+            warn!("Failed to restore profile of {}: {:#}", profile.key(), e);
+            failed += 1;
+        }
+    }
+    output::progress(format!("Restored {} app profiles", profiles.len() - failed));
+    ensure!(
+        failed == 0,
+        "Failed to restore {failed} of {} app profiles",
+        profiles.len()
+    );
+    Ok(())
+}
+
+pub fn restore_backup(file: &Path) -> Result<()> {
+    module::ensure_boot_completed()?;
+    // a relocated tree has no kernel to take the profiles
+    let kernel = (!defs::has_custom_root()).then(ksucalls::kernel);
+    restore_backup_in(defs::root(), file, kernel)
+}
+
+fn restore_backup_in(root: &Path, file: &Path, kernel: Option<&dyn KernelInterface>) -> Result<()> {
+    let mut archive = ZipArchive::new(File::open(file)?)?;
+    let checked = unzip::check(&mut archive)?;
+    let manifest: Manifest = {
+        let entry = archive
+            .by_name(MANIFEST_NAME)
+            .context("Not a KernelSU backup")?;
+        serde_json::from_reader(entry)?
+    };
+    ensure!(
+        manifest.version <= BACKUP_FORMAT_VERSION,
+        "Backup version {} is not supported, please upgrade KernelSU",
+        manifest.version
+    );
+    info!(
+        "restore backup created at {} by ksud {}",
+        manifest.created, manifest.ksud_version
+    );
+
+    // modules are staged as updates and take effect after reboot
+    let modules_dir = defs::resolve_in(root, Path::new(defs::MODULE_DIR));
+    let modules_update_dir = defs::resolve_in(root, Path::new(defs::MODULE_UPDATE_DIR));
+    ensure_dir_exists(&modules_update_dir)?;
+    for meta in &manifest.modules {
+        let update_dir: PathBuf = modules_update_dir.join(&meta.id);
+        module_prop::validate_id(&meta.id)
+            .with_context(|| format!("Invalid module id in backup: {}", meta.id))?;
+        ensure_clean_dir(&update_dir)?;
+        let result = unzip_dir(
+            &mut archive,
+            &checked,
+            &format!("modules/{}/", meta.id),
+            &update_dir,
+        )
+        .and_then(|_| apply_file_meta(&update_dir, &meta.files))
+        .and_then(|_| module::mark_updated(&modules_dir.join(&meta.id), &update_dir));
+        if let Err(e) = result {
+            fs::remove_dir_all(&update_dir).ok();
+            return Err(e).with_context(|| format!("Failed to restore module {}", meta.id));
+        }
+        let state = if meta.remove {
+            "removed"
+        } else if meta.enabled {
+            "enabled"
+        } else {
+            "disabled"
+        };
+        output::progress(format!("Restored module {} ({state})", meta.id));
+    }
+
+    unzip_dir(
+        &mut archive,
+        &checked,
+        "profile/selinux/",
+        &defs::resolve_in(root, Path::new(defs::PROFILE_SELINUX_DIR)),
+    )?;
+    unzip_dir(
+        &mut archive,
+        &checked,
+        "profile/templates/",
+        &defs::resolve_in(root, Path::new(defs::PROFILE_TEMPLATE_DIR)),
+    )?;
+
+    restore_allowlist(&mut archive, root, kernel)?;
+
+    output::progress("Backup restored, reboot to apply");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ksucalls::{AppProfile, MockKernel, DEFAULT_SELINUX_DOMAIN};
+    use std::os::unix::fs::{symlink, PermissionsExt};
+
+    fn write(path: &Path, content: &str) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+    }
+
+    fn resolve(root: &Path, path: &str) -> PathBuf {
+        defs::resolve_in(root, Path::new(path))
+    }
+
+    fn module(root: &Path, id: &str) -> PathBuf {
+        let dir = resolve(root, defs::MODULE_DIR).join(id);
+        write(
+            &dir.join("module.prop"),
+            &format!("id={id}\nname={id}\nversion=1\nversionCode=1\n"),
+        );
+        dir
+    }
+
+    fn profile(key: &str, uid: i32) -> AppProfile {
+        let mut profile = AppProfile::new(key, uid).unwrap();
+        profile.allow_su = true;
+        profile
+            .root_config_mut()
+            .profile
+            .set_selinux_domain(DEFAULT_SELINUX_DOMAIN)
+            .unwrap();
+        profile
+    }
+
+    #[test]
+    fn backup_round_trip() {
+        let from = tempfile::tempdir().unwrap();
+        let from = from.path();
+        let a = module(from, "mod_a");
+        write(&a.join("system/bin/tool"), "#!/bin/sh\n");
+        fs::set_permissions(a.join("system/bin/tool"), fs::Permissions::from_mode(0o755)).unwrap();
+        symlink("../bin/tool", a.join("system/bin/link")).unwrap();
+        fs::create_dir_all(a.join("system/etc/opaque")).unwrap();
+        extattr::lsetxattr(
+            a.join("system/etc/opaque"),
+            REPLACE_DIR_XATTR,
+            "y",
+            extattr::Flags::empty(),
+        )
+        .unwrap();
+        rustix::fs::mknodat(
+            rustix::fs::CWD,
+            a.join("system/etc/gone"),
+            rustix::fs::FileType::CharacterDevice,
+            rustix::fs::Mode::from_raw_mode(0o644),
+            0,
+        )
+        .unwrap();
+        write(&a.join(defs::DISABLE_FILE_NAME), "");
+        let b = module(from, "mod_b");
+        write(&b.join(defs::REMOVE_FILE_NAME), "");
+        write(
+            &resolve(from, defs::PROFILE_SELINUX_DIR).join("com.example.a"),
+            "allow su * * *",
+        );
+        write(
+            &resolve(from, defs::PROFILE_TEMPLATE_DIR).join("tmpl.a"),
+            "{}",
+        );
+        allowlist::save_allowlist(
+            &resolve(from, defs::ALLOWLIST_PATH),
+            &[profile("com.example.a", 10100)],
+        )
+        .unwrap();
+
+        let backup = from.join("backup.zip");
+        create_backup_in(from, &backup).unwrap();
+
+        // the app got another uid on the new device
+        let to = tempfile::tempdir().unwrap();
+        let to = to.path();
+        write(
+            &resolve(to, defs::PACKAGES_LIST_PATH),
+            "com.example.a 10200 0 /data/user/0/com.example.a default none\n",
+        );
+        restore_backup_in(to, &backup, None).unwrap();
+
+        let update = resolve(to, defs::MODULE_UPDATE_DIR);
+        let a = update.join("mod_a");
+        assert_eq!(
+            fs::read_to_string(a.join("system/bin/tool")).unwrap(),
+            "#!/bin/sh\n"
+        );
+        let mode = fs::metadata(a.join("system/bin/tool")).unwrap().mode();
+        assert_eq!(mode & 0o7777, 0o755);
+        assert_eq!(
+            fs::read_link(a.join("system/bin/link")).unwrap(),
+            Path::new("../bin/tool")
+        );
+        assert_eq!(
+            extattr::lgetxattr(a.join("system/etc/opaque"), REPLACE_DIR_XATTR).unwrap(),
+            b"y"
+        );
+        let gone = fs::symlink_metadata(a.join("system/etc/gone")).unwrap();
+        assert!(gone.file_type().is_char_device() && gone.rdev() == 0);
+        assert!(a.join(defs::DISABLE_FILE_NAME).exists());
+        assert!(!a.join(defs::REMOVE_FILE_NAME).exists());
+        let b = update.join("mod_b");
+        assert!(b.join(defs::REMOVE_FILE_NAME).exists());
+        assert!(!b.join(defs::DISABLE_FILE_NAME).exists());
+
+        // staged as updates, applied on the next boot
+        for id in ["mod_a", "mod_b"] {
+            let module = resolve(to, defs::MODULE_DIR).join(id);
+            assert!(module.join(defs::UPDATE_FILE_NAME).exists());
+            assert!(module.join("module.prop").exists());
+        }
+
+        assert!(resolve(to, defs::PROFILE_SELINUX_DIR)
+            .join("com.example.a")
+            .exists());
+        assert!(resolve(to, defs::PROFILE_TEMPLATE_DIR)
+            .join("tmpl.a")
+            .exists());
+        let profiles = allowlist::load_allowlist(&resolve(to, defs::ALLOWLIST_PATH)).unwrap();
+        assert_eq!(profiles.len(), 1);
+        assert_eq!(profiles[0].key(), "com.example.a");
+        assert_eq!(profiles[0].current_uid, 10200);
+    }
+
+    #[test]
+    fn report_profiles_not_restored() {
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path();
+        fs::create_dir_all(resolve(root, defs::WORKING_DIR)).unwrap();
+        allowlist::save_allowlist(
+            &resolve(root, defs::ALLOWLIST_PATH),
+            // the kernel refuses uids lower than 2000
+            &[profile("com.example.a", 10100), profile("system.bad", 1500)],
+        )
+        .unwrap();
+        let backup = root.join("backup.zip");
+        create_backup_in(root, &backup).unwrap();
+
+        let kernel = MockKernel::new(0);
+        let err = restore_backup_in(root, &backup, Some(&kernel)).unwrap_err();
+        assert_eq!(err.to_string(), "Failed to restore 1 of 2 app profiles");
+        assert_eq!(kernel.get_allow_list().unwrap(), [10100]);
+    }
+}
@@ -16,6 +16,7 @@ use which::which;
 use crate::defs;
 use crate::defs::BACKUP_FILENAME;
 use crate::defs::{KSU_BACKUP_DIR, KSU_BACKUP_FILE_PREFIX};
+use crate::output;
 use crate::{assets, utils};
 
 #[cfg(target_os = "android")]
@@ -125,7 +126,7 @@ fn parse_kmi_from_kernel(kernel: &PathBuf, workdir: &Path) -> Result<String> {
             }
         }
     }
-    println!("- Failed to get KMI version");
+    output::progress("Failed to get KMI version");
     bail!("Try to choose LKM manually")
 }
 
@@ -211,6 +212,7 @@ pub fn restore(
     magiskboot_path: Option<PathBuf>,
     flash: bool,
 ) -> Result<()> {
+    ensure_live_device(flash)?;
     let tmpdir = tempfile::Builder::new()
         .prefix("KernelSU")
         .tempdir()
@@ -224,7 +226,7 @@ pub fn restore(
 
     let (bootimage, bootdevice) = find_boot_image(&image, skip_init, false, false, workdir)?;
 
-    println!("- Unpacking boot image");
+    output::progress("Unpacking boot image");
     let status = Command::new(&magiskboot)
         .current_dir(workdir)
         .stdout(Stdio::null())
@@ -251,19 +253,19 @@ pub fn restore(
         let sha = String::from_utf8(sha)?;
         let sha = sha.trim();
         let backup_path =
-            PathBuf::from(KSU_BACKUP_DIR).join(format!("{KSU_BACKUP_FILE_PREFIX}{sha}"));
+            defs::resolve(KSU_BACKUP_DIR).join(format!("{KSU_BACKUP_FILE_PREFIX}{sha}"));
         if backup_path.is_file() {
             new_boot = Some(backup_path);
             from_backup = true;
         } else {
-            println!("- Warning: no backup {backup_path:?} found!");
+            output::progress(format!("Warning: no backup {backup_path:?} found!"));
         }
 
         if let Err(e) = clean_backup(sha) {
-            println!("- Warning: Cleanup backup image failed: {e}");
+            output::progress(format!("Warning: Cleanup backup image failed: {e}"));
         }
     } else {
-        println!("- Backup info is absent!");
+        output::progress("Backup info is absent!");
     }
 
     if new_boot.is_none() {
@@ -279,7 +281,7 @@ pub fn restore(
             std::fs::remove_file(ramdisk)?;
         }
 
-        println!("- Repacking boot image");
+        output::progress("Repacking boot image");
         let status = Command::new(&magiskboot)
             .current_dir(workdir)
             .stdout(Stdio::null())
@@ -305,18 +307,30 @@ pub fn restore(
         if from_backup || std::fs::rename(&new_boot, &output_image).is_err() {
             std::fs::copy(&new_boot, &output_image).context("copy out new boot failed")?;
         }
-        println!("- Output file is written to");
-        println!("- {}", output_image.display().to_string().trim_matches('"'));
+        output::progress("Output file is written to");
+        output::progress(output_image.display().to_string().trim_matches('"'));
     }
     if flash {
         if from_backup {
-            println!("- Flashing new boot image from {}", new_boot.display());
+            output::progress(format!(
+                "Flashing new boot image from {}",
+                new_boot.display()
+            ));
         } else {
-            println!("- Flashing new boot image");
+            output::progress("Flashing new boot image");
         }
         flash_boot(&bootdevice, new_boot)?;
     }
-    println!("- Done!");
+    output::progress("Done!");
+    Ok(())
+}
+
+// flashing and bootctl always act on the running device, whatever the root prefix is
+fn ensure_live_device(flash: bool) -> Result<()> {
+    ensure!(
+        !flash || !defs::has_custom_root(),
+        "refuse to flash the boot partition of the running device with a custom root"
+    );
     Ok(())
 }
 
@@ -334,7 +348,7 @@ pub fn patch(
 ) -> Result<()> {
     let result = do_patch(image, kernel, kmod, init, ota, flash, out, magiskboot, kmi);
     if let Err(ref e) = result {
-        println!("- Install Error: {e}");
+        output::progress(format!("Install Error: {e}"));
     }
     result
 }
@@ -351,7 +365,10 @@ fn do_patch(
     magiskboot_path: Option<PathBuf>,
     kmi: Option<String>,
 ) -> Result<()> {
-    println!(include_str!("banner"));
+    ensure_live_device(flash)?;
+    if !output::is_json() {
+        println!(include_str!("banner"));
+    }
 
     let patch_file = image.is_some();
 
@@ -384,18 +401,18 @@ fn do_patch(
         match get_current_kmi() {
             Ok(value) => value,
             Err(e) => {
-                println!("- {}", e);
+                output::progress(e);
                 if let Some(image_path) = &image {
-                    println!(
-                        "- Trying to auto detect KMI version for {}",
+                    output::progress(format!(
+                        "Trying to auto detect KMI version for {}",
                         image_path.to_str().unwrap()
-                    );
+                    ));
                     parse_kmi_from_boot(&magiskboot, image_path, tmpdir.path())?
                 } else if let Some(kernel_path) = &kernel {
-                    println!(
-                        "- Trying to auto detect KMI version for {}",
+                    output::progress(format!(
+                        "Trying to auto detect KMI version for {}",
                         kernel_path.to_str().unwrap()
-                    );
+                    ));
                     parse_kmi_from_kernel(kernel_path, tmpdir.path())?
                 } else {
                     "".to_string()
@@ -418,14 +435,14 @@ fn do_patch(
         std::fs::copy(kernel, workdir.join("kernel")).context("copy kernel from failed")?;
     }
 
-    println!("- Preparing assets");
+    output::progress("Preparing assets");
 
     let kmod_file = workdir.join("kernelsu.ko");
     if let Some(kmod) = kmod {
         std::fs::copy(kmod, kmod_file).context("copy kernel module failed")?;
     } else {
         // If kmod is not specified, extract from assets
-        println!("- KMI: {kmi}");
+        output::progress(format!("KMI: {kmi}"));
         let name = format!("{kmi}_kernelsu.ko");
         assets::copy_assets_to_file(&name, kmod_file)
             .with_context(|| format!("Failed to copy {name}"))?;
@@ -443,7 +460,7 @@ fn do_patch(
     // magiskboot cpio ramdisk.cpio 'add 0755 ksuinit init'
     // magiskboot cpio ramdisk.cpio 'add 0755 <kmod> kernelsu.ko'
 
-    println!("- Unpacking boot image");
+    output::progress("Unpacking boot image");
     let status = Command::new(&magiskboot)
         .current_dir(workdir)
         .stdout(Stdio::null())
@@ -460,7 +477,7 @@ fn do_patch(
         "Cannot work with Magisk patched image"
     );
 
-    println!("- Adding KernelSU LKM");
+    output::progress("Adding KernelSU LKM");
     let is_kernelsu_patched = is_kernelsu_patched(&magiskboot, workdir)?;
 
     let mut need_backup = false;
@@ -480,11 +497,11 @@ fn do_patch(
     #[cfg(target_os = "android")]
     if need_backup {
         if let Err(e) = do_backup(&magiskboot, workdir, &bootimage) {
-            println!("- Backup stock image failed: {e}");
+            output::progress(format!("Backup stock image failed: {e}"));
         }
     }
 
-    println!("- Repacking boot image");
+    output::progress("Repacking boot image");
     // magiskboot repack boot.img
     let status = Command::new(&magiskboot)
         .current_dir(workdir)
@@ -508,12 +525,12 @@ fn do_patch(
         if std::fs::rename(&new_boot, &output_image).is_err() {
             std::fs::copy(&new_boot, &output_image).context("copy out new boot failed")?;
         }
-        println!("- Output file is written to");
-        println!("- {}", output_image.display().to_string().trim_matches('"'));
+        output::progress("Output file is written to");
+        output::progress(output_image.display().to_string().trim_matches('"'));
     }
 
     if flash {
-        println!("- Flashing new boot image");
+        output::progress("Flashing new boot image");
         flash_boot(&bootdevice, new_boot)?;
 
         if ota {
@@ -521,7 +538,7 @@ fn do_patch(
         }
     }
 
-    println!("- Done!");
+    output::progress("Done!");
     Ok(())
 }
 
@@ -550,26 +567,26 @@ fn do_backup(magiskboot: &Path, workdir: &Path, image: &str) -> Result<()> {
     let sha1 = calculate_sha1(image)?;
     let filename = format!("{KSU_BACKUP_FILE_PREFIX}{sha1}");
 
-    println!("- Backup stock boot image");
+    output::progress("Backup stock boot image");
     // magiskboot cpio ramdisk.cpio 'add 0755 $BACKUP_FILENAME'
-    let target = format!("{KSU_BACKUP_DIR}{filename}");
-    std::fs::copy(image, &target).with_context(|| format!("backup to {target}"))?;
+    let target = defs::resolve(KSU_BACKUP_DIR).join(filename);
+    std::fs::copy(image, &target).with_context(|| format!("backup to {}", target.display()))?;
     std::fs::write(workdir.join(BACKUP_FILENAME), sha1.as_bytes()).context("write sha1")?;
     do_cpio_cmd(
         magiskboot,
         workdir,
         &format!("add 0755 {0} {0}", BACKUP_FILENAME),
     )?;
-    println!("- Stock image has been backup to");
-    println!("- {target}");
+    output::progress("Stock image has been backup to");
+    output::progress(target.display());
     Ok(())
 }
 
 #[cfg(target_os = "android")]
 fn clean_backup(sha1: &str) -> Result<()> {
-    println!("- Clean up backup");
+    output::progress("Clean up backup");
     let backup_name = format!("{}{}", KSU_BACKUP_FILE_PREFIX, sha1);
-    let dir = std::fs::read_dir(defs::KSU_BACKUP_DIR)?;
+    let dir = std::fs::read_dir(defs::resolve(KSU_BACKUP_DIR))?;
     for entry in dir.flatten() {
         let path = entry.path();
         if !path.is_file() {
@@ -581,7 +598,7 @@ fn clean_backup(sha1: &str) -> Result<()> {
                 && name.starts_with(KSU_BACKUP_FILE_PREFIX)
                 && std::fs::remove_file(path).is_ok()
             {
-                println!("- removed {name}");
+                output::progress(format!("removed {name}"));
             }
         }
     }
@@ -639,7 +656,9 @@ fn find_boot_image(
         bootimage = std::fs::canonicalize(image)?;
     } else {
         if cfg!(not(target_os = "android")) {
-            println!("- Current OS is not android, refusing auto bootimage/bootdevice detection");
+            output::progress(
+                "Current OS is not android, refusing auto bootimage/bootdevice detection",
+            );
             bail!("Please specify a boot image");
         }
         let mut slot_suffix =
@@ -661,7 +680,7 @@ fn find_boot_image(
             format!("/dev/block/by-name/boot{slot_suffix}")
         };
 
-        println!("- Bootdevice: {boot_partition}");
+        output::progress(format!("Bootdevice: {boot_partition}"));
         let tmp_boot_path = workdir.join("boot.img");
 
         dd(&boot_partition, &tmp_boot_path)?;
@@ -677,12 +696,13 @@ fn find_boot_image(
 fn post_ota() -> Result<()> {
     use crate::defs::ADB_DIR;
     use assets::BOOTCTL_PATH;
-    let status = Command::new(BOOTCTL_PATH).arg("hal-info").status()?;
+    let bootctl = defs::resolve(BOOTCTL_PATH);
+    let status = Command::new(&bootctl).arg("hal-info").status()?;
     if !status.success() {
         return Ok(());
     }
 
-    let current_slot = Command::new(BOOTCTL_PATH)
+    let current_slot = Command::new(&bootctl)
         .arg("get-current-slot")
         .output()?
         .stdout;
@@ -690,11 +710,12 @@ fn post_ota() -> Result<()> {
     let current_slot = current_slot.trim();
     let target_slot = if current_slot == "0" { 1 } else { 0 };
 
-    Command::new(BOOTCTL_PATH)
+    Command::new(&bootctl)
         .arg(format!("set-active-boot-slot {target_slot}"))
         .status()?;
 
-    let post_fs_data = std::path::Path::new(ADB_DIR).join("post-fs-data.d");
+    // the script runs on the device itself, so its content keeps the unprefixed paths
+    let post_fs_data = defs::resolve(ADB_DIR).join("post-fs-data.d");
     utils::ensure_dir_exists(&post_fs_data)?;
     let post_ota_sh = post_fs_data.join("post_ota.sh");
 
@@ -0,0 +1,234 @@
+use anyhow::{Context, Result};
+use log::warn;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use crate::config::{self, Limits};
+use crate::utils::PreparedWrite;
+use crate::{defs, utils};
+
+// limits a module can set in module.prop, written as is to the cgroup files of the same name
+const LIMIT_KEYS: [&str; 3] = ["cpu.max", "memory.max", "pids.max"];
+
+const CONTROLLERS: [&str; 3] = ["cpu", "memory", "pids"];
+
+/// Resource usage of a module cgroup
+#[derive(Debug, Default, Serialize)]
+pub struct Usage {
+    /// microseconds
+    pub cpu: Option<u64>,
+    /// bytes
+    pub memory: Option<u64>,
+    pub pids: Option<u64>,
+}
+
+fn is_cgroup_v2(root: &Path) -> bool {
+    defs::resolve_in(root, Path::new(defs::CGROUP_V2_DIR))
+        .join("cgroup.controllers")
+        .exists()
+}
+
+// controllers must be enabled in every ancestor for the limits to exist in a child,
+// the root cgroup belongs to init so only the ones it enabled are used
+fn enable_controllers(root: &Path, ksu: &Path) {
+    let Ok(enabled) = std::fs::read_to_string(root.join("cgroup.subtree_control")) else {
+        return;
+    };
+    let enable: Vec<String> = enabled
+        .split_whitespace()
+        .filter(|c| CONTROLLERS.contains(c))
+        .map(|c| format!("+{c}"))
+        .collect();
+    if enable.is_empty() {
+        return;
+    }
+    if let Err(e) = std::fs::write(ksu.join("cgroup.subtree_control"), enable.join(" ")) {
+        warn!("Failed to enable {:?} in {}: {}", enable, ksu.display(), e);
+    }
+}
+
+fn path_of(root: &Path, id: &str) -> PathBuf {
+    defs::resolve_in(root, Path::new(defs::MODULE_CGROUP_DIR)).join(id)
+}
+
+/// Create the cgroup of module <id> and apply the limits in its module.prop,
+/// None if cgroup v2 is not available.
+pub fn prepare(id: &str, module_prop: &HashMap<String, String>) -> Result<Option<PathBuf>> {
+    prepare_in(defs::root(), id, module_prop, &config::get().modules.limits)
+}
+
+// same as prepare, in <root> with the default <limits>
+fn prepare_in(
+    root: &Path,
+    id: &str,
+    module_prop: &HashMap<String, String>,
+    limits: &Limits,
+) -> Result<Option<PathBuf>> {
+    if !is_cgroup_v2(root) {
+        return Ok(None);
+    }
+    let cgroup_root = defs::resolve_in(root, Path::new(defs::CGROUP_V2_DIR));
+    let ksu = defs::resolve_in(root, Path::new(defs::MODULE_CGROUP_DIR));
+    let cgroup = path_of(root, id);
+    utils::ensure_dir_exists(&cgroup)
+        .with_context(|| format!("Failed to create cgroup {}", cgroup.display()))?;
+    enable_controllers(&cgroup_root, &ksu);
+
+    for key in LIMIT_KEYS {
+        // unset limits are reset, the module.prop may have changed since last run
+        let value = module_prop
+            .get(key)
+            .map(|v| v.trim())
+            .or_else(|| limits.get(key));
+        let file = cgroup.join(key);
+        if !file.exists() {
+            if value.is_some() {
+                warn!("{id}: {key} is not available, ignored");
+            }
+            continue;
+        }
+        let value = value.unwrap_or("max");
+        if let Err(e) = std::fs::write(&file, value) {
+            warn!("{id}: Failed to set {key} to {value}: {e}");
+        }
+    }
+    Ok(Some(cgroup))
+}
+
+/// Prepare moving a child into <cgroup>, run it between fork and exec
+pub fn join(cgroup: &Path) -> std::io::Result<PreparedWrite> {
+    // 0 is the writing process, the pid of the child is unknown before fork
+    PreparedWrite::new(&cgroup.join("cgroup.procs"), b"0")
+}
+
+fn read_value(cgroup: &Path, file: &str) -> Option<u64> {
+    std::fs::read_to_string(cgroup.join(file))
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// Usage of the cgroup of module <id>, None if it has no cgroup
+pub fn usage(id: &str) -> Option<Usage> {
+    usage_in(defs::root(), id)
+}
+
+fn usage_in(root: &Path, id: &str) -> Option<Usage> {
+    let cgroup = path_of(root, id);
+    if !cgroup.is_dir() {
+        return None;
+    }
+    let cpu = std::fs::read_to_string(cgroup.join("cpu.stat"))
+        .ok()
+        .and_then(|stat| {
+            stat.lines()
+                .find_map(|line| line.strip_prefix("usage_usec "))
+                .and_then(|v| v.trim().parse().ok())
+        });
+    Some(Usage {
+        cpu,
+        memory: read_value(&cgroup, "memory.current"),
+        pids: read_value(&cgroup, "pids.current"),
+    })
+}
+
+/// Modules with a cgroup created on this boot
+pub fn modules() -> Vec<String> {
+    let Ok(dir) = std::fs::read_dir(defs::resolve(defs::MODULE_CGROUP_DIR)) else {
+        return Vec::new();
+    };
+    let mut ids: Vec<String> = dir
+        .flatten()
+        .filter(|e| e.path().is_dir())
+        .map(|e| e.file_name().to_string_lossy().to_string())
+        .collect();
+    ids.sort();
+    ids
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(path: &Path, content: &str) {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, content).unwrap();
+    }
+
+    fn read(path: &Path) -> String {
+        std::fs::read_to_string(path).unwrap()
+    }
+
+    // files of a cgroup are created by the kernel, pids.max is missing as if
+    // the pids controller isn't there
+    fn fake_cgroup(root: &Path, id: &str) -> PathBuf {
+        let cgroup_root = defs::resolve_in(root, Path::new(defs::CGROUP_V2_DIR));
+        write(
+            &cgroup_root.join("cgroup.controllers"),
+            "cpu io memory pids\n",
+        );
+        write(
+            &cgroup_root.join("cgroup.subtree_control"),
+            "cpu io memory\n",
+        );
+        let cgroup = path_of(root, id);
+        write(&cgroup.join("cpu.max"), "max 100000\n");
+        write(&cgroup.join("memory.max"), "max\n");
+        cgroup
+    }
+
+    #[test]
+    fn no_cgroup_v2() {
+        let root = tempfile::tempdir().unwrap();
+        let prepared = prepare_in(root.path(), "mod_a", &HashMap::new(), &Limits::default());
+        assert!(prepared.unwrap().is_none());
+        assert!(usage_in(root.path(), "mod_a").is_none());
+    }
+
+    #[test]
+    fn prepare_limits() {
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path();
+        let cgroup = fake_cgroup(root, "mod_a");
+        let limits = Limits {
+            cpu_max: Some("50000 100000".to_string()),
+            memory_max: Some("512M".to_string()),
+            pids_max: Some("64".to_string()),
+        };
+        let module_prop = HashMap::from([("memory.max".to_string(), " 256M ".to_string())]);
+
+        let prepared = prepare_in(root, "mod_a", &module_prop, &limits).unwrap();
+        assert_eq!(prepared.as_deref(), Some(cgroup.as_path()));
+        // module.prop goes before the config
+        assert_eq!(read(&cgroup.join("cpu.max")), "50000 100000");
+        assert_eq!(read(&cgroup.join("memory.max")), "256M");
+        assert!(!cgroup.join("pids.max").exists());
+        // only the controllers enabled in the root cgroup
+        let ksu = defs::resolve_in(root, Path::new(defs::MODULE_CGROUP_DIR));
+        assert_eq!(read(&ksu.join("cgroup.subtree_control")), "+cpu +memory");
+
+        // limits removed since the last run are reset
+        prepare_in(root, "mod_a", &HashMap::new(), &Limits::default()).unwrap();
+        assert_eq!(read(&cgroup.join("cpu.max")), "max");
+        assert_eq!(read(&cgroup.join("memory.max")), "max");
+    }
+
+    #[test]
+    fn read_usage() {
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path();
+        let cgroup = fake_cgroup(root, "mod_a");
+        write(
+            &cgroup.join("cpu.stat"),
+            "usage_usec 1234\nuser_usec 1000\nsystem_usec 234\n",
+        );
+        write(&cgroup.join("memory.current"), "4096\n");
+        let usage = usage_in(root, "mod_a").unwrap();
+        assert_eq!(usage.cpu, Some(1234));
+        assert_eq!(usage.memory, Some(4096));
+        assert_eq!(usage.pids, None);
+        assert!(usage_in(root, "mod_b").is_none());
+    }
+}
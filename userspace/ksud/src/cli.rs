@@ -1,14 +1,12 @@
 use anyhow::{Ok, Result};
 use clap::Parser;
+use serde_json::json;
 use std::path::{Path, PathBuf};
 
-#[cfg(target_os = "android")]
-use android_logger::Config;
-#[cfg(target_os = "android")]
 use log::LevelFilter;
 
 use crate::defs::KSUD_VERBOSE_LOG_FILE;
-use crate::{apk_sign, assets, debug, defs, init_event, ksucalls, module, utils};
+use crate::{apk_sign, assets, debug, defs, init_event, ksucalls, module, output, utils};
 
 /// KernelSU userspace cli
 #[derive(Parser, Debug)]
@@ -19,6 +17,14 @@ struct Args {
 
     #[arg(short, long, default_value_t = cfg!(debug_assertions))]
     verbose: bool,
+
+    /// Root prefix to operate on instead of `/`, can also be set by $KSU_ROOT
+    #[arg(long, global = true)]
+    root: Option<PathBuf>,
+
+    /// Output format, json prints one object per line and ends with a result or an error
+    #[arg(long, global = true, value_enum, default_value_t)]
+    format: output::Format,
 }
 
 #[derive(clap::Subcommand, Debug)]
@@ -38,6 +44,10 @@ enum Commands {
     /// Trigger `boot-complete` event
     BootCompleted,
 
+    /// Supervise service.sh of modules, started by `services`
+    #[command(hide = true)]
+    ServiceSupervisor,
+
     /// Install KernelSU userspace component to system
     Install {
         #[arg(long, default_value = None)]
@@ -63,6 +73,33 @@ enum Commands {
         command: Profile,
     },
 
+    /// Backup or restore modules, app profiles and allowlist
+    Backup {
+        #[command(subcommand)]
+        command: Backup,
+    },
+
+    /// Show how long each step of this boot took, compared with the last boot
+    BootTimeline,
+
+    /// Inspect or control the boot-loop protection
+    Safemode {
+        #[command(subcommand)]
+        command: Safemode,
+    },
+
+    /// Show or change the settings of ksud
+    Config {
+        #[command(subcommand)]
+        command: Config,
+    },
+
+    /// Inspect or migrate the allowlist file of kernel offline
+    Allowlist {
+        #[command(subcommand)]
+        command: Allowlist,
+    },
+
     /// Patch boot or init_boot images to apply KernelSU
     BootPatch {
         /// boot image path, if not specified, will try to find the boot image automatically
@@ -163,6 +200,13 @@ enum Debug {
     /// Get kernel version
     Version,
 
+    /// List installed packages from packages.list
+    Packages {
+        /// only show shared uid groups
+        #[arg(long, default_value = "false")]
+        shared_uid: bool,
+    },
+
     Mount,
 
     /// For testing
@@ -204,6 +248,22 @@ enum Module {
         id: String,
     },
 
+    /// Export module <id> as an installable zip
+    Export {
+        /// module id
+        id: String,
+
+        /// output zip file
+        #[arg(short, long)]
+        output: PathBuf,
+    },
+
+    /// Restore the previous version of module <id> after reboot
+    Rollback {
+        /// module id
+        id: String,
+    },
+
     /// enable module <id>
     Enable {
         /// module id
@@ -224,6 +284,141 @@ enum Module {
 
     /// list all modules
     List,
+
+    /// check module.prop of a module zip or directory
+    Validate {
+        /// module zip file or directory
+        path: PathBuf,
+    },
+
+    /// sign module <ZIP> with a minisign secret key
+    Sign {
+        /// module zip file path
+        zip: PathBuf,
+
+        /// unencrypted minisign secret key
+        #[arg(short, long)]
+        key: PathBuf,
+
+        /// write the signed zip here instead of replacing <ZIP>
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+
+    /// verify the signature of module <ZIP>, or the files of installed module <ID>
+    Verify {
+        /// module zip file path or module id
+        target: String,
+
+        /// minisign public key, the trusted keys are used by default
+        #[arg(short, long)]
+        key: Option<PathBuf>,
+    },
+
+    /// create a key pair <PATH> and <PATH>.pub for signing modules
+    Keygen {
+        /// secret key file path
+        path: PathBuf,
+    },
+
+    /// show the state of module <id> and what is inconsistent about it
+    Status {
+        /// module id
+        id: String,
+    },
+
+    /// check every module for inconsistent states
+    Fsck {
+        /// repair them, every change is reported
+        #[arg(long, default_value = "false")]
+        repair: bool,
+    },
+
+    /// list service.sh of modules supervised by ksud
+    Services,
+
+    /// show the script logs of module <id>
+    Logs {
+        /// module id
+        id: String,
+
+        /// only show the log of <stage>, e.g. post-fs-data, service, action
+        #[arg(short, long)]
+        stage: Option<String>,
+    },
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Config {
+    /// print all settings, including the defaults
+    Show,
+
+    /// print the value of <key>, e.g. log.level
+    Get {
+        /// dot separated key
+        key: String,
+    },
+
+    /// set <key> to <value>, the value is checked before the file is written
+    Set {
+        /// dot separated key
+        key: String,
+
+        /// json value, plain strings need no quotes
+        value: String,
+    },
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Safemode {
+    /// show the boot counter and modules disabled by the boot-loop protection
+    Status,
+
+    /// disable all modules on next boot
+    ArmNextBoot,
+
+    /// reset the boot counter, disabled modules stay disabled
+    Clear,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Backup {
+    /// create a backup archive
+    Create {
+        /// output file
+        #[arg(short, long)]
+        output: PathBuf,
+    },
+
+    /// restore from a backup archive, takes effect after reboot
+    Restore {
+        /// backup file
+        file: PathBuf,
+    },
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Allowlist {
+    /// dump profiles in the allowlist file
+    Dump {
+        /// allowlist file, defaults to the one used by kernel
+        #[arg(short, long)]
+        file: Option<PathBuf>,
+    },
+
+    /// import profiles from <json> into the allowlist file
+    Import {
+        /// json file produced by `ksud --format json allowlist dump`
+        json: PathBuf,
+
+        /// allowlist file, defaults to the one used by kernel
+        #[arg(short, long)]
+        file: Option<PathBuf>,
+
+        /// keep existing profiles instead of replacing the whole file
+        #[arg(long, default_value = "false")]
+        merge: bool,
+    },
 }
 
 #[derive(clap::Subcommand, Debug)]
@@ -264,12 +459,86 @@ enum Profile {
 
     /// list all templates
     ListTemplates,
+
+    /// remove selinux policies of uninstalled packages
+    Prune {
+        /// only show what would be removed
+        #[arg(long, default_value = "false")]
+        dry_run: bool,
+    },
+
+    /// get app profile of <package|uid> from kernel
+    Get {
+        /// package name (pkg@user for other users) or uid
+        target: String,
+    },
+
+    /// set app profile of <package|uid>, unspecified fields are kept
+    Set {
+        /// package name (pkg@user for other users) or uid
+        target: String,
+
+        /// allow su for the app
+        #[arg(long)]
+        allow_su: Option<bool>,
+
+        /// use the default root/non-root profile
+        #[arg(long)]
+        use_default: Option<bool>,
+
+        /// root profile template name
+        #[arg(long)]
+        template: Option<String>,
+
+        /// uid of the root shell
+        #[arg(long)]
+        uid: Option<i32>,
+
+        /// gid of the root shell
+        #[arg(long)]
+        gid: Option<i32>,
+
+        /// supplementary groups, separated by comma
+        #[arg(long, value_delimiter = ',')]
+        groups: Option<Vec<i32>>,
+
+        /// capability numbers, separated by comma
+        #[arg(long, value_delimiter = ',')]
+        capabilities: Option<Vec<u32>>,
+
+        /// selinux domain of the root shell
+        #[arg(long)]
+        domain: Option<String>,
+
+        /// mount namespace of the root shell
+        #[arg(long, value_enum)]
+        namespace: Option<crate::profile::Namespace>,
+
+        /// umount modules for the app
+        #[arg(long)]
+        umount_modules: Option<bool>,
+    },
+
+    /// allow su for <package|uid>
+    Allow {
+        /// package name (pkg@user for other users) or uid
+        target: String,
+    },
+
+    /// deny su for <package|uid>
+    Deny {
+        /// package name (pkg@user for other users) or uid
+        target: String,
+    },
+
+    /// list all app profiles in kernel
+    List,
 }
 
 pub fn run() -> Result<()> {
     #[cfg(target_os = "android")]
     android_logger::init_once(
-        Config::default()
+        android_logger::Config::default()
             .with_max_level(LevelFilter::Trace) // limit log level
             .with_tag("KernelSU"), // logs will show under mytag tag
     );
@@ -284,9 +553,19 @@ pub fn run() -> Result<()> {
     }
 
     let cli = Args::parse();
+    output::set_format(cli.format);
 
-    if !cli.verbose && !Path::new(KSUD_VERBOSE_LOG_FILE).exists() {
-        log::set_max_level(LevelFilter::Info);
+    if let Some(root) = cli
+        .root
+        .or_else(|| std::env::var_os("KSU_ROOT").map(PathBuf::from))
+    {
+        defs::set_root(root)?;
+    }
+
+    if cli.verbose || defs::resolve(KSUD_VERBOSE_LOG_FILE).exists() {
+        log::set_max_level(LevelFilter::Trace);
+    } else {
+        log::set_max_level(crate::config::get().log.level.into());
     }
 
     log::info!("command: {:?}", cli.command);
@@ -294,20 +573,39 @@ pub fn run() -> Result<()> {
     let result = match cli.command {
         Commands::PostFsData => init_event::on_post_data_fs(),
         Commands::BootCompleted => init_event::on_boot_completed(),
+        Commands::ServiceSupervisor => crate::supervisor::run(),
 
         Commands::Module { command } => {
             #[cfg(any(target_os = "linux", target_os = "android"))]
-            {
+            if !defs::has_custom_root() {
                 utils::switch_mnt_ns(1)?;
                 utils::unshare_mnt_ns()?;
             }
             match command {
                 Module::Install { zip } => module::install_module(&zip),
                 Module::Uninstall { id } => module::uninstall_module(&id),
+                Module::Rollback { id } => module::rollback_module(&id),
+                Module::Export { id, output } => crate::backup::export_module(&id, &output),
                 Module::Enable { id } => module::enable_module(&id),
                 Module::Disable { id } => module::disable_module(&id),
                 Module::Action { id } => module::run_action(&id),
                 Module::List => module::list_modules(),
+                Module::Validate { path } => module::validate_module(&path),
+                Module::Sign { zip, key, output } => {
+                    crate::signing::sign(&zip, &key, output.as_deref())
+                }
+                Module::Verify { target, key } if Path::new(&target).is_file() => {
+                    crate::signing::verify(Path::new(&target), key.as_deref())
+                }
+                Module::Verify { target, key: None } => module::verify_module(&target),
+                Module::Verify { target, .. } => {
+                    Err(anyhow::anyhow!("{target} is not a module zip"))
+                }
+                Module::Keygen { path } => crate::signing::keygen(&path),
+                Module::Status { id } => crate::module_state::status(&id),
+                Module::Fsck { repair } => crate::module_state::fsck(repair),
+                Module::Services => crate::supervisor::list_services(),
+                Module::Logs { id, stage } => module::print_logs(&id, stage.as_deref()),
             }
         }
         Commands::Install { magiskboot } => utils::install(magiskboot),
@@ -318,6 +616,17 @@ pub fn run() -> Result<()> {
             Sepolicy::Check { sepolicy } => crate::sepolicy::check_rule(&sepolicy),
         },
         Commands::Services => init_event::on_services(),
+        Commands::BootTimeline => crate::timeline::show(),
+        Commands::Safemode { command } => match command {
+            Safemode::Status => crate::safemode::status(),
+            Safemode::ArmNextBoot => crate::safemode::arm_next_boot(),
+            Safemode::Clear => crate::safemode::clear(),
+        },
+        Commands::Config { command } => match command {
+            Config::Show => crate::config::show(),
+            Config::Get { key } => crate::config::get_value(&key),
+            Config::Set { key, value } => crate::config::set_value(&key, &value),
+        },
         Commands::Profile { command } => match command {
             Profile::GetSepolicy { package } => crate::profile::get_sepolicy(package),
             Profile::SetSepolicy { package, policy } => {
@@ -327,19 +636,66 @@ pub fn run() -> Result<()> {
             Profile::SetTemplate { id, template } => crate::profile::set_template(id, template),
             Profile::DeleteTemplate { id } => crate::profile::delete_template(id),
             Profile::ListTemplates => crate::profile::list_templates(),
+            Profile::Prune { dry_run } => crate::profile::prune(dry_run),
+            Profile::Get { target } => crate::profile::get_profile(target),
+            Profile::Set {
+                target,
+                allow_su,
+                use_default,
+                template,
+                uid,
+                gid,
+                groups,
+                capabilities,
+                domain,
+                namespace,
+                umount_modules,
+            } => crate::profile::set_profile(
+                target,
+                crate::profile::ProfileUpdate {
+                    allow_su,
+                    use_default,
+                    template,
+                    uid,
+                    gid,
+                    groups,
+                    capabilities,
+                    selinux_domain: domain,
+                    namespace,
+                    umount_modules,
+                },
+            ),
+            Profile::Allow { target } => crate::profile::allow_su(target),
+            Profile::Deny { target } => crate::profile::deny_su(target),
+            Profile::List => crate::profile::list_profiles(),
+        },
+
+        Commands::Backup { command } => match command {
+            Backup::Create { output } => crate::backup::create_backup(&output),
+            Backup::Restore { file } => crate::backup::restore_backup(&file),
+        },
+
+        Commands::Allowlist { command } => match command {
+            Allowlist::Dump { file } => crate::allowlist::dump(file),
+            Allowlist::Import { json, file, merge } => crate::allowlist::import(json, file, merge),
         },
 
         Commands::Debug { command } => match command {
             Debug::SetManager { apk } => debug::set_manager(&apk),
-            Debug::GetSign { apk } => {
-                let sign = apk_sign::get_apk_signature(&apk)?;
-                println!("size: {:#x}, hash: {}", sign.0, sign.1);
-                Ok(())
-            }
+            Debug::GetSign { apk } => apk_sign::get_apk_signature(&apk).and_then(|sign| {
+                output::result(&json!({ "size": sign.0, "hash": sign.1 }), |_| {
+                    println!("size: {:#x}, hash: {}", sign.0, sign.1);
+                    Ok(())
+                })
+            }),
             Debug::Version => {
-                println!("Kernel Version: {}", ksucalls::get_version());
-                Ok(())
+                let version = ksucalls::get_version();
+                output::result(&version, |v| {
+                    println!("Kernel Version: {v}");
+                    Ok(())
+                })
             }
+            Debug::Packages { shared_uid } => crate::packages::list_packages(shared_uid),
             Debug::Su { global_mnt } => crate::su::grant_root(global_mnt),
             Debug::Mount => init_event::mount_modules_systemlessly(),
             Debug::Test => assets::ensure_binaries(false),
@@ -359,15 +715,23 @@ pub fn run() -> Result<()> {
 
         Commands::BootInfo { command } => match command {
             BootInfo::CurrentKmi => {
-                let kmi = crate::boot_patch::get_current_kmi()?;
-                println!("{}", kmi);
+                let result = crate::boot_patch::get_current_kmi().and_then(|kmi| {
+                    output::result(&kmi, |kmi| {
+                        println!("{}", kmi);
+                        Ok(())
+                    })
+                });
                 // return here to avoid printing the error message
-                return Ok(());
+                return output::finish(result);
             }
             BootInfo::SupportedKmi => {
-                let kmi = crate::assets::list_supported_kmi()?;
-                kmi.iter().for_each(|kmi| println!("{}", kmi));
-                return Ok(());
+                let result = crate::assets::list_supported_kmi().and_then(|kmi| {
+                    output::result(&kmi, |kmi| {
+                        kmi.iter().for_each(|kmi| println!("{}", kmi));
+                        Ok(())
+                    })
+                });
+                return output::finish(result);
             }
         },
         Commands::BootRestore {
@@ -377,8 +741,11 @@ pub fn run() -> Result<()> {
         } => crate::boot_patch::restore(boot, magiskboot, flash),
     };
 
+    // steps of the boot stages are written once, when the stage is done
+    crate::timeline::flush();
+
     if let Err(e) = &result {
         log::error!("Error: {:?}", e);
     }
-    result
+    output::finish(result)
 }
@@ -0,0 +1,328 @@
+use anyhow::{bail, Context, Result};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::sync::OnceLock;
+
+use crate::{defs, output, utils};
+
+// stages a script time limit can be set for
+const STAGES: [&str; 4] = ["post-fs-data", "post-mount", "service", "boot-completed"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl From<LogLevel> for log::LevelFilter {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Error => log::LevelFilter::Error,
+            LogLevel::Warn => log::LevelFilter::Warn,
+            LogLevel::Info => log::LevelFilter::Info,
+            LogLevel::Debug => log::LevelFilter::Debug,
+            LogLevel::Trace => log::LevelFilter::Trace,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    pub level: LogLevel,
+    /// seconds of logcat and dmesg captured on boot
+    pub boot_log_duration: u64,
+    /// previous logs kept, as <name>.old.log, <name>.old.2.log and so on
+    pub retention: u32,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            level: LogLevel::Info,
+            boot_log_duration: 30,
+            retention: 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ScriptsConfig {
+    /// time limits of stage scripts in seconds, 0 means no limit
+    pub timeouts: BTreeMap<String, u64>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MountStrategy {
+    #[default]
+    Magic,
+    /// don't mount module files, scripts still run
+    None,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MountConfig {
+    pub strategy: MountStrategy,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SafemodeConfig {
+    /// boots in a row which never complete before modules are disabled, 0 to turn it off
+    pub bootloop_threshold: u32,
+}
+
+impl Default for SafemodeConfig {
+    fn default() -> Self {
+        SafemodeConfig {
+            bootloop_threshold: 3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ModuleOrder {
+    /// after the modules they require or are declared `after`
+    #[default]
+    Dependencies,
+    /// by id only
+    Id,
+}
+
+/// Limits of module cgroups, used when module.prop doesn't set them
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    pub cpu_max: Option<String>,
+    pub memory_max: Option<String>,
+    pub pids_max: Option<String>,
+}
+
+impl Limits {
+    /// Default of the cgroup file <key>
+    pub fn get(&self, key: &str) -> Option<&str> {
+        match key {
+            "cpu.max" => self.cpu_max.as_deref(),
+            "memory.max" => self.memory_max.as_deref(),
+            "pids.max" => self.pids_max.as_deref(),
+            _ => None,
+        }
+    }
+}
+
+/// What to do with a module zip which isn't signed by a trusted key
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SignaturePolicy {
+    /// install it, signatures are not checked
+    #[default]
+    Off,
+    /// install it with a warning
+    Warn,
+    /// refuse to install it
+    Enforce,
+}
+
+/// What to do on boot with a module whose files don't match its manifest
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum VerifyPolicy {
+    /// don't check
+    #[default]
+    Off,
+    /// log the differences
+    Warn,
+    /// log the differences and disable the module
+    Disable,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ModulesConfig {
+    pub order: ModuleOrder,
+    pub limits: Limits,
+    pub signature_policy: SignaturePolicy,
+    pub verify_on_boot: VerifyPolicy,
+}
+
+/// Settings of ksud in [`defs::CONFIG_PATH`], missing ones take the defaults
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub log: LogConfig,
+    pub scripts: ScriptsConfig,
+    pub mount: MountConfig,
+    pub safemode: SafemodeConfig,
+    pub modules: ModulesConfig,
+}
+
+impl Config {
+    // what serde can't check
+    fn validate(&self) -> Result<()> {
+        for stage in self.scripts.timeouts.keys() {
+            if !STAGES.contains(&stage.as_str()) {
+                bail!(
+                    "unknown stage {stage} in scripts.timeouts, expected one of {}",
+                    STAGES.join(", ")
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn from_value(value: Value) -> Result<Self> {
+        let config: Config = serde_json::from_value(value)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn read() -> Result<Self> {
+        let path = defs::resolve(defs::CONFIG_PATH);
+        if !path.exists() {
+            return Ok(Config::default());
+        }
+        let content = std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let value = serde_json::from_str(&content)
+            .with_context(|| format!("Invalid {}", path.display()))?;
+        Self::from_value(value).with_context(|| format!("Invalid {}", path.display()))
+    }
+
+    fn save(&self) -> Result<()> {
+        let path = defs::resolve(defs::CONFIG_PATH);
+        let dir = path.parent().unwrap();
+        utils::ensure_dir_exists(dir)?;
+        // a config cut short by a reboot would be ignored on boot
+        let mut tmp = tempfile::NamedTempFile::new_in(dir)
+            .with_context(|| format!("Failed to create {}", path.display()))?;
+        tmp.write_all((serde_json::to_string_pretty(self)? + "\n").as_bytes())?;
+        tmp.as_file().sync_all()?;
+        tmp.persist(&path)
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+        Ok(())
+    }
+
+    // <value> is parsed as json if possible, e.g. `30`, `null`, and otherwise or when
+    // the field is a string, e.g. `64` for `modules.limits.pids_max`, taken as string
+    fn with_value(&self, key: &str, value: &str) -> Result<Self> {
+        let config = serde_json::to_value(self)?;
+        let pointer = pointer(key)?;
+        let (parent, name) = pointer.rsplit_once('/').unwrap();
+        let candidates = serde_json::from_str(value)
+            .into_iter()
+            .chain([Value::String(value.to_string())]);
+        let mut error = None;
+        for candidate in candidates {
+            let mut config = config.clone();
+            let Some(Value::Object(parent)) = config.pointer_mut(parent) else {
+                bail!("unknown key: {key}");
+            };
+            parent.insert(name.to_string(), candidate);
+            match Self::from_value(config) {
+                Ok(config) => return Ok(config),
+                Err(e) => error = error.or(Some(e)),
+            }
+        }
+        Err(error.unwrap()).with_context(|| format!("Invalid value for {key}"))
+    }
+}
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+/// The config of ksud, an invalid file is ignored so that it never stops the boot
+pub fn get() -> &'static Config {
+    CONFIG.get_or_init(|| {
+        Config::read().unwrap_or_else(|e| {
+            warn!("{e:#}, use the defaults");
+            Config::default()
+        })
+    })
+}
+
+// "log.level" -> "/log/level"
+fn pointer(key: &str) -> Result<String> {
+    if key.is_empty() || key.split('.').any(str::is_empty) {
+        bail!("invalid key: {key}");
+    }
+    Ok(format!("/{}", key.replace('.', "/")))
+}
+
+pub fn show() -> Result<()> {
+    output::result(get(), |config| {
+        println!("{}", serde_json::to_string_pretty(config)?);
+        Ok(())
+    })
+}
+
+pub fn get_value(key: &str) -> Result<()> {
+    let config = serde_json::to_value(get())?;
+    let Some(value) = config.pointer(&pointer(key)?) else {
+        bail!("unknown key: {key}");
+    };
+    output::result(value, |value| {
+        match value {
+            Value::String(s) => println!("{s}"),
+            Value::Null => {}
+            value => println!("{value}"),
+        }
+        Ok(())
+    })
+}
+
+/// Set <key> to <value>, see [`Config::with_value`]
+pub fn set_value(key: &str, value: &str) -> Result<()> {
+    // an invalid file must be fixed by hand rather than replaced by the defaults
+    Config::read()?.with_value(key, value)?.save()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn set_values() {
+        let config = Config::default();
+        let config = config.with_value("log.retention", "3").unwrap();
+        assert_eq!(config.log.retention, 3);
+        let config = config.with_value("log.level", "debug").unwrap();
+        assert_eq!(config.log.level, LogLevel::Debug);
+        let config = config.with_value("modules.limits.pids_max", "64").unwrap();
+        assert_eq!(config.modules.limits.pids_max.as_deref(), Some("64"));
+        let config = config
+            .with_value("modules.limits.cpu_max", "50000 100000")
+            .unwrap();
+        assert_eq!(
+            config.modules.limits.cpu_max.as_deref(),
+            Some("50000 100000")
+        );
+        let config = config
+            .with_value("modules.limits.pids_max", "null")
+            .unwrap();
+        assert_eq!(config.modules.limits.pids_max, None);
+        let config = config.with_value("scripts.timeouts.service", "30").unwrap();
+        assert_eq!(config.scripts.timeouts.get("service"), Some(&30));
+    }
+
+    #[test]
+    fn reject_invalid_values() {
+        let config = Config::default();
+        assert!(config.with_value("log.retention", "three").is_err());
+        assert!(config.with_value("log.retention", "-1").is_err());
+        assert!(config.with_value("log.level", "loud").is_err());
+        assert!(config.with_value("log.unknown", "1").is_err());
+        assert!(config.with_value("unknown.key", "1").is_err());
+        assert!(config.with_value("scripts.timeouts.install", "30").is_err());
+        assert!(config.with_value("log..level", "debug").is_err());
+    }
+}
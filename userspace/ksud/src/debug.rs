@@ -1,9 +1,11 @@
-use anyhow::{ensure, Context, Ok, Result};
+use anyhow::{ensure, Ok, Result};
 use std::{
     path::{Path, PathBuf},
     process::Command,
 };
 
+use crate::{output, packages::Packages};
+
 const KERNEL_PARAM_PATH: &str = "/sys/module/kernelsu";
 
 fn read_u32(path: &PathBuf) -> Result<u32> {
@@ -21,31 +23,19 @@ fn set_kernel_param(uid: u32) -> Result<()> {
     std::fs::write(&ksu_debug_manager_uid, uid.to_string())?;
     let after_uid = read_u32(&ksu_debug_manager_uid)?;
 
-    println!("set manager uid: {before_uid} -> {after_uid}");
+    output::progress(format!("set manager uid: {before_uid} -> {after_uid}"));
 
     Ok(())
 }
 
-#[cfg(target_os = "android")]
-fn get_pkg_uid(pkg: &str) -> Result<u32> {
-    // stat /data/data/<pkg>
-    let uid = rustix::fs::stat(format!("/data/data/{pkg}"))
-        .with_context(|| format!("stat /data/data/{}", pkg))?
-        .st_uid;
-    Ok(uid)
-}
-
 pub fn set_manager(pkg: &str) -> Result<()> {
     ensure!(
         Path::new(KERNEL_PARAM_PATH).exists(),
         "CONFIG_KSU_DEBUG is not enabled"
     );
 
-    #[cfg(target_os = "android")]
-    let uid = get_pkg_uid(pkg)?;
-    #[cfg(not(target_os = "android"))]
-    let uid = 0;
-    set_kernel_param(uid)?;
+    let uid = Packages::load()?.uid_of(pkg, 0)?;
+    set_kernel_param(uid as u32)?;
     // force-stop it
     let _ = Command::new("am").args(["force-stop", pkg]).status();
     Ok(())
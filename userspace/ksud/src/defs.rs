@@ -1,15 +1,29 @@
+use anyhow::{anyhow, Result};
 use const_format::concatcp;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
 
 pub const ADB_DIR: &str = "/data/adb/";
 pub const WORKING_DIR: &str = concatcp!(ADB_DIR, "ksu/");
 pub const BINARY_DIR: &str = concatcp!(WORKING_DIR, "bin/");
 pub const LOG_DIR: &str = concatcp!(WORKING_DIR, "log/");
+pub const MODULE_LOG_DIR: &str = concatcp!(LOG_DIR, "modules/");
+pub const COMMON_SCRIPT_LOG_DIR: &str = concatcp!(LOG_DIR, "common/");
+pub const BOOT_TIMELINE_PATH: &str = concatcp!(LOG_DIR, "boot_timeline.json");
 
 pub const PROFILE_DIR: &str = concatcp!(WORKING_DIR, "profile/");
 pub const PROFILE_SELINUX_DIR: &str = concatcp!(PROFILE_DIR, "selinux/");
 pub const PROFILE_TEMPLATE_DIR: &str = concatcp!(PROFILE_DIR, "templates/");
 
 pub const KSURC_PATH: &str = concatcp!(WORKING_DIR, ".ksurc");
+// settings of ksud, see config.rs
+pub const CONFIG_PATH: &str = concatcp!(WORKING_DIR, "config.json");
+// public keys trusted to sign modules, see signing.rs
+pub const TRUST_DIR: &str = concatcp!(WORKING_DIR, "trust/");
+// written by kernel, see kernel/allowlist.c
+pub const ALLOWLIST_PATH: &str = concatcp!(WORKING_DIR, ".allowlist");
+// boot counter of the boot-loop protection
+pub const SAFEMODE_STATE_PATH: &str = concatcp!(WORKING_DIR, ".safemode");
 pub const KSU_MOUNT_SOURCE: &str = "KSU";
 pub const DAEMON_PATH: &str = concatcp!(ADB_DIR, "ksud");
 pub const MAGISKBOOT_PATH: &str = concatcp!(BINARY_DIR, "magiskboot");
@@ -21,22 +35,129 @@ pub const MODULE_DIR: &str = concatcp!(ADB_DIR, "modules/");
 
 // warning: this directory should not change, or you need to change the code in module_installer.sh!!!
 pub const MODULE_UPDATE_DIR: &str = concatcp!(ADB_DIR, "modules_update/");
+// previous version of updated modules, must be on the same filesystem as MODULE_DIR
+pub const MODULE_SNAPSHOT_DIR: &str = concatcp!(ADB_DIR, "modules_snapshot/");
 
 pub const KSUD_VERBOSE_LOG_FILE: &str = concatcp!(ADB_DIR, "verbose");
 
 pub const TEMP_DIR: &str = "/debug_ramdisk";
 
+pub const PACKAGES_LIST_PATH: &str = "/data/system/packages.list";
+
 pub const MODULE_WEB_DIR: &str = "webroot";
 pub const MODULE_ACTION_SH: &str = "action.sh";
 pub const DISABLE_FILE_NAME: &str = "disable";
 pub const UPDATE_FILE_NAME: &str = "update";
 pub const REMOVE_FILE_NAME: &str = "remove";
 pub const SKIP_MOUNT_FILE_NAME: &str = "skip_mount";
+// written on boot while the module doesn't support this KernelSU, holds the reason
+pub const INCOMPATIBLE_FILE_NAME: &str = "incompatible";
+// module.prop of the running version while an update is pending
+pub const PREVIOUS_MODULE_PROP: &str = "module.prop.previous";
+// files of the module as installed, see module_manifest.rs
+pub const MODULE_MANIFEST_FILE_NAME: &str = ".manifest.json";
 pub const MAGIC_MOUNT_WORK_DIR: &str = concatcp!(TEMP_DIR, "/workdir");
+pub const CGROUP_V2_DIR: &str = "/sys/fs/cgroup";
+pub const MODULE_CGROUP_DIR: &str = concatcp!(CGROUP_V2_DIR, "/ksu/");
+// written by the service supervisor, lives until reboot
+pub const SERVICE_STATE_PATH: &str = concatcp!(TEMP_DIR, "/services.json");
 
 pub const VERSION_CODE: &str = include_str!(concat!(env!("OUT_DIR"), "/VERSION_CODE"));
 pub const VERSION_NAME: &str = include_str!(concat!(env!("OUT_DIR"), "/VERSION_NAME"));
+/// [`VERSION_CODE`] as a number, a malformed one fails the build
+pub const VERSION_CODE_NUM: i64 = parse_version_code(VERSION_CODE);
+
+const fn parse_version_code(code: &str) -> i64 {
+    let bytes = code.as_bytes();
+    let mut len = bytes.len();
+    while len > 0 && bytes[len - 1].is_ascii_whitespace() {
+        len -= 1;
+    }
+    assert!(len > 0, "VERSION_CODE is empty");
+    let mut value = 0i64;
+    let mut i = 0;
+    while i < len {
+        assert!(bytes[i].is_ascii_digit(), "VERSION_CODE is not a number");
+        value = value * 10 + (bytes[i] - b'0') as i64;
+        i += 1;
+    }
+    value
+}
 
 pub const KSU_BACKUP_DIR: &str = WORKING_DIR;
 pub const KSU_BACKUP_FILE_PREFIX: &str = "ksu_backup_";
 pub const BACKUP_FILENAME: &str = "stock_image.sha1";
+
+static ROOT: OnceLock<PathBuf> = OnceLock::new();
+
+/// Set the root prefix that all the absolute paths above are resolved against.
+/// It can only be set once, before any path is resolved.
+pub fn set_root<P: AsRef<Path>>(root: P) -> Result<()> {
+    let root = root.as_ref();
+    ROOT.set(root.to_path_buf()).map_err(|_| {
+        anyhow!(
+            "root prefix is already {}, can not set it to {}",
+            self::root().display(),
+            root.display()
+        )
+    })
+}
+
+pub fn root() -> &'static Path {
+    ROOT.get_or_init(|| PathBuf::from("/"))
+}
+
+/// Whether ksud is working on a relocated tree instead of the live system.
+pub fn has_custom_root() -> bool {
+    root() != Path::new("/")
+}
+
+/// Resolve an absolute on-device path, e.g. [`MODULE_DIR`], against the root prefix.
+pub fn resolve<P: AsRef<Path>>(path: P) -> PathBuf {
+    resolve_in(root(), path.as_ref())
+}
+
+/// Same as [`resolve`], against <root> instead of the root prefix
+pub fn resolve_in(root: &Path, path: &Path) -> PathBuf {
+    root.join(path.strip_prefix("/").unwrap_or(path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolve_against_root() {
+        let root = Path::new("/tmp/fakeroot");
+        assert_eq!(
+            resolve_in(root, Path::new(MODULE_DIR)),
+            Path::new("/tmp/fakeroot/data/adb/modules/")
+        );
+        assert_eq!(
+            resolve_in(root, Path::new("relative")),
+            Path::new("/tmp/fakeroot/relative")
+        );
+        assert_eq!(
+            resolve_in(Path::new("/"), Path::new(MODULE_DIR)),
+            Path::new(MODULE_DIR)
+        );
+    }
+
+    #[test]
+    fn version_code() {
+        assert_eq!(parse_version_code("11986"), 11986);
+        assert_eq!(parse_version_code("11986\n"), 11986);
+    }
+
+    #[test]
+    fn default_root() {
+        assert_eq!(resolve(WORKING_DIR), Path::new(WORKING_DIR));
+    }
+
+    #[test]
+    fn root_is_set_once() {
+        assert_eq!(root(), Path::new("/"));
+        assert!(set_root("/tmp/fakeroot").is_err());
+        assert_eq!(root(), Path::new("/"));
+    }
+}
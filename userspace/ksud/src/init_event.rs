@@ -1,16 +1,18 @@
+use crate::config::{self, MountStrategy};
 use crate::defs::{KSU_MOUNT_SOURCE, TEMP_DIR};
 use crate::module::{handle_updated_modules, prune_modules};
-use crate::{assets, defs, ksucalls, restorecon, utils};
+use crate::{assets, defs, ksucalls, restorecon, safemode, timeline, utils};
 use anyhow::{Context, Result};
 use log::{info, warn};
 use rustix::fs::{mount, MountFlags};
-use std::path::Path;
 
 pub fn on_post_data_fs() -> Result<()> {
     ksucalls::report_post_fs_data();
 
     utils::umask(0);
 
+    timeline::begin_boot();
+
     #[cfg(unix)]
     let _ = catch_bootlog("logcat", vec!["logcat"]);
     #[cfg(unix)]
@@ -32,7 +34,10 @@ pub fn on_post_data_fs() -> Result<()> {
         }
     }
 
-    assets::ensure_binaries(true).with_context(|| "Failed to extract bin assets")?;
+    timeline::step("post-fs-data", "ensure_binaries", || {
+        assets::ensure_binaries(true)
+    })
+    .with_context(|| "Failed to extract bin assets")?;
 
     // tell kernel that we've mount the module, so that it can do some optimization
     ksucalls::report_module_mounted();
@@ -46,45 +51,106 @@ pub fn on_post_data_fs() -> Result<()> {
         return Ok(());
     }
 
-    if let Err(e) = prune_modules() {
+    // interrupted updates and inconsistent flags, see module_state.rs
+    if let Err(e) = timeline::step(
+        "post-fs-data",
+        "repair_modules",
+        crate::module_state::repair_all,
+    ) {
+        warn!("repair modules failed: {}", e);
+    }
+
+    if let Err(e) = timeline::step("post-fs-data", "prune_modules", prune_modules) {
         warn!("prune modules failed: {}", e);
     }
 
-    if let Err(e) = handle_updated_modules() {
+    if let Err(e) = timeline::step(
+        "post-fs-data",
+        "handle_updated_modules",
+        handle_updated_modules,
+    ) {
         warn!("handle updated modules failed: {}", e);
     }
 
-    if let Err(e) = restorecon::restorecon() {
+    // disable modules if the last boots never completed
+    if let Err(e) = timeline::step("post-fs-data", "check_bootloop", safemode::check_bootloop) {
+        warn!("check boot loop failed: {}", e);
+    }
+
+    if let Err(e) = timeline::step("post-fs-data", "restorecon", restorecon::restorecon) {
         warn!("restorecon failed: {}", e);
     }
 
+    // before anything of the modules is loaded
+    if let Err(e) = timeline::step(
+        "post-fs-data",
+        "verify_modules",
+        crate::module::verify_modules,
+    ) {
+        warn!("verify modules failed: {}", e);
+    }
+
     // load sepolicy.rule
-    if crate::module::load_sepolicy_rule().is_err() {
+    if timeline::step(
+        "post-fs-data",
+        "load_sepolicy_rule",
+        crate::module::load_sepolicy_rule,
+    )
+    .is_err()
+    {
         warn!("load sepolicy.rule failed");
     }
 
-    if let Err(e) = crate::profile::apply_sepolies() {
+    // prune_sepolicies keeps templates and gives up if packages.list is empty or unreadable
+    match timeline::step("post-fs-data", "prune_sepolicies", || {
+        crate::profile::prune_sepolicies(false)
+    }) {
+        Ok(pruned) if !pruned.is_empty() => {
+            info!("pruned sepolicy of uninstalled packages: {:?}", pruned)
+        }
+        Ok(_) => {}
+        Err(e) => warn!("prune root profile sepolicy failed: {}", e),
+    }
+
+    if let Err(e) = timeline::step(
+        "post-fs-data",
+        "apply_sepolicies",
+        crate::profile::apply_sepolies,
+    ) {
         warn!("apply root profile sepolicy failed: {}", e);
     }
 
     // mount temp dir
-    if let Err(e) = mount(KSU_MOUNT_SOURCE, TEMP_DIR, "tmpfs", MountFlags::empty(), "") {
+    if let Err(e) = mount(
+        KSU_MOUNT_SOURCE,
+        defs::resolve(TEMP_DIR),
+        "tmpfs",
+        MountFlags::empty(),
+        "",
+    ) {
         warn!("do temp dir mount failed: {}", e);
     }
-    
+
     // exec modules post-fs-data scripts
-    // TODO: Add timeout
+    // scripts are killed after their time limit, see module::stage_timeout
     if let Err(e) = crate::module::exec_stage_script("post-fs-data", true) {
         warn!("exec post-fs-data scripts failed: {}", e);
     }
 
     // load system.prop
-    if let Err(e) = crate::module::load_system_prop() {
+    if let Err(e) = timeline::step(
+        "post-fs-data",
+        "load_system_prop",
+        crate::module::load_system_prop,
+    ) {
         warn!("load system.prop failed: {}", e);
     }
 
     // mount module systemlessly by magic mount
-    if let Err(e) = mount_modules_systemlessly() {
+    if config::get().mount.strategy == MountStrategy::None {
+        info!("mount strategy is none, skip mounting modules");
+    } else if let Err(e) = timeline::step("post-fs-data", "magic_mount", mount_modules_systemlessly)
+    {
         warn!("do systemless mount failed: {}", e);
     }
 
@@ -135,6 +201,10 @@ pub fn on_boot_completed() -> Result<()> {
     ksucalls::report_boot_complete();
     info!("on_boot_completed triggered!");
 
+    if let Err(e) = safemode::boot_completed() {
+        warn!("reset boot counter failed: {}", e);
+    }
+
     run_stage("boot-completed", false);
 
     Ok(())
@@ -145,25 +215,24 @@ fn catch_bootlog(logname: &str, command: Vec<&str>) -> Result<()> {
     use std::os::unix::process::CommandExt;
     use std::process::Stdio;
 
-    let logdir = Path::new(defs::LOG_DIR);
-    utils::ensure_dir_exists(logdir)?;
+    let logdir = defs::resolve(defs::LOG_DIR);
+    utils::ensure_dir_exists(&logdir)?;
     let bootlog = logdir.join(format!("{logname}.log"));
-    let oldbootlog = logdir.join(format!("{logname}.old.log"));
-
-    if bootlog.exists() {
-        std::fs::rename(&bootlog, oldbootlog)?;
-    }
+    let log_config = &config::get().log;
+    utils::rotate_log(&bootlog, log_config.retention)?;
 
     let bootlog = std::fs::File::create(bootlog)?;
 
-    let mut args = vec!["-s", "9", "30s"];
+    let duration = format!("{}s", log_config.boot_log_duration);
+    let mut args = vec!["-s", "9", duration.as_str()];
     args.extend_from_slice(&command);
-    // timeout -s 9 30s logcat > boot.log
+    // timeout -s 9 <duration> logcat > boot.log
+    let cgroups = utils::CgroupSwitch::prepare();
     let result = unsafe {
         std::process::Command::new("timeout")
             .process_group(0)
-            .pre_exec(|| {
-                utils::switch_cgroups();
+            .pre_exec(move || {
+                cgroups.run();
                 Ok(())
             })
             .args(args)
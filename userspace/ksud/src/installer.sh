@@ -339,14 +339,17 @@ install_module() {
   local MODDIRNAME=modules
   $BOOTMODE && MODDIRNAME=modules_update
   local MODULEROOT=$NVBASE/$MODDIRNAME
-  MODID=`grep_prop id $TMPDIR/module.prop`
+  # ksud passes the id it validated
+  [ -z "$MODID" ] && MODID=`grep_prop id $TMPDIR/module.prop`
   MODNAME=`grep_prop name $TMPDIR/module.prop`
   MODAUTH=`grep_prop author $TMPDIR/module.prop`
   MODPATH=$MODULEROOT/$MODID
 
-  # Create mod paths
-  rm -rf $MODPATH
-  mkdir -p $MODPATH
+  # Create mod paths, ksud has already extracted and checked the zip there
+  if [ "$KSU_EXTRACTED" != true ] || is_legacy_script; then
+    rm -rf $MODPATH
+    mkdir -p $MODPATH
+  fi
 
   if is_legacy_script; then
     unzip -oj "$ZIPFILE" module.prop install.sh uninstall.sh 'common/*' -d $TMPDIR >&2
@@ -371,11 +374,21 @@ install_module() {
     print_title "$MODNAME" "by $MODAUTH"
     print_title "Powered by KernelSU"
 
-    unzip -o "$ZIPFILE" customize.sh -d $MODPATH >&2
+    if [ "$KSU_EXTRACTED" = true ]; then
+      rm -rf $MODPATH/META-INF
+      # the module extracts what it needs itself
+      if grep -q '^SKIPUNZIP=1$' $MODPATH/customize.sh 2>/dev/null; then
+        find $MODPATH -mindepth 1 -maxdepth 1 ! -name customize.sh -exec rm -rf {} +
+      fi
+    else
+      unzip -o "$ZIPFILE" customize.sh -d $MODPATH >&2
+    fi
 
     if ! grep -q '^SKIPUNZIP=1$' $MODPATH/customize.sh 2>/dev/null; then
-      ui_print "- Extracting module files"
-      unzip -o "$ZIPFILE" -x 'META-INF/*' -d $MODPATH >&2
+      if [ "$KSU_EXTRACTED" != true ]; then
+        ui_print "- Extracting module files"
+        unzip -o "$ZIPFILE" -x 'META-INF/*' -d $MODPATH >&2
+      fi
 
       # Default permissions
       set_perm_recursive $MODPATH 0 0 0755 0644
@@ -435,7 +448,8 @@ install_module() {
 [ -z $BOOTMODE ] && ps -A 2>/dev/null | grep zygote | grep -qv grep && BOOTMODE=true
 [ -z $BOOTMODE ] && BOOTMODE=false
 
-NVBASE=/data/adb
+# ksud may work on a relocated tree, see `ksud --root`
+NVBASE=${KSU_ROOT%/}/data/adb
 TMPDIR=/dev/tmp
 POSTFSDATAD=$NVBASE/post-fs-data.d
 SERVICED=$NVBASE/service.d
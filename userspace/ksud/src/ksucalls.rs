@@ -1,33 +1,691 @@
+use anyhow::{bail, ensure, Result};
+use std::ffi::c_void;
+#[cfg(test)]
+use std::sync::Mutex;
+use std::sync::OnceLock;
+
+use crate::defs;
+
 const EVENT_POST_FS_DATA: u64 = 1;
 const EVENT_BOOT_COMPLETED: u64 = 2;
 const EVENT_MODULE_MOUNTED: u64 = 3;
 
+// keep in sync with kernel/ksu.h
 #[cfg(any(target_os = "linux", target_os = "android"))]
-pub fn get_version() -> i32 {
-    rustix::process::ksu_get_version()
+const KERNEL_SU_OPTION: u32 = 0xDEAD_BEEF;
+
+const CMD_GET_VERSION: u64 = 2;
+const CMD_GET_ALLOW_LIST: u64 = 5;
+const CMD_GET_DENY_LIST: u64 = 6;
+const CMD_REPORT_EVENT: u64 = 7;
+const CMD_SET_SEPOLICY: u64 = 8;
+const CMD_CHECK_SAFEMODE: u64 = 9;
+const CMD_GET_APP_PROFILE: u64 = 10;
+const CMD_SET_APP_PROFILE: u64 = 11;
+const CMD_UID_GRANTED_ROOT: u64 = 12;
+const CMD_UID_SHOULD_UMOUNT: u64 = 13;
+
+pub const KSU_APP_PROFILE_VER: u32 = 2;
+pub const KSU_MAX_PACKAGE_NAME: usize = 256;
+pub const KSU_MAX_GROUPS: usize = 32;
+pub const KSU_SELINUX_DOMAIN: usize = 64;
+
+// the kernel copies at most 128 uids for CMD_GET_ALLOW_LIST/CMD_GET_DENY_LIST
+const MAX_ALLOW_LIST_LEN: usize = 128;
+
+/// Profile key of the default non-root profile
+#[cfg(test)]
+pub const DEFAULT_NON_ROOT_PROFILE_KEY: &str = "$";
+pub const DEFAULT_SELINUX_DOMAIN: &str = "u:r:su:s0";
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Capabilities {
+    pub effective: u64,
+    pub permitted: u64,
+    pub inheritable: u64,
 }
 
-#[cfg(not(any(target_os = "linux", target_os = "android")))]
-pub fn get_version() -> i32 {
-    0
+/// Mirror of `struct root_profile`
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct RootProfile {
+    pub uid: i32,
+    pub gid: i32,
+    pub groups_count: i32,
+    pub groups: [i32; KSU_MAX_GROUPS],
+    pub capabilities: Capabilities,
+    pub selinux_domain: [u8; KSU_SELINUX_DOMAIN],
+    pub namespaces: i32,
 }
 
-#[cfg(any(target_os = "linux", target_os = "android"))]
-fn report_event(event: u64) {
-    rustix::process::ksu_report_event(event)
+/// Mirror of `struct non_root_profile`
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct NonRootProfile {
+    pub umount_modules: bool,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct RootProfileConfig {
+    pub use_default: bool,
+    pub template_name: [u8; KSU_MAX_PACKAGE_NAME],
+    pub profile: RootProfile,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct NonRootProfileConfig {
+    pub use_default: bool,
+    pub profile: NonRootProfile,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+union ProfileConfig {
+    rp_config: RootProfileConfig,
+    nrp_config: NonRootProfileConfig,
+}
+
+/// Mirror of `struct app_profile`, which `allow_su` decides the active member of the union
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct AppProfile {
+    pub version: u32,
+    pub key: [u8; KSU_MAX_PACKAGE_NAME],
+    pub current_uid: i32,
+    pub allow_su: bool,
+    config: ProfileConfig,
+}
+
+const _: () = assert!(std::mem::size_of::<AppProfile>() == 776);
+
+impl AppProfile {
+    pub const SIZE: usize = std::mem::size_of::<AppProfile>();
+
+    /// Decode a raw `struct app_profile`, e.g. from the allowlist file
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        ensure!(
+            bytes.len() == Self::SIZE,
+            "app profile should be {} bytes, got {}",
+            Self::SIZE,
+            bytes.len()
+        );
+        let mut buf = bytes.to_vec();
+        // bools must be 0 or 1, normalize them before reinterpreting the bytes
+        let allow_su = std::mem::offset_of!(AppProfile, allow_su);
+        let config = std::mem::offset_of!(AppProfile, config);
+        let use_default = config + std::mem::offset_of!(RootProfileConfig, use_default);
+        buf[allow_su] = (buf[allow_su] != 0) as u8;
+        buf[use_default] = (buf[use_default] != 0) as u8;
+        if buf[allow_su] == 0 {
+            // this byte is part of template_name in root profile
+            let umount_modules = config
+                + std::mem::offset_of!(NonRootProfileConfig, profile)
+                + std::mem::offset_of!(NonRootProfile, umount_modules);
+            buf[umount_modules] = (buf[umount_modules] != 0) as u8;
+        }
+        // SAFETY: size is checked and every bool is valid now
+        Ok(unsafe { std::ptr::read_unaligned(buf.as_ptr().cast::<AppProfile>()) })
+    }
+
+    /// Encode as a raw `struct app_profile`, field by field so that padding is zero
+    pub fn to_bytes(self) -> Vec<u8> {
+        use std::mem::offset_of;
+
+        let mut buf = vec![0u8; Self::SIZE];
+        let mut put = |offset: usize, bytes: &[u8]| {
+            buf[offset..offset + bytes.len()].copy_from_slice(bytes);
+        };
+        put(offset_of!(AppProfile, version), &self.version.to_ne_bytes());
+        put(offset_of!(AppProfile, key), &self.key);
+        put(
+            offset_of!(AppProfile, current_uid),
+            &self.current_uid.to_ne_bytes(),
+        );
+        put(offset_of!(AppProfile, allow_su), &[self.allow_su as u8]);
+
+        let config = offset_of!(AppProfile, config);
+        if self.allow_su {
+            let rp_config = self.root_config();
+            put(
+                config + offset_of!(RootProfileConfig, use_default),
+                &[rp_config.use_default as u8],
+            );
+            put(
+                config + offset_of!(RootProfileConfig, template_name),
+                &rp_config.template_name,
+            );
+            let profile = config + offset_of!(RootProfileConfig, profile);
+            let rp = &rp_config.profile;
+            put(
+                profile + offset_of!(RootProfile, uid),
+                &rp.uid.to_ne_bytes(),
+            );
+            put(
+                profile + offset_of!(RootProfile, gid),
+                &rp.gid.to_ne_bytes(),
+            );
+            put(
+                profile + offset_of!(RootProfile, groups_count),
+                &rp.groups_count.to_ne_bytes(),
+            );
+            for (i, group) in rp.groups.iter().enumerate() {
+                put(
+                    profile + offset_of!(RootProfile, groups) + i * 4,
+                    &group.to_ne_bytes(),
+                );
+            }
+            let caps = profile + offset_of!(RootProfile, capabilities);
+            put(
+                caps + offset_of!(Capabilities, effective),
+                &rp.capabilities.effective.to_ne_bytes(),
+            );
+            put(
+                caps + offset_of!(Capabilities, permitted),
+                &rp.capabilities.permitted.to_ne_bytes(),
+            );
+            put(
+                caps + offset_of!(Capabilities, inheritable),
+                &rp.capabilities.inheritable.to_ne_bytes(),
+            );
+            put(
+                profile + offset_of!(RootProfile, selinux_domain),
+                &rp.selinux_domain,
+            );
+            put(
+                profile + offset_of!(RootProfile, namespaces),
+                &rp.namespaces.to_ne_bytes(),
+            );
+        } else {
+            let nrp_config = self.non_root_config();
+            put(
+                config + offset_of!(NonRootProfileConfig, use_default),
+                &[nrp_config.use_default as u8],
+            );
+            put(
+                config
+                    + offset_of!(NonRootProfileConfig, profile)
+                    + offset_of!(NonRootProfile, umount_modules),
+                &[nrp_config.profile.umount_modules as u8],
+            );
+        }
+        buf
+    }
+
+    pub fn new(key: &str, uid: i32) -> Result<Self> {
+        // SAFETY: all-zero is a valid bit pattern for every field
+        let mut profile: AppProfile = unsafe { std::mem::zeroed() };
+        profile.version = KSU_APP_PROFILE_VER;
+        profile.current_uid = uid;
+        write_c_str(&mut profile.key, key)?;
+        Ok(profile)
+    }
+
+    pub fn key(&self) -> String {
+        read_c_str(&self.key)
+    }
+
+    pub fn root_config(&self) -> &RootProfileConfig {
+        // SAFETY: both members only contain plain data
+        unsafe { &self.config.rp_config }
+    }
+
+    pub fn root_config_mut(&mut self) -> &mut RootProfileConfig {
+        unsafe { &mut self.config.rp_config }
+    }
+
+    pub fn non_root_config(&self) -> &NonRootProfileConfig {
+        unsafe { &self.config.nrp_config }
+    }
+
+    pub fn non_root_config_mut(&mut self) -> &mut NonRootProfileConfig {
+        unsafe { &mut self.config.nrp_config }
+    }
+}
+
+impl RootProfile {
+    pub fn groups(&self) -> &[i32] {
+        let count = self.groups_count.clamp(0, KSU_MAX_GROUPS as i32) as usize;
+        &self.groups[..count]
+    }
+
+    pub fn set_groups(&mut self, groups: &[i32]) -> Result<()> {
+        ensure!(
+            groups.len() <= KSU_MAX_GROUPS,
+            "at most {KSU_MAX_GROUPS} groups are supported"
+        );
+        self.groups = [0; KSU_MAX_GROUPS];
+        self.groups[..groups.len()].copy_from_slice(groups);
+        self.groups_count = groups.len() as i32;
+        Ok(())
+    }
+
+    pub fn selinux_domain(&self) -> String {
+        read_c_str(&self.selinux_domain)
+    }
+
+    pub fn set_selinux_domain(&mut self, domain: &str) -> Result<()> {
+        write_c_str(&mut self.selinux_domain, domain)
+    }
+}
+
+impl RootProfileConfig {
+    pub fn template_name(&self) -> String {
+        read_c_str(&self.template_name)
+    }
+
+    pub fn set_template_name(&mut self, name: &str) -> Result<()> {
+        write_c_str(&mut self.template_name, name)
+    }
+}
+
+pub fn read_c_str(buf: &[u8]) -> String {
+    let end = buf.iter().position(|&c| c == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..end]).to_string()
 }
 
-#[cfg(not(any(target_os = "linux", target_os = "android")))]
-fn report_event(_event: u64) {}
+pub fn write_c_str(buf: &mut [u8], s: &str) -> Result<()> {
+    ensure!(
+        s.len() < buf.len(),
+        "{s} is too long, max length is {}",
+        buf.len() - 1
+    );
+    buf.fill(0);
+    buf[..s.len()].copy_from_slice(s.as_bytes());
+    Ok(())
+}
+
+/// Everything ksud asks from the KernelSU kernel
+pub trait KernelInterface: Send + Sync {
+    fn get_version(&self) -> i32;
+
+    fn report_event(&self, event: u64);
+
+    fn check_safemode(&self) -> bool;
+
+    /// `policy` points to a `struct sepol_data`, returns false if the kernel rejects it
+    fn set_sepolicy(&self, policy: *const c_void) -> bool;
+
+    /// uids of the profiles which are allowed to su
+    fn get_allow_list(&self) -> Result<Vec<i32>>;
+
+    /// uids of the profiles which are not allowed to su
+    fn get_deny_list(&self) -> Result<Vec<i32>>;
+
+    /// the profile of `uid`, or None if there is no such profile
+    fn get_app_profile(&self, uid: i32) -> Result<Option<AppProfile>>;
 
+    fn set_app_profile(&self, profile: &AppProfile) -> Result<()>;
+
+    fn uid_granted_root(&self, uid: i32) -> Result<bool>;
+
+    fn uid_should_umount(&self, uid: i32) -> Result<bool>;
+}
+
+/// Talks to the kernel through `prctl(KERNEL_SU_OPTION, ...)`
 #[cfg(any(target_os = "linux", target_os = "android"))]
-pub fn check_kernel_safemode() -> bool {
-    rustix::process::ksu_check_kernel_safemode()
+pub struct PrctlKernel;
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+impl PrctlKernel {
+    // the kernel writes KERNEL_SU_OPTION to the last argument on success
+    fn ksuctl(cmd: u64, arg1: *mut c_void, arg2: *mut c_void) -> bool {
+        let mut result: u32 = 0;
+        unsafe {
+            libc::prctl(
+                KERNEL_SU_OPTION as libc::c_int,
+                cmd as libc::c_ulong,
+                arg1 as libc::c_ulong,
+                arg2 as libc::c_ulong,
+                std::ptr::addr_of_mut!(result) as libc::c_ulong,
+            );
+        }
+        result == KERNEL_SU_OPTION
+    }
+
+    fn get_list(cmd: u64) -> Result<Vec<i32>> {
+        let mut uids = [0i32; MAX_ALLOW_LIST_LEN];
+        let mut len: u32 = 0;
+        ensure!(
+            Self::ksuctl(
+                cmd,
+                uids.as_mut_ptr().cast(),
+                std::ptr::addr_of_mut!(len).cast()
+            ),
+            "get allow list failed, are we root?"
+        );
+        let len = (len as usize).min(MAX_ALLOW_LIST_LEN);
+        Ok(uids[..len].to_vec())
+    }
+
+    fn check_uid(cmd: u64, uid: i32) -> Result<bool> {
+        let mut result = false;
+        ensure!(
+            Self::ksuctl(
+                cmd,
+                uid as usize as *mut c_void,
+                std::ptr::addr_of_mut!(result).cast()
+            ),
+            "check uid {uid} failed"
+        );
+        Ok(result)
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+impl KernelInterface for PrctlKernel {
+    fn get_version(&self) -> i32 {
+        let mut version: i32 = 0;
+        Self::ksuctl(
+            CMD_GET_VERSION,
+            std::ptr::addr_of_mut!(version).cast(),
+            std::ptr::null_mut(),
+        );
+        version
+    }
+
+    fn report_event(&self, event: u64) {
+        Self::ksuctl(CMD_REPORT_EVENT, event as *mut c_void, std::ptr::null_mut());
+    }
+
+    fn check_safemode(&self) -> bool {
+        Self::ksuctl(
+            CMD_CHECK_SAFEMODE,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+        )
+    }
+
+    fn set_sepolicy(&self, policy: *const c_void) -> bool {
+        Self::ksuctl(CMD_SET_SEPOLICY, std::ptr::null_mut(), policy.cast_mut())
+    }
+
+    fn get_allow_list(&self) -> Result<Vec<i32>> {
+        Self::get_list(CMD_GET_ALLOW_LIST)
+    }
+
+    fn get_deny_list(&self) -> Result<Vec<i32>> {
+        Self::get_list(CMD_GET_DENY_LIST)
+    }
+
+    fn get_app_profile(&self, uid: i32) -> Result<Option<AppProfile>> {
+        let mut profile = AppProfile::new("", uid)?;
+        // the kernel only replies for missing profiles if it is asked where to put the answer,
+        // older kernels don't reply to root at all
+        let mut found = false;
+        ensure!(
+            Self::ksuctl(
+                CMD_GET_APP_PROFILE,
+                std::ptr::addr_of_mut!(profile).cast(),
+                std::ptr::addr_of_mut!(found).cast()
+            ),
+            "get app profile of uid {uid} failed, the kernel doesn't let ksud manage app profiles"
+        );
+        Ok(found.then_some(profile))
+    }
+
+    fn set_app_profile(&self, profile: &AppProfile) -> Result<()> {
+        let mut app = *profile;
+        if !Self::ksuctl(
+            CMD_SET_APP_PROFILE,
+            std::ptr::addr_of_mut!(app).cast(),
+            std::ptr::null_mut(),
+        ) {
+            // an invalid profile and a refused call look the same, ask again to tell them apart
+            self.get_app_profile(profile.current_uid)?;
+            bail!("kernel rejected the profile of {}", profile.key());
+        }
+        Ok(())
+    }
+
+    fn uid_granted_root(&self, uid: i32) -> Result<bool> {
+        Self::check_uid(CMD_UID_GRANTED_ROOT, uid)
+    }
+
+    fn uid_should_umount(&self, uid: i32) -> Result<bool> {
+        Self::check_uid(CMD_UID_SHOULD_UMOUNT, uid)
+    }
+}
+
+/// Used when ksud works on a relocated root, where the running kernel must not be touched.
+/// App profiles only live in the kernel, they can't be managed there.
+pub struct OfflineKernel;
+
+impl OfflineKernel {
+    fn unavailable<T>(&self) -> Result<T> {
+        bail!(
+            "app profiles are kept by the running kernel, use `ksud allowlist` on a relocated root"
+        )
+    }
+}
+
+impl KernelInterface for OfflineKernel {
+    fn get_version(&self) -> i32 {
+        0
+    }
+
+    fn report_event(&self, _event: u64) {}
+
+    fn check_safemode(&self) -> bool {
+        false
+    }
+
+    fn set_sepolicy(&self, _policy: *const c_void) -> bool {
+        false
+    }
+
+    fn get_allow_list(&self) -> Result<Vec<i32>> {
+        self.unavailable()
+    }
+
+    fn get_deny_list(&self) -> Result<Vec<i32>> {
+        self.unavailable()
+    }
+
+    fn get_app_profile(&self, _uid: i32) -> Result<Option<AppProfile>> {
+        self.unavailable()
+    }
+
+    fn set_app_profile(&self, _profile: &AppProfile) -> Result<()> {
+        self.unavailable()
+    }
+
+    fn uid_granted_root(&self, _uid: i32) -> Result<bool> {
+        self.unavailable()
+    }
+
+    fn uid_should_umount(&self, _uid: i32) -> Result<bool> {
+        self.unavailable()
+    }
+}
+
+/// In-memory kernel, which mimics the allowlist logic of the kernel
+#[cfg(test)]
+pub struct MockKernel {
+    version: i32,
+    profiles: Mutex<Vec<AppProfile>>,
+    sepolicies: Mutex<Vec<String>>,
+}
+
+/// Mirror of `struct sepol_data` in kernel/selinux/rules.c
+#[cfg(test)]
+#[repr(C)]
+struct SepolData {
+    cmd: u32,
+    subcmd: u32,
+    sepol: [*const std::ffi::c_char; 7],
+}
+
+#[cfg(test)]
+impl MockKernel {
+    pub fn new(version: i32) -> Self {
+        MockKernel {
+            version,
+            profiles: Mutex::new(Vec::new()),
+            sepolicies: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Rules passed to `set_sepolicy`, as "<cmd> <subcmd> <objects...>"
+    pub fn sepolicies(&self) -> Vec<String> {
+        self.sepolicies
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    fn profiles(&self) -> std::sync::MutexGuard<'_, Vec<AppProfile>> {
+        self.profiles.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn get_list(&self, allow: bool) -> Vec<i32> {
+        self.profiles()
+            .iter()
+            .filter(|p| p.allow_su == allow)
+            .map(|p| p.current_uid)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+impl KernelInterface for MockKernel {
+    fn get_version(&self) -> i32 {
+        self.version
+    }
+
+    fn report_event(&self, event: u64) {
+        log::debug!("mock kernel: report event {event}");
+    }
+
+    fn check_safemode(&self) -> bool {
+        false
+    }
+
+    fn set_sepolicy(&self, policy: *const c_void) -> bool {
+        let data = unsafe { &*policy.cast::<SepolData>() };
+        let mut rule = format!("{} {}", data.cmd, data.subcmd);
+        // null objects are either unused or "*"
+        for &object in data.sepol.iter().filter(|o| !o.is_null()) {
+            let object = unsafe { std::ffi::CStr::from_ptr(object) };
+            rule.push(' ');
+            rule.push_str(&object.to_string_lossy());
+        }
+        self.sepolicies
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(rule);
+        true
+    }
+
+    fn get_allow_list(&self) -> Result<Vec<i32>> {
+        Ok(self.get_list(true))
+    }
+
+    fn get_deny_list(&self) -> Result<Vec<i32>> {
+        Ok(self.get_list(false))
+    }
+
+    fn get_app_profile(&self, uid: i32) -> Result<Option<AppProfile>> {
+        Ok(self
+            .profiles()
+            .iter()
+            .find(|p| p.current_uid == uid)
+            .copied())
+    }
+
+    fn set_app_profile(&self, profile: &AppProfile) -> Result<()> {
+        // same checks as profile_valid() in kernel/allowlist.c
+        if profile.current_uid < 2000 && profile.current_uid != 1000 {
+            bail!(
+                "uid lower than 2000 is unsupported: {}",
+                profile.current_uid
+            );
+        }
+        ensure!(
+            profile.version >= KSU_APP_PROFILE_VER,
+            "unsupported profile version: {}",
+            profile.version
+        );
+        if profile.allow_su {
+            let root = &profile.root_config().profile;
+            ensure!(
+                (0..=KSU_MAX_GROUPS as i32).contains(&root.groups_count),
+                "too many groups"
+            );
+            ensure!(!root.selinux_domain().is_empty(), "selinux domain is empty");
+        }
+
+        let mut profiles = self.profiles();
+        let key = profile.key();
+        match profiles
+            .iter_mut()
+            .find(|p| p.current_uid == profile.current_uid && p.key() == key)
+        {
+            Some(p) => *p = *profile,
+            None => profiles.push(*profile),
+        }
+        Ok(())
+    }
+
+    fn uid_granted_root(&self, uid: i32) -> Result<bool> {
+        Ok(self
+            .profiles()
+            .iter()
+            .any(|p| p.current_uid == uid && p.allow_su))
+    }
+
+    fn uid_should_umount(&self, uid: i32) -> Result<bool> {
+        let profiles = self.profiles();
+        // modules are umounted by default, unless the default non-root profile says otherwise
+        let default = match profiles
+            .iter()
+            .find(|p| p.key() == DEFAULT_NON_ROOT_PROFILE_KEY)
+        {
+            Some(p) => p.non_root_config().profile.umount_modules,
+            None => true,
+        };
+        let Some(profile) = profiles.iter().find(|p| p.current_uid == uid) else {
+            return Ok(default);
+        };
+        if profile.allow_su {
+            return Ok(false);
+        }
+        let config = profile.non_root_config();
+        Ok(if config.use_default {
+            default
+        } else {
+            config.profile.umount_modules
+        })
+    }
+}
+
+static KERNEL: OnceLock<Box<dyn KernelInterface>> = OnceLock::new();
+
+/// The kernel backend, which is an offline one if we work on a relocated root.
+pub fn kernel() -> &'static dyn KernelInterface {
+    KERNEL
+        .get_or_init(|| {
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            if !defs::has_custom_root() {
+                return Box::new(PrctlKernel);
+            }
+            Box::new(OfflineKernel)
+        })
+        .as_ref()
+}
+
+pub fn get_version() -> i32 {
+    kernel().get_version()
+}
+
+fn report_event(event: u64) {
+    kernel().report_event(event);
 }
 
-#[cfg(not(any(target_os = "linux", target_os = "android")))]
 pub fn check_kernel_safemode() -> bool {
-    false
+    kernel().check_safemode()
 }
 
 pub fn report_post_fs_data() {
@@ -41,3 +699,148 @@ pub fn report_boot_complete() {
 pub fn report_module_mounted() {
     report_event(EVENT_MODULE_MOUNTED);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn profile(key: &str, uid: i32, allow_su: bool) -> AppProfile {
+        let mut profile = AppProfile::new(key, uid).unwrap();
+        profile.allow_su = allow_su;
+        if allow_su {
+            profile
+                .root_config_mut()
+                .profile
+                .set_selinux_domain(DEFAULT_SELINUX_DOMAIN)
+                .unwrap();
+        } else {
+            profile.non_root_config_mut().use_default = true;
+        }
+        profile
+    }
+
+    #[test]
+    fn profile_layout() {
+        let bytes = profile("com.example.a", 10100, true).to_bytes();
+        assert_eq!(bytes.len(), AppProfile::SIZE);
+        assert_eq!(bytes[0..4], KSU_APP_PROFILE_VER.to_ne_bytes());
+        assert_eq!(&bytes[4..17], b"com.example.a");
+        assert_eq!(bytes[260..264], 10100i32.to_ne_bytes());
+        assert_eq!(bytes[264], 1);
+        // padding before the union
+        assert_eq!(bytes[265..272], [0; 7]);
+    }
+
+    #[test]
+    fn profile_round_trip() {
+        let mut root = profile("com.example.a", 10100, true);
+        let config = root.root_config_mut();
+        config.set_template_name("tmpl").unwrap();
+        config.profile.set_groups(&[1004, 3003]).unwrap();
+        config.profile.capabilities.effective = 1 << 21;
+        config.profile.namespaces = 2;
+        let mut non_root = profile("com.example.b", 10101, false);
+        non_root.non_root_config_mut().profile.umount_modules = true;
+
+        for app in [root, non_root] {
+            let bytes = app.to_bytes();
+            assert_eq!(AppProfile::from_bytes(&bytes).unwrap().to_bytes(), bytes);
+        }
+        let decoded = AppProfile::from_bytes(&root.to_bytes()).unwrap();
+        let rp = &decoded.root_config().profile;
+        assert_eq!(decoded.root_config().template_name(), "tmpl");
+        assert_eq!(rp.groups(), [1004, 3003]);
+        assert_eq!(rp.capabilities.effective, 1 << 21);
+        assert_eq!(rp.selinux_domain(), DEFAULT_SELINUX_DOMAIN);
+        assert_eq!(rp.namespaces, 2);
+        let decoded = AppProfile::from_bytes(&non_root.to_bytes()).unwrap();
+        assert!(decoded.non_root_config().profile.umount_modules);
+        assert!(decoded.non_root_config().use_default);
+    }
+
+    #[test]
+    fn mock_set_and_get() {
+        let kernel = MockKernel::new(11000);
+        assert_eq!(kernel.get_version(), 11000);
+        assert!(kernel.get_app_profile(10100).unwrap().is_none());
+
+        kernel
+            .set_app_profile(&profile("com.example.a", 10100, true))
+            .unwrap();
+        kernel
+            .set_app_profile(&profile("com.example.b", 10101, false))
+            .unwrap();
+        assert_eq!(kernel.get_allow_list().unwrap(), [10100]);
+        assert_eq!(kernel.get_deny_list().unwrap(), [10101]);
+        assert!(kernel.uid_granted_root(10100).unwrap());
+        assert!(!kernel.uid_granted_root(10101).unwrap());
+
+        // the same key and uid replaces the profile
+        kernel
+            .set_app_profile(&profile("com.example.a", 10100, false))
+            .unwrap();
+        assert!(kernel.get_allow_list().unwrap().is_empty());
+        let app = kernel.get_app_profile(10100).unwrap().unwrap();
+        assert_eq!(app.key(), "com.example.a");
+        assert!(!app.allow_su);
+    }
+
+    #[test]
+    fn mock_rejects_invalid_profiles() {
+        let kernel = MockKernel::new(11000);
+        assert!(kernel
+            .set_app_profile(&profile("system", 1999, true))
+            .is_err());
+        assert!(kernel
+            .set_app_profile(&profile("shell", 1000, true))
+            .is_ok());
+
+        let mut old = profile("com.example.a", 10100, true);
+        old.version = 1;
+        assert!(kernel.set_app_profile(&old).is_err());
+
+        let mut no_domain = profile("com.example.a", 10100, true);
+        no_domain
+            .root_config_mut()
+            .profile
+            .set_selinux_domain("")
+            .unwrap();
+        assert!(kernel.set_app_profile(&no_domain).is_err());
+    }
+
+    #[test]
+    fn mock_umount_modules() {
+        let kernel = MockKernel::new(11000);
+        // umounted by default
+        assert!(kernel.uid_should_umount(10100).unwrap());
+
+        kernel
+            .set_app_profile(&profile("com.example.a", 10100, true))
+            .unwrap();
+        assert!(!kernel.uid_should_umount(10100).unwrap());
+
+        let mut default = profile(DEFAULT_NON_ROOT_PROFILE_KEY, 9999, false);
+        default.non_root_config_mut().profile.umount_modules = false;
+        kernel.set_app_profile(&default).unwrap();
+        kernel
+            .set_app_profile(&profile("com.example.b", 10101, false))
+            .unwrap();
+        assert!(!kernel.uid_should_umount(10101).unwrap());
+
+        let mut own = profile("com.example.c", 10102, false);
+        own.non_root_config_mut().use_default = false;
+        own.non_root_config_mut().profile.umount_modules = true;
+        kernel.set_app_profile(&own).unwrap();
+        assert!(kernel.uid_should_umount(10102).unwrap());
+    }
+
+    #[test]
+    fn offline_kernel_refuses_profiles() {
+        let kernel = OfflineKernel;
+        assert!(kernel.get_allow_list().is_err());
+        assert!(kernel
+            .set_app_profile(&profile("com.example.a", 10100, true))
+            .is_err());
+        assert!(!kernel.set_sepolicy(std::ptr::null()));
+    }
+}
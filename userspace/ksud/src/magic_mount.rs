@@ -1,6 +1,5 @@
-use crate::defs::{
-    DISABLE_FILE_NAME, KSU_MOUNT_SOURCE, MAGIC_MOUNT_WORK_DIR, MODULE_DIR, SKIP_MOUNT_FILE_NAME,
-};
+use crate::defs;
+use crate::defs::{KSU_MOUNT_SOURCE, MAGIC_MOUNT_WORK_DIR, SKIP_MOUNT_FILE_NAME};
 use crate::magic_mount::NodeFileType::{Directory, RegularFile, Symlink, Whiteout};
 use crate::restorecon::{lgetfilecon, lsetfilecon};
 use crate::utils::ensure_dir_exists;
@@ -125,25 +124,19 @@ impl Node {
 fn collect_module_files() -> Result<Option<Node>> {
     let mut root = Node::new_root("");
     let mut system = Node::new_root("system");
-    let module_root = Path::new(MODULE_DIR);
     let mut has_file = false;
-    for entry in module_root.read_dir()?.flatten() {
-        if !entry.file_type()?.is_dir() {
-            continue;
-        }
-
-        if entry.path().join(DISABLE_FILE_NAME).exists()
-            || entry.path().join(SKIP_MOUNT_FILE_NAME).exists()
-        {
+    // the first module collecting a file owns it, so later modules in the order go first
+    for module in crate::module::sorted_active_modules()?.into_iter().rev() {
+        if module.join(SKIP_MOUNT_FILE_NAME).exists() {
             continue;
         }
 
-        let mod_system = entry.path().join("system");
+        let mod_system = module.join("system");
         if !mod_system.is_dir() {
             continue;
         }
 
-        log::debug!("collecting {}", entry.path().display());
+        log::debug!("collecting {}", module.display());
 
         has_file |= system.collect_module_files(&mod_system)?;
     }
@@ -417,7 +410,7 @@ fn do_magic_mount<P: AsRef<Path>, WP: AsRef<Path>>(
 pub fn magic_mount() -> Result<()> {
     if let Some(root) = collect_module_files()? {
         log::debug!("collected: {:#?}", root);
-        let tmp_dir = PathBuf::from(MAGIC_MOUNT_WORK_DIR);
+        let tmp_dir = defs::resolve(MAGIC_MOUNT_WORK_DIR);
         ensure_dir_exists(&tmp_dir)?;
         mount(KSU_MOUNT_SOURCE, &tmp_dir, "tmpfs", MountFlags::empty(), "").context("mount tmp")?;
         mount_change(&tmp_dir, MountPropagationFlags::PRIVATE).context("make tmp private")?;
@@ -1,7 +1,11 @@
+mod allowlist;
 mod apk_sign;
 mod assets;
+mod backup;
 mod boot_patch;
+mod cgroup;
 mod cli;
+mod config;
 mod debug;
 mod defs;
 mod init_event;
@@ -9,10 +13,22 @@ mod ksucalls;
 #[cfg(target_os = "android")]
 mod magic_mount;
 mod module;
+mod module_deps;
+mod module_manifest;
+mod module_prop;
+mod module_state;
+mod output;
+mod packages;
 mod profile;
 mod restorecon;
+mod seclabel;
+mod safemode;
 mod sepolicy;
+mod signing;
 mod su;
+mod supervisor;
+mod timeline;
+mod unzip;
 mod utils;
 
 fn main() -> anyhow::Result<()> {
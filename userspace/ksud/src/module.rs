@@ -1,9 +1,15 @@
+use crate::module_deps::{self, ModuleInfo};
+use crate::module_manifest;
+use crate::module_prop::{self, ModuleProp};
+use crate::supervisor::RestartPolicy;
 #[allow(clippy::wildcard_imports)]
 use crate::utils::*;
 use crate::{
-    assets, defs, ksucalls,
-    restorecon::{restore_syscon, setsyscon},
-    sepolicy,
+    assets, cgroup,
+    config::{self, ModuleOrder, VerifyPolicy},
+    defs, ksucalls, output,
+    restorecon::{lsetfilecon, restore_syscon, setsyscon},
+    seclabel, sepolicy, signing, timeline, unzip,
 };
 
 use anyhow::{anyhow, bail, ensure, Context, Result};
@@ -19,8 +25,9 @@ use std::{
     fs::{remove_dir_all, remove_file, set_permissions, File, Permissions},
     io::Cursor,
     path::{Path, PathBuf},
-    process::Command,
+    process::{Child, Command},
     str::FromStr,
+    time::{Duration, Instant},
 };
 use zip_extensions::zip_extract_file_to_memory;
 
@@ -28,6 +35,37 @@ use crate::defs::{MODULE_DIR, MODULE_UPDATE_DIR, UPDATE_FILE_NAME};
 #[cfg(unix)]
 use std::os::unix::{prelude::PermissionsExt, process::CommandExt};
 
+// stages with a log of module scripts, in the order they run
+const LOG_STAGES: [&str; 6] = [
+    "post-fs-data",
+    "post-mount",
+    "service",
+    "boot-completed",
+    "action",
+    "uninstall",
+];
+
+// sh -c <wrapper> sh <echo> <script>, with the log opened by ksud on stderr.
+// the script may run in a domain that can't open the log itself.
+// the wrapper outlives ksud for background scripts, so it writes the exit code itself
+const SCRIPT_LOG_WRAPPER: &str = r##"echo="$1"; script="$2"
+exec 3>&2
+echo "# $script started at $(date -Iseconds)" >&3
+start=$(date +%s)
+if [ "$echo" = true ]; then
+    set -o pipefail
+    sh "$script" 2>&1 | while IFS= read -r line || [ -n "$line" ]; do
+        printf '%s\n' "$line"
+        printf '%s\n' "$line" >&3
+    done
+else
+    sh "$script" >&3 2>&1
+fi
+code=$?
+echo "# exit code $code after $(($(date +%s) - start))s" >&3
+exit $code
+"##;
+
 const INSTALLER_CONTENT: &str = include_str!("./installer.sh");
 const INSTALL_MODULE_SCRIPT: &str = concatcp!(
     INSTALLER_CONTENT,
@@ -38,11 +76,12 @@ const INSTALL_MODULE_SCRIPT: &str = concatcp!(
     "\n"
 );
 
-fn exec_install_script(module_file: &str) -> Result<()> {
+fn exec_install_script(module_file: &str, module_id: &str) -> Result<()> {
     let realpath = std::fs::canonicalize(module_file)
         .with_context(|| format!("realpath: {module_file} failed"))?;
 
-    let result = Command::new(assets::BUSYBOX_PATH)
+    let mut command = Command::new(defs::resolve(assets::BUSYBOX_PATH));
+    command
         .args(["sh", "-c", INSTALL_MODULE_SCRIPT])
         .env("ASH_STANDALONE", "1")
         .env(
@@ -50,16 +89,21 @@ fn exec_install_script(module_file: &str) -> Result<()> {
             format!(
                 "{}:{}",
                 env_var("PATH").unwrap(),
-                defs::BINARY_DIR.trim_end_matches('/')
+                defs::resolve(defs::BINARY_DIR).display()
             ),
         )
         .env("KSU", "true")
+        .env("KSU_ROOT", defs::root())
         .env("KSU_KERNEL_VER_CODE", ksucalls::get_version().to_string())
         .env("KSU_VER", defs::VERSION_NAME)
         .env("KSU_VER_CODE", defs::VERSION_CODE)
         .env("OUTFD", "1")
         .env("ZIPFILE", realpath)
-        .status()?;
+        .env("MODID", module_id)
+        // the zip is already extracted to modules_update/<id>, where the installer works
+        .env("KSU_EXTRACTED", "true")
+        .env("BOOTMODE", "true");
+    let result = output::run_command(&mut command)?;
     ensure!(result.success(), "Failed to install module script");
     Ok(())
 }
@@ -68,7 +112,11 @@ fn exec_install_script(module_file: &str) -> Result<()> {
 // we need to update the module state after the boot_completed
 // if someone(such as the module) install a module before the boot_completed
 // then it may cause some problems, just forbid it
-fn ensure_boot_completed() -> Result<()> {
+pub fn ensure_boot_completed() -> Result<()> {
+    // a relocated tree is not the running system, nothing to race with
+    if defs::has_custom_root() {
+        return Ok(());
+    }
     // ensure getprop sys.boot_completed == 1
     if getprop("sys.boot_completed").as_deref() != Some("1") {
         bail!("Android is Booting!");
@@ -77,7 +125,7 @@ fn ensure_boot_completed() -> Result<()> {
 }
 
 fn mark_module_state(module: &str, flag_file: &str, create: bool) -> Result<()> {
-    let module_state_file = Path::new(MODULE_DIR).join(module).join(flag_file);
+    let module_state_file = defs::resolve(MODULE_DIR).join(module).join(flag_file);
     if create {
         ensure_file_exists(module_state_file)
     } else {
@@ -95,8 +143,20 @@ enum ModuleType {
     Updated,
 }
 
+/// Whether <module> is loaded on boot, i.e. neither disabled, removed nor incompatible
+pub fn is_active(module: &Path) -> bool {
+    module.is_dir()
+        && ![
+            defs::DISABLE_FILE_NAME,
+            defs::REMOVE_FILE_NAME,
+            defs::INCOMPATIBLE_FILE_NAME,
+        ]
+        .iter()
+        .any(|flag| module.join(flag).exists())
+}
+
 fn foreach_module(module_type: ModuleType, mut f: impl FnMut(&Path) -> Result<()>) -> Result<()> {
-    let modules_dir = Path::new(match module_type {
+    let modules_dir = defs::resolve(match module_type {
         ModuleType::Updated => MODULE_UPDATE_DIR,
         _ => defs::MODULE_DIR,
     });
@@ -104,7 +164,7 @@ fn foreach_module(module_type: ModuleType, mut f: impl FnMut(&Path) -> Result<()
         warn!("{} is not a directory, skip", modules_dir.display());
         return Ok(());
     }
-    let dir = std::fs::read_dir(modules_dir)?;
+    let dir = std::fs::read_dir(&modules_dir)?;
     for entry in dir.flatten() {
         let path = entry.path();
         if !path.is_dir() {
@@ -116,6 +176,10 @@ fn foreach_module(module_type: ModuleType, mut f: impl FnMut(&Path) -> Result<()
             info!("{} is disabled, skip", path.display());
             continue;
         }
+        if module_type == ModuleType::Active && path.join(defs::INCOMPATIBLE_FILE_NAME).exists() {
+            info!("{} is incompatible, skip", path.display());
+            continue;
+        }
         if module_type == ModuleType::Active && path.join(defs::REMOVE_FILE_NAME).exists() {
             warn!("{} is removed, skip", path.display());
             continue;
@@ -127,11 +191,122 @@ fn foreach_module(module_type: ModuleType, mut f: impl FnMut(&Path) -> Result<()
     Ok(())
 }
 
-fn foreach_active_module(f: impl FnMut(&Path) -> Result<()>) -> Result<()> {
-    foreach_module(ModuleType::Active, f)
+fn read_prop_file(file: &Path) -> Result<HashMap<String, String>> {
+    parse_props(std::fs::read(file)?)
+}
+
+fn parse_props(content: Vec<u8>) -> Result<HashMap<String, String>> {
+    let mut props = HashMap::new();
+    PropertiesIter::new_with_encoding(Cursor::new(content), encoding_rs::UTF_8).read_into(
+        |k, v| {
+            props.insert(k, v);
+        },
+    )?;
+    Ok(props)
+}
+
+// module.prop to be installed, installer.sh would read a repeated key differently
+fn parse_new_module_prop(content: Vec<u8>) -> Result<HashMap<String, String>> {
+    let mut props = HashMap::new();
+    let mut duplicates = Vec::new();
+    PropertiesIter::new_with_encoding(Cursor::new(content), encoding_rs::UTF_8).read_into(
+        |k, v| {
+            if props.insert(k.clone(), v).is_some() {
+                duplicates.push(k);
+            }
+        },
+    )?;
+    ensure!(
+        duplicates.is_empty(),
+        "duplicate keys in module.prop: {}",
+        duplicates.join(", ")
+    );
+    Ok(props)
+}
+
+pub fn read_module_prop(module: &Path) -> Result<HashMap<String, String>> {
+    read_prop_file(&module.join("module.prop"))
+}
+
+fn read_module_info(module: &Path) -> ModuleInfo {
+    let id = module
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let module_prop = read_module_prop(module).unwrap_or_else(|e| {
+        warn!("Failed to read module.prop of {}: {}", module.display(), e);
+        HashMap::new()
+    });
+    ModuleInfo::from_prop(&id, module, &module_prop)
+}
+
+// check minKsuVersion, maxKsuVersion and minKernelVersion in module.prop
+fn check_compatibility(module_prop: &HashMap<String, String>) -> Result<()> {
+    let get = |key: &str| -> Result<Option<i64>> {
+        module_prop
+            .get(key)
+            .map(|v| v.trim())
+            .filter(|v| !v.is_empty())
+            .map(|v| {
+                v.parse::<i64>()
+                    .with_context(|| format!("invalid {key}: {v}"))
+            })
+            .transpose()
+    };
+    let ksu_version = defs::VERSION_CODE_NUM;
+    let kernel_version = i64::from(ksucalls::get_version());
+
+    if let Some(min) = get("minKsuVersion")? {
+        ensure!(
+            ksu_version >= min,
+            "module requires KernelSU {min} or newer, current is {ksu_version}"
+        );
+    }
+    if let Some(max) = get("maxKsuVersion")? {
+        ensure!(
+            ksu_version <= max,
+            "module supports KernelSU up to {max}, current is {ksu_version}"
+        );
+    }
+    if let Some(min) = get("minKernelVersion")? {
+        ensure!(
+            kernel_version >= min,
+            "module requires KernelSU kernel {min} or newer, current is {kernel_version}"
+        );
+    }
+    Ok(())
+}
+
+fn active_module_infos() -> Result<Vec<ModuleInfo>> {
+    let mut modules = Vec::new();
+    foreach_module(ModuleType::Active, |module| {
+        modules.push(read_module_info(module));
+        Ok(())
+    })?;
+    Ok(modules)
+}
+
+/// Active modules whose requirements are met, in dependency order
+pub fn sorted_active_modules() -> Result<Vec<PathBuf>> {
+    let mut modules = module_deps::resolve(active_module_infos()?);
+    if config::get().modules.order == ModuleOrder::Id {
+        modules.sort_by(|a, b| a.id.cmp(&b.id));
+    }
+    Ok(modules.into_iter().map(|m| m.path).collect())
+}
+
+fn foreach_active_module(mut f: impl FnMut(&Path) -> Result<()>) -> Result<()> {
+    for module in sorted_active_modules()? {
+        f(&module)?;
+    }
+    Ok(())
 }
 
 pub fn load_sepolicy_rule() -> Result<()> {
+    if let Err(e) = seclabel::define_module_domain() {
+        warn!("Failed to define module domain: {e:#}");
+    }
+
     foreach_active_module(|path| {
         let rule_file = path.join("sepolicy.rule");
         if !rule_file.exists() {
@@ -148,25 +323,171 @@ pub fn load_sepolicy_rule() -> Result<()> {
     Ok(())
 }
 
-fn exec_script<T: AsRef<Path>>(path: T, wait: bool) -> Result<()> {
-    info!("exec {}", path.as_ref().display());
+/// Kill the process group of a script when it runs longer than `limit`
+struct Watchdog {
+    limit: Duration,
+    // file to record the timeout to
+    record: Option<PathBuf>,
+}
+
+/// Capture the output of a script to `path`
+struct ScriptLog {
+    path: PathBuf,
+    // also pass the output through, for scripts run by the user
+    echo: bool,
+}
 
-    let mut command = &mut Command::new(assets::BUSYBOX_PATH);
+impl ScriptLog {
+    fn new(path: PathBuf) -> Self {
+        ScriptLog { path, echo: false }
+    }
+
+    fn rotate(&self) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            ensure_dir_exists(parent)?;
+        }
+        rotate_log(&self.path, config::get().log.retention)
+    }
+
+    // truncated for this run, the label lets a confined script write to it
+    fn open(&self, confined: bool) -> Result<std::fs::File> {
+        let file = std::fs::File::create(&self.path)
+            .with_context(|| format!("Failed to open {}", self.path.display()))?;
+        if confined {
+            lsetfilecon(&self.path, seclabel::MODULE_LOG_CON)?;
+        }
+        Ok(file)
+    }
+
+    fn append(&self, line: &str) {
+        use std::io::Write;
+        let result = std::fs::OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&self.path)
+            .and_then(|mut f| writeln!(f, "{line}"));
+        if let Err(e) = result {
+            warn!("Failed to write {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+fn module_log(module: &Path, stage: &str) -> Option<ScriptLog> {
+    let id = module.file_name()?;
+    Some(ScriptLog::new(
+        defs::resolve(defs::MODULE_LOG_DIR)
+            .join(id)
+            .join(format!("{stage}.log")),
+    ))
+}
+
+fn record_timeout(record: &Path, limit: Duration) {
+    let reason = format!(
+        "timeout after {}s at {}\n",
+        limit.as_secs(),
+        chrono::Local::now().to_rfc3339()
+    );
+    if let Err(e) = record
+        .parent()
+        .map_or(Ok(()), ensure_dir_exists)
+        .and_then(|_| Ok(std::fs::write(record, reason)?))
+    {
+        warn!("Failed to record timeout to {}: {}", record.display(), e);
+    }
+}
+
+// a script without its log is better than no script
+fn prepare_log(log: Option<ScriptLog>) -> Option<ScriptLog> {
+    log.filter(|log| match log.rotate() {
+        Ok(()) => true,
+        Err(e) => {
+            warn!("Failed to prepare {}: {}", log.path.display(), e);
+            false
+        }
+    })
+}
+
+/// Where the scripts of a module run
+#[derive(Default)]
+struct Sandbox {
+    cgroup: Option<PathBuf>,
+    seclabel: Option<String>,
+}
+
+// cgroup and SELinux context of the scripts of <module>, from its module.prop
+fn module_sandbox(module: &Path, module_prop: Option<&HashMap<String, String>>) -> Result<Sandbox> {
+    let empty = HashMap::new();
+    let module_prop = module_prop.unwrap_or(&empty);
+    let id = module
+        .file_name()
+        .map(|id| id.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let cgroup = match cgroup::prepare(&id, module_prop) {
+        Ok(cgroup) => cgroup,
+        Err(e) => {
+            warn!("{e:#}");
+            None
+        }
+    };
+    // an invalid label must not fall back to the privilege of ksud
+    let seclabel = seclabel::of_module(module_prop)
+        .with_context(|| format!("Refuse to run scripts of {id}"))?;
+    Ok(Sandbox { cgroup, seclabel })
+}
+
+// start <path> in its own process group, confined by <sandbox>
+fn spawn_script(path: &Path, log: Option<&ScriptLog>, sandbox: Sandbox) -> Result<Child> {
+    let context = sandbox
+        .seclabel
+        .as_ref()
+        .map(|label| format!(" in {label}"))
+        .unwrap_or_default();
+    // a script without its log is better than no script
+    let log = log.and_then(|log| match log.open(sandbox.seclabel.is_some()) {
+        Ok(file) => Some((log, file)),
+        Err(e) => {
+            warn!("{e:#}");
+            None
+        }
+    });
+    let mut command = &mut Command::new(defs::resolve(assets::BUSYBOX_PATH));
     #[cfg(unix)]
     {
+        // supervisor threads spawn scripts too, nothing may be allocated after fork
+        let cgroups = CgroupSwitch::prepare();
+        let join_cgroup = sandbox.cgroup.as_deref().map(cgroup::join).transpose()?;
+        let exec_context = sandbox
+            .seclabel
+            .as_deref()
+            .map(seclabel::exec_context)
+            .transpose()?;
         command = command.process_group(0);
         command = unsafe {
-            command.pre_exec(|| {
+            command.pre_exec(move || {
                 // ignore the error?
-                switch_cgroups();
+                cgroups.run();
+                // both fail the spawn, rather than running with fewer limits
+                // or more privilege than asked
+                if let Some(join_cgroup) = &join_cgroup {
+                    join_cgroup.run()?;
+                }
+                if let Some(exec_context) = &exec_context {
+                    exec_context.run()?;
+                }
                 Ok(())
             })
         };
     }
+    command = command.current_dir(path.parent().unwrap()).arg("sh");
+    command = match log {
+        Some((log, file)) => command
+            .args(["-c", SCRIPT_LOG_WRAPPER, "sh"])
+            .arg(log.echo.to_string())
+            .arg(path)
+            .stderr(file),
+        None => command.arg(path),
+    };
     command = command
-        .current_dir(path.as_ref().parent().unwrap())
-        .arg("sh")
-        .arg(path.as_ref())
         .env("ASH_STANDALONE", "1")
         .env("KSU", "true")
         .env("KSU_KERNEL_VER_CODE", ksucalls::get_version().to_string())
@@ -177,38 +498,232 @@ fn exec_script<T: AsRef<Path>>(path: T, wait: bool) -> Result<()> {
             format!(
                 "{}:{}",
                 env_var("PATH").unwrap(),
-                defs::BINARY_DIR.trim_end_matches('/')
+                defs::resolve(defs::BINARY_DIR).display()
             ),
         );
 
-    let result = if wait {
-        command.status().map(|_| ())
-    } else {
-        command.spawn().map(|_| ())
+    command
+        .spawn()
+        .map_err(|err| anyhow!("Failed to exec {}{}: {}", path.display(), context, err))
+}
+
+/// Start service.sh of <module> for the supervisor, see [`crate::supervisor`]
+pub fn spawn_service_script(module: &Path) -> Result<Child> {
+    let script = module.join("service.sh");
+    info!("exec {}", script.display());
+    let log = prepare_log(module_log(module, "service"));
+    let sandbox = module_sandbox(module, read_module_prop(module).ok().as_ref())?;
+    spawn_script(&script, log.as_ref(), sandbox)
+}
+
+fn exec_script<T: AsRef<Path>>(
+    path: T,
+    wait: bool,
+    watchdog: Option<Watchdog>,
+    log: Option<ScriptLog>,
+    sandbox: Sandbox,
+) -> Result<()> {
+    info!("exec {}", path.as_ref().display());
+
+    let log = prepare_log(log);
+    let mut child = spawn_script(path.as_ref(), log.as_ref(), sandbox)?;
+    let Some(watchdog) = watchdog else {
+        if wait {
+            child.wait()?;
+        }
+        return Ok(());
+    };
+
+    if !wait {
+        return spawn_watchdog(child.id(), &watchdog, log.as_ref());
+    }
+
+    let deadline = Instant::now() + watchdog.limit;
+    while child.try_wait()?.is_none() {
+        if Instant::now() >= deadline {
+            kill_process_group(child.id());
+            child.wait()?;
+            if let Some(record) = &watchdog.record {
+                record_timeout(record, watchdog.limit);
+            }
+            if let Some(log) = &log {
+                log.append(&format!(
+                    "# killed after timeout of {}s",
+                    watchdog.limit.as_secs()
+                ));
+            }
+            bail!(
+                "{} timed out after {}s, killed",
+                path.as_ref().display(),
+                watchdog.limit.as_secs()
+            );
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    Ok(())
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn kill_process_group(pid: u32) {
+    use rustix::process::{kill_process_group, Pid, Signal};
+    if let Some(pid) = Pid::from_raw(pid as i32) {
+        if let Err(e) = kill_process_group(pid, Signal::Kill) {
+            warn!("Failed to kill process group {pid:?}: {e}");
+        }
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn kill_process_group(_pid: u32) {
+    unimplemented!()
+}
+
+// starttime of /proc/<pid>/stat, which tells a reused pid apart
+fn process_start_time(pid: u32) -> Option<String> {
+    let stat = std::fs::read_to_string(format!("/proc/{pid}/stat")).ok()?;
+    // the command name may contain spaces, fields after it are counted from the state
+    let (_, fields) = stat.rsplit_once(") ")?;
+    fields.split(' ').nth(19).map(str::to_string)
+}
+
+// ksud exits before background scripts, so the watchdog is a detached shell.
+// the pid of the script may be reused by then, only a group whose leader has the same
+// start time or is gone is killed, a pid isn't reused while its group has members.
+fn spawn_watchdog(pgid: u32, watchdog: &Watchdog, log: Option<&ScriptLog>) -> Result<()> {
+    let seconds = watchdog.limit.as_secs();
+    let start_time = process_start_time(pgid).context("Failed to read start time of script")?;
+    let mut record = match &watchdog.record {
+        Some(record) => {
+            if let Some(parent) = record.parent() {
+                ensure_dir_exists(parent)?;
+            }
+            format!(
+                " && echo \"timeout after {seconds}s at $(date -Iseconds)\" > '{}'",
+                record.display()
+            )
+        }
+        None => String::new(),
     };
-    result.map_err(|err| anyhow!("Failed to exec {}: {}", path.as_ref().display(), err))
+    if let Some(log) = log {
+        record.push_str(&format!(
+            "; echo '# killed after timeout of {seconds}s' >> '{}'",
+            log.path.display()
+        ));
+    }
+    let script = format!(
+        "sleep {seconds}; \
+         stat=$(cat /proc/{pgid}/stat 2>/dev/null) && \
+         [ \"$(echo \"${{stat##*\\) }}\" | cut -d' ' -f20)\" != {start_time} ] && exit; \
+         kill -0 -- -{pgid} 2>/dev/null && kill -9 -- -{pgid}{record}"
+    );
+
+    let mut command = &mut Command::new(defs::resolve(assets::BUSYBOX_PATH));
+    #[cfg(unix)]
+    {
+        let cgroups = CgroupSwitch::prepare();
+        command = command.process_group(0);
+        command = unsafe {
+            command.pre_exec(move || {
+                cgroups.run();
+                Ok(())
+            })
+        };
+    }
+    command
+        .args(["sh", "-c", &script])
+        .env("ASH_STANDALONE", "1")
+        .spawn()
+        .with_context(|| format!("Failed to start watchdog of process group {pgid}"))?;
+    Ok(())
+}
+
+fn parse_timeout(value: &str) -> Option<Option<Duration>> {
+    match value.trim().parse::<u64>() {
+        Ok(0) => Some(None),
+        Ok(seconds) => Some(Some(Duration::from_secs(seconds))),
+        Err(_) => {
+            warn!("invalid script timeout: {value}");
+            None
+        }
+    }
+}
+
+// time limit of <stage> scripts, from module.prop `timeout.<stage>`, then the config.
+// scripts are never limited unless configured, 0 means no limit
+fn stage_timeout(stage: &str, module_prop: Option<&HashMap<String, String>>) -> Option<Duration> {
+    if let Some(timeout) = module_prop
+        .and_then(|prop| prop.get(&format!("timeout.{stage}")))
+        .and_then(|v| parse_timeout(v))
+    {
+        return timeout;
+    }
+    config::get()
+        .scripts
+        .timeouts
+        .get(stage)
+        .filter(|&&seconds| seconds > 0)
+        .map(|&seconds| Duration::from_secs(seconds))
 }
 
 pub fn exec_stage_script(stage: &str, block: bool) -> Result<()> {
+    let record_root = defs::resolve(defs::MODULE_LOG_DIR);
     foreach_active_module(|module| {
         let script_path = module.join(format!("{stage}.sh"));
         if !script_path.exists() {
             return Ok(());
         }
 
-        exec_script(&script_path, block)
+        let module_prop = read_module_prop(module).ok();
+        // started by the supervisor afterwards
+        if stage == "service"
+            && module_prop
+                .as_ref()
+                .is_some_and(|prop| RestartPolicy::of(prop) != RestartPolicy::No)
+        {
+            return Ok(());
+        }
+
+        let record = module
+            .file_name()
+            .map(|id| record_root.join(id).join(format!("{stage}.failed")));
+        if let Some(record) = &record {
+            remove_file(record).ok();
+        }
+        let watchdog =
+            stage_timeout(stage, module_prop.as_ref()).map(|limit| Watchdog { limit, record });
+
+        let log = module_log(module, stage);
+        let log_path = log.as_ref().map(|log| log.path.clone());
+        let name = format!(
+            "{}/{stage}.sh",
+            module.file_name().unwrap().to_string_lossy()
+        );
+        // one hung script shouldn't stop the others
+        if let Err(e) = timeline::script(stage, &name, log_path, || {
+            let sandbox = module_sandbox(module, module_prop.as_ref())?;
+            exec_script(&script_path, block, watchdog, log, sandbox)
+        }) {
+            warn!("{e:#}");
+        }
+        Ok(())
     })?;
 
+    if stage == "service" {
+        crate::supervisor::start()?;
+    }
     Ok(())
 }
 
 pub fn exec_common_scripts(dir: &str, wait: bool) -> Result<()> {
-    let script_dir = Path::new(defs::ADB_DIR).join(dir);
+    let script_dir = defs::resolve(defs::ADB_DIR).join(dir);
     if !script_dir.exists() {
         info!("{} not exists, skip", script_dir.display());
         return Ok(());
     }
 
+    let stage = dir.trim_end_matches(".d");
+    let script_dir_name = dir;
+    let log_dir = defs::resolve(defs::COMMON_SCRIPT_LOG_DIR).join(dir);
     let dir = std::fs::read_dir(&script_dir)?;
     for entry in dir.flatten() {
         let path = entry.path();
@@ -218,7 +733,19 @@ pub fn exec_common_scripts(dir: &str, wait: bool) -> Result<()> {
             continue;
         }
 
-        exec_script(path, wait)?;
+        let watchdog = stage_timeout(stage, None).map(|limit| Watchdog {
+            limit,
+            record: None,
+        });
+        let file_name = entry.file_name().to_string_lossy().to_string();
+        let log = ScriptLog::new(log_dir.join(format!("{file_name}.log")));
+        let log_path = Some(log.path.clone());
+        let name = format!("{script_dir_name}/{file_name}");
+        if let Err(e) = timeline::script(stage, &name, log_path, || {
+            exec_script(path, wait, watchdog, Some(log), Sandbox::default())
+        }) {
+            warn!("{e:#}");
+        }
     }
 
     Ok(())
@@ -233,7 +760,7 @@ pub fn load_system_prop() -> Result<()> {
         info!("load {} system.prop", module.display());
 
         // resetprop -n --file system.prop
-        Command::new(assets::RESETPROP_PATH)
+        Command::new(defs::resolve(assets::RESETPROP_PATH))
             .arg("-n")
             .arg("--file")
             .arg(&system_prop)
@@ -253,7 +780,8 @@ pub fn prune_modules() -> Result<()> {
 
             let uninstaller = module.join("uninstall.sh");
             if uninstaller.exists() {
-                if let Err(e) = exec_script(uninstaller, true) {
+                let log = module_log(module, "uninstall");
+                if let Err(e) = exec_script(uninstaller, true, None, log, Sandbox::default()) {
                     warn!("Failed to exec uninstaller: {}", e);
                 }
             }
@@ -261,6 +789,12 @@ pub fn prune_modules() -> Result<()> {
             if let Err(e) = remove_dir_all(module) {
                 warn!("Failed to remove {}: {}", module.display(), e);
             }
+            if let Some(name) = module.file_name() {
+                let snapshot = defs::resolve(defs::MODULE_SNAPSHOT_DIR).join(name);
+                if snapshot.exists() {
+                    remove_dir_all(&snapshot).ok();
+                }
+            }
         } else {
             remove_file(module.join(defs::UPDATE_FILE_NAME)).ok();
         }
@@ -270,22 +804,142 @@ pub fn prune_modules() -> Result<()> {
     Ok(())
 }
 
+// whether <module> is only the module.prop placeholder of a fresh installation
+pub fn is_placeholder(module: &Path) -> bool {
+    let Ok(dir) = std::fs::read_dir(module) else {
+        return false;
+    };
+    dir.flatten().all(|entry| {
+        [
+            "module.prop",
+            defs::PREVIOUS_MODULE_PROP,
+            defs::UPDATE_FILE_NAME,
+            defs::DISABLE_FILE_NAME,
+            defs::SKIP_MOUNT_FILE_NAME,
+        ]
+        .contains(&entry.file_name().to_string_lossy().as_ref())
+    })
+}
+
+// keep module.prop of the running version, the one in module dir is replaced by the pending update
+fn stash_module_prop(module: &Path) -> Result<()> {
+    let module_prop = module.join("module.prop");
+    if module_prop.exists() && !module.join(UPDATE_FILE_NAME).exists() {
+        copy(&module_prop, module.join(defs::PREVIOUS_MODULE_PROP))?;
+    }
+    Ok(())
+}
+
+// undo stash_module_prop and what installer.sh did to the module dir
+fn unstash_module_prop(module: &Path) -> Result<()> {
+    let previous_prop = module.join(defs::PREVIOUS_MODULE_PROP);
+    if previous_prop.exists() {
+        rename(previous_prop, module.join("module.prop"))?;
+    }
+    let flag = module.join(UPDATE_FILE_NAME);
+    if flag.exists() {
+        remove_file(flag)?;
+    }
+    Ok(())
+}
+
+/// Mark module <id> as updated, its new version in modules_update takes effect after reboot
+pub fn mark_module_updated(id: &str) -> Result<()> {
+    mark_updated(
+        &defs::resolve(MODULE_DIR).join(id),
+        &defs::resolve(MODULE_UPDATE_DIR).join(id),
+    )
+}
+
+/// Show the update staged in <update_dir> in <module_dir>, until it's applied on boot
+pub fn mark_updated(module_dir: &Path, update_dir: &Path) -> Result<()> {
+    ensure_dir_exists(module_dir)?;
+    stash_module_prop(module_dir)?;
+    copy(
+        update_dir.join("module.prop"),
+        module_dir.join("module.prop"),
+    )?;
+    ensure_file_exists(module_dir.join(UPDATE_FILE_NAME))?;
+    Ok(())
+}
+
+// replace <current> with <new>, keeping <current> as <snapshot>
+fn swap_module(new: &Path, current: &Path, snapshot: &Path) -> Result<()> {
+    if current.exists() && !is_placeholder(current) {
+        if snapshot.exists() {
+            remove_dir_all(snapshot)?;
+        }
+        rename(current, snapshot)?;
+        let previous_prop = snapshot.join(defs::PREVIOUS_MODULE_PROP);
+        if previous_prop.exists() {
+            rename(previous_prop, snapshot.join("module.prop"))?;
+        }
+    } else if current.exists() {
+        remove_dir_all(current)?;
+    }
+
+    if let Err(e) = rename(new, current) {
+        // keep running the old version, the update is retried on next boot
+        if snapshot.exists() && !current.exists() {
+            rename(snapshot, current)?;
+        }
+        return Err(e).with_context(|| format!("Failed to move {}", new.display()));
+    }
+    Ok(())
+}
+
 pub fn handle_updated_modules() -> Result<()> {
-    let modules_root = Path::new(MODULE_DIR);
+    let modules_root = defs::resolve(MODULE_DIR);
+    let snapshot_root = defs::resolve(defs::MODULE_SNAPSHOT_DIR);
+    ensure_dir_exists(&snapshot_root)?;
     foreach_module(ModuleType::Updated, |module| {
         if !module.is_dir() {
             return Ok(());
         }
 
         if let Some(name) = module.file_name() {
-            let old_dir = modules_root.join(name);
-            if old_dir.exists() {
-                if let Err(e) = remove_dir_all(&old_dir) {
-                    log::error!("Failed to remove old {}: {}", old_dir.display(), e);
+            // staged by an older ksud or a restored backup, it's checked only here
+            match ModuleProp::load(module) {
+                Ok(prop) if prop.id.as_str() == name => {}
+                result => {
+                    let reason = result.map_or_else(
+                        |e| format!("{e:#}"),
+                        |prop| format!("id {} doesn't match the directory", prop.id),
+                    );
+                    log::error!("Drop invalid update {}: {reason}", module.display());
+                    remove_dir_all(module).ok();
+                    return Ok(());
+                }
+            }
+            let current = modules_root.join(name);
+            match swap_module(module, &current, &snapshot_root.join(name)) {
+                Ok(()) => crate::safemode::record_update(&name.to_string_lossy()),
+                Err(e) => log::error!("Failed to update {}: {:?}", current.display(), e),
+            }
+        }
+        Ok(())
+    })?;
+
+    // ksud may be downgraded since the modules were installed, or upgraded back
+    foreach_module(ModuleType::All, |module| {
+        let Ok(module_prop) = read_module_prop(module) else {
+            return Ok(());
+        };
+        let marker = module.join(defs::INCOMPATIBLE_FILE_NAME);
+        match check_compatibility(&module_prop) {
+            Ok(()) if marker.exists() => {
+                info!("{} is compatible again, load it", module.display());
+                if let Err(e) = remove_file(&marker) {
+                    warn!("Failed to remove {}: {e}", marker.display());
                 }
             }
-            if let Err(e) = rename(module, &old_dir) {
-                log::error!("Failed to move new module {}: {}", module.display(), e);
+            Ok(()) => {}
+            Err(e) => {
+                warn!("skip incompatible module {}: {}", module.display(), e);
+                // one module must not keep the others from being checked
+                if let Err(e) = std::fs::write(&marker, format!("{e}\n")) {
+                    warn!("Failed to write {}: {e}", marker.display());
+                }
             }
         }
         Ok(())
@@ -293,18 +947,65 @@ pub fn handle_updated_modules() -> Result<()> {
     Ok(())
 }
 
+// space left free after an install, the system needs some to keep running
+const INSTALL_RESERVE: u64 = 32 << 20;
+// /data/adb is nearly full when less than this percentage is free
+const LOW_SPACE_PERCENT: u64 = 5;
+
+fn check_free_space(id: &str, size: u64) -> Result<()> {
+    let adb_dir = defs::resolve(defs::ADB_DIR);
+    let (available, total) = disk_space(&adb_dir)?;
+    // a pending update of the module is replaced
+    let pending = defs::resolve(MODULE_UPDATE_DIR).join(id);
+    let available = available
+        + if pending.is_dir() {
+            dir_size(&pending)
+        } else {
+            0
+        };
+    // the installer may copy files of the installed version, e.g. to keep its settings
+    let current = defs::resolve(MODULE_DIR).join(id);
+    let existing = if current.is_dir() {
+        dir_size(&current)
+    } else {
+        0
+    };
+
+    let needed = size + existing + INSTALL_RESERVE;
+    let format = |bytes| humansize::format_size(bytes, humansize::DECIMAL);
+    ensure!(
+        available >= needed,
+        "Not enough space in {}: {} needed, {} available. Free up space on /data or uninstall unused modules, then try again",
+        adb_dir.display(),
+        format(needed),
+        format(available)
+    );
+    if available - needed < total * LOW_SPACE_PERCENT / 100 {
+        output::progress(format!(
+            "Warning: {} is nearly full, {} left after the installation",
+            adb_dir.display(),
+            format(available - size - existing)
+        ));
+    }
+    Ok(())
+}
+
 pub fn install_module(zip: &str) -> Result<()> {
     fn inner(zip: &str) -> Result<()> {
         ensure_boot_completed()?;
 
         // print banner
-        println!(include_str!("banner"));
+        if !output::is_json() {
+            println!(include_str!("banner"));
+        }
 
         assets::ensure_binaries(false).with_context(|| "Failed to extract assets")?;
 
         // first check if working dir is usable
-        ensure_dir_exists(defs::WORKING_DIR).with_context(|| "Failed to create working dir")?;
-        ensure_dir_exists(defs::BINARY_DIR).with_context(|| "Failed to create bin dir")?;
+        ensure_dir_exists(defs::resolve(defs::WORKING_DIR))
+            .with_context(|| "Failed to create working dir")?;
+        ensure_dir_exists(defs::resolve(defs::BINARY_DIR))
+            .with_context(|| "Failed to create bin dir")?;
 
         // read the module_id from zip, if failed it will return early.
         let mut buffer: Vec<u8> = Vec::new();
@@ -313,45 +1014,53 @@ pub fn install_module(zip: &str) -> Result<()> {
         let zip_path = zip_path.canonicalize()?;
         zip_extract_file_to_memory(&zip_path, &entry_path, &mut buffer)?;
 
-        let mut module_prop = HashMap::new();
-        PropertiesIter::new_with_encoding(Cursor::new(buffer), encoding_rs::UTF_8).read_into(
-            |k, v| {
-                module_prop.insert(k, v);
-            },
-        )?;
+        // the id names the module directory, it must be checked before anything is written
+        let module_prop = ModuleProp::parse(parse_new_module_prop(buffer)?)?;
         info!("module prop: {:?}", module_prop);
+        let module_id = module_prop.id.as_str();
 
-        let Some(module_id) = module_prop.get("id") else {
-            bail!("module id not found in module.prop!");
-        };
-        let module_id = module_id.trim();
+        check_compatibility(&module_prop.props)?;
+
+        let module_info = ModuleInfo::from_prop(
+            module_id,
+            &defs::resolve(MODULE_DIR).join(module_id),
+            &module_prop.props,
+        );
+        module_deps::check_install(&module_info, &active_module_infos()?)?;
 
-        let zip_uncompressed_size = get_zip_uncompressed_size(zip)?;
+        // every entry is checked before anything is written
+        let mut archive = zip::ZipArchive::new(File::open(&zip_path)?)?;
+        let checked = unzip::check(&mut archive)?;
+        signing::check_policy(&zip_path)?;
+        let zip_uncompressed_size = checked.size;
 
         info!(
             "zip uncompressed size: {}",
             humansize::format_size(zip_uncompressed_size, humansize::DECIMAL)
         );
 
-        println!("- Preparing Zip");
-        println!(
-            "- Module size: {}",
+        output::progress("Preparing Zip");
+        output::progress(format!(
+            "Module size: {}",
             humansize::format_size(zip_uncompressed_size, humansize::DECIMAL)
-        );
+        ));
+        // a failed extraction may not be cleaned up, refuse early instead
+        check_free_space(module_id, zip_uncompressed_size)?;
 
         // ensure modules_update exists
-        ensure_dir_exists(MODULE_UPDATE_DIR)?;
-        setsyscon(MODULE_UPDATE_DIR)?;
+        let modules_update_dir = defs::resolve(MODULE_UPDATE_DIR);
+        ensure_dir_exists(&modules_update_dir)?;
+        setsyscon(&modules_update_dir)?;
 
-        let update_module_dir = Path::new(MODULE_UPDATE_DIR).join(module_id);
+        let update_module_dir = modules_update_dir.join(module_id);
         ensure_clean_dir(&update_module_dir)?;
+        let module_dir = defs::resolve(MODULE_DIR).join(module_id);
+        let pending = module_dir.join(UPDATE_FILE_NAME).exists();
         info!("module dir: {}", update_module_dir.display());
 
-        let do_install = || -> Result<()> {
+        let mut do_install = || -> Result<()> {
             // unzip the image and move it to modules_update/<id> dir
-            let file = File::open(zip)?;
-            let mut archive = zip::ZipArchive::new(file)?;
-            archive.extract(&update_module_dir)?;
+            unzip::extract(&mut archive, &checked, &update_module_dir)?;
 
             // set permission and selinux context for $MOD/system
             let module_system_dir = update_module_dir.join("system");
@@ -361,15 +1070,14 @@ pub fn install_module(zip: &str) -> Result<()> {
                 restore_syscon(&module_system_dir)?;
             }
 
-            exec_install_script(zip)?;
+            // installer.sh replaces module.prop of the running version
+            stash_module_prop(&module_dir)?;
+            exec_install_script(zip, module_id)?;
+
+            // after the installer, which may change the files and their permissions
+            module_manifest::write(&update_module_dir)?;
 
-            let module_dir = Path::new(MODULE_DIR).join(module_id);
-            ensure_dir_exists(&module_dir)?;
-            copy(
-                update_module_dir.join("module.prop"),
-                module_dir.join("module.prop"),
-            )?;
-            ensure_file_exists(module_dir.join(UPDATE_FILE_NAME))?;
+            mark_module_updated(module_id)?;
 
             info!("Module install successfully!");
 
@@ -378,23 +1086,57 @@ pub fn install_module(zip: &str) -> Result<()> {
         let result = do_install();
         if result.is_err() {
             remove_dir_all(&update_module_dir).ok();
+            if !pending {
+                unstash_module_prop(&module_dir).ok();
+            }
         }
         result
     }
     let result = inner(zip);
     if let Err(ref e) = result {
-        println!("- Error: {e}");
+        output::progress(format!("Error: {e}"));
     }
     result
 }
 
+pub fn rollback_module(id: &str) -> Result<()> {
+    ensure_boot_completed()?;
+
+    let snapshot = defs::resolve(defs::MODULE_SNAPSHOT_DIR).join(id);
+    ensure!(snapshot.is_dir(), "No previous version of {id} to rollback");
+
+    let update_dir = defs::resolve(MODULE_UPDATE_DIR).join(id);
+    ensure!(
+        !update_dir.exists(),
+        "Module {id} has a pending update, reboot first"
+    );
+    ensure_dir_exists(defs::resolve(MODULE_UPDATE_DIR))?;
+
+    // stage the snapshot as an update, so the current version becomes the snapshot after reboot
+    rename(&snapshot, &update_dir)?;
+    mark_module_updated(id)?;
+
+    let version = read_module_prop(&update_dir)
+        .ok()
+        .and_then(|prop| prop.get("version").cloned())
+        .unwrap_or_default();
+    output::progress(format!(
+        "{id} will be rolled back to {version} after reboot"
+    ));
+    Ok(())
+}
+
 pub fn uninstall_module(id: &str) -> Result<()> {
     mark_module_state(id, defs::REMOVE_FILE_NAME, true)
 }
 
 pub fn run_action(id: &str) -> Result<()> {
-    let action_script_path = format!("/data/adb/modules/{}/action.sh", id);
-    exec_script(&action_script_path, true)
+    let module = defs::resolve(MODULE_DIR).join(id);
+    let action_script_path = module.join(defs::MODULE_ACTION_SH);
+    // the manager shows the output of action.sh
+    let log = module_log(&module, "action").map(|log| ScriptLog { echo: true, ..log });
+    let sandbox = module_sandbox(&module, read_module_prop(&module).ok().as_ref())?;
+    exec_script(&action_script_path, true, None, log, sandbox)
 }
 
 pub fn enable_module(id: &str) -> Result<()> {
@@ -414,7 +1156,7 @@ pub fn uninstall_all_modules() -> Result<()> {
 }
 
 fn mark_all_modules(flag_file: &str) -> Result<()> {
-    let dir = std::fs::read_dir(MODULE_DIR)?;
+    let dir = std::fs::read_dir(defs::resolve(MODULE_DIR))?;
     for entry in dir.flatten() {
         let path = entry.path();
         let flag = path.join(flag_file);
@@ -426,7 +1168,7 @@ fn mark_all_modules(flag_file: &str) -> Result<()> {
     Ok(())
 }
 
-fn _list_modules(path: &str) -> Vec<HashMap<String, String>> {
+fn _list_modules(path: &Path) -> Vec<HashMap<String, String>> {
     // first check enabled modules
     let dir = std::fs::read_dir(path);
     let Ok(dir) = dir else {
@@ -454,6 +1196,10 @@ fn _list_modules(path: &str) -> Vec<HashMap<String, String>> {
                 module_prop_map.insert(k, v);
             });
 
+        // problems are listed rather than hiding the module
+        let (_, diagnostics) = ModuleProp::check(module_prop_map.clone());
+        module_prop_map.insert("errors".to_owned(), diagnostics.errors.join(", "));
+
         if !module_prop_map.contains_key("id") || module_prop_map["id"].is_empty() {
             if let Some(id) = entry.file_name().to_str() {
                 info!("Use dir name as module id: {}", id);
@@ -468,15 +1214,34 @@ fn _list_modules(path: &str) -> Vec<HashMap<String, String>> {
         let enabled = !path.join(defs::DISABLE_FILE_NAME).exists();
         let update = path.join(defs::UPDATE_FILE_NAME).exists();
         let remove = path.join(defs::REMOVE_FILE_NAME).exists();
+        let incompatible = std::fs::read_to_string(path.join(defs::INCOMPATIBLE_FILE_NAME))
+            .map(|reason| reason.trim().to_string())
+            .unwrap_or_default();
         let web = path.join(defs::MODULE_WEB_DIR).exists();
         let action = path.join(defs::MODULE_ACTION_SH).exists();
 
         module_prop_map.insert("enabled".to_owned(), enabled.to_string());
         module_prop_map.insert("update".to_owned(), update.to_string());
         module_prop_map.insert("remove".to_owned(), remove.to_string());
+        module_prop_map.insert("incompatible".to_owned(), incompatible);
         module_prop_map.insert("web".to_owned(), web.to_string());
         module_prop_map.insert("action".to_owned(), action.to_string());
 
+        // stages whose script timed out on last run
+        let mut failed: Vec<String> =
+            std::fs::read_dir(defs::resolve(defs::MODULE_LOG_DIR).join(&module_prop_map["id"]))
+                .map(|dir| {
+                    dir.flatten()
+                        .filter_map(|e| {
+                            let name = e.file_name().to_string_lossy().to_string();
+                            name.strip_suffix(".failed").map(str::to_string)
+                        })
+                        .collect()
+                })
+                .unwrap_or_default();
+        failed.sort();
+        module_prop_map.insert("failed".to_owned(), failed.join(","));
+
         if result.is_err() {
             warn!("Failed to parse module.prop: {}", module_prop.display());
             continue;
@@ -487,8 +1252,193 @@ fn _list_modules(path: &str) -> Vec<HashMap<String, String>> {
     modules
 }
 
+/// Print the script logs of module <id>, of all stages unless <stage> is given
+pub fn print_logs(id: &str, stage: Option<&str>) -> Result<()> {
+    module_prop::validate_id(id)?;
+    let log_dir = defs::resolve(defs::MODULE_LOG_DIR).join(id);
+    if let Some(stage) = stage {
+        ensure!(
+            LOG_STAGES.contains(&stage),
+            "unknown stage {stage}, expected one of {}",
+            LOG_STAGES.join(", ")
+        );
+        let log = log_dir.join(format!("{stage}.log"));
+        let content = std::fs::read_to_string(&log)
+            .with_context(|| format!("No {stage} log of module {id}"))?;
+        return output::result(&content, |content| {
+            print!("{content}");
+            Ok(())
+        });
+    }
+
+    #[derive(serde::Serialize)]
+    struct StageLog {
+        stage: &'static str,
+        log: String,
+    }
+    let logs: Vec<StageLog> = LOG_STAGES
+        .iter()
+        .filter_map(|stage| {
+            let log = std::fs::read_to_string(log_dir.join(format!("{stage}.log"))).ok()?;
+            Some(StageLog { stage, log })
+        })
+        .collect();
+    ensure!(!logs.is_empty(), "No script logs of module {id}");
+    output::result(&logs, |logs| {
+        for (i, StageLog { stage, log }) in logs.iter().enumerate() {
+            if i > 0 {
+                println!();
+            }
+            println!("==> {stage} <==");
+            print!("{log}");
+        }
+        Ok(())
+    })
+}
+
+/// Check module.prop and the entries of a module zip or directory, every problem is reported
+pub fn validate_module(path: &Path) -> Result<()> {
+    let props = if path.is_dir() {
+        parse_new_module_prop(std::fs::read(path.join("module.prop"))?)?
+    } else {
+        let mut archive = zip::ZipArchive::new(File::open(path)?)?;
+        unzip::check(&mut archive)?;
+        let mut buffer = Vec::new();
+        zip_extract_file_to_memory(path, &PathBuf::from("module.prop"), &mut buffer)
+            .with_context(|| format!("Failed to read module.prop in {}", path.display()))?;
+        parse_new_module_prop(buffer)?
+    };
+
+    let (prop, diagnostics) = ModuleProp::check(props);
+    for warning in &diagnostics.warnings {
+        output::progress(format!("Warning: {warning}"));
+    }
+    for error in &diagnostics.errors {
+        output::progress(format!("Error: {error}"));
+    }
+    let Some(prop) = prop else {
+        bail!("{} problems in module.prop", diagnostics.errors.len());
+    };
+    output::result(&prop, |prop| {
+        output::progress(format!(
+            "{} {} ({}) is valid",
+            prop.id, prop.version, prop.version_code
+        ));
+        Ok(())
+    })
+}
+
+/// Compare the files of module <id> with its manifest, every difference is reported
+pub fn verify_module(id: &str) -> Result<()> {
+    module_prop::validate_id(id)?;
+    let module = defs::resolve(MODULE_DIR).join(id);
+    ensure!(module.is_dir(), "module {id} is not installed");
+    ensure!(
+        !is_placeholder(&module),
+        "module {id} is installed on next reboot, verify it then"
+    );
+    let report = module_manifest::verify(&module)?;
+    for line in report.lines() {
+        output::progress(line);
+    }
+    ensure!(
+        report.is_clean(),
+        "{} files of module {id} don't match its manifest",
+        report.len()
+    );
+    output::result(&report, |_| {
+        output::progress(format!("Files of module {id} match its manifest"));
+        Ok(())
+    })
+}
+
+/// Check the active modules against their manifests before they are loaded,
+/// as `modules.verify_on_boot` says
+pub fn verify_modules() -> Result<()> {
+    let policy = config::get().modules.verify_on_boot;
+    if policy == VerifyPolicy::Off {
+        return Ok(());
+    }
+    foreach_active_module(|module| {
+        let report = match module_manifest::verify(module) {
+            Ok(report) => report,
+            Err(e) => {
+                info!("skip verifying {}: {:#}", module.display(), e);
+                return Ok(());
+            }
+        };
+        if report.is_clean() {
+            return Ok(());
+        }
+        warn!(
+            "{} files of {} don't match its manifest",
+            report.len(),
+            module.display()
+        );
+        for line in report.lines() {
+            warn!("{line}");
+        }
+        if policy == VerifyPolicy::Disable {
+            warn!("disable modified module {}", module.display());
+            ensure_file_exists(module.join(defs::DISABLE_FILE_NAME))?;
+        }
+        Ok(())
+    })
+}
+
 pub fn list_modules() -> Result<()> {
-    let modules = _list_modules(defs::MODULE_DIR);
-    println!("{}", serde_json::to_string_pretty(&modules)?);
-    Ok(())
+    let modules = _list_modules(&defs::resolve(defs::MODULE_DIR));
+    output::result(&modules, |modules| {
+        println!("{}", serde_json::to_string_pretty(modules)?);
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Seek};
+
+    #[test]
+    fn log_wrapper_needs_no_access_to_the_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let script = dir.path().join("post-fs-data.sh");
+        std::fs::write(&script, "echo out\necho err >&2\nexit 3\n").unwrap();
+        let log_path = dir.path().join("post-fs-data.log");
+        let mut log = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&log_path)
+            .unwrap();
+        // like a confined script, the wrapper only has the fd
+        std::fs::remove_file(&log_path).unwrap();
+
+        let status = Command::new("sh")
+            .args(["-c", SCRIPT_LOG_WRAPPER, "sh", "false"])
+            .arg(&script)
+            .stderr(log.try_clone().unwrap())
+            .status()
+            .unwrap();
+        assert_eq!(status.code(), Some(3));
+
+        let mut content = String::new();
+        log.rewind().unwrap();
+        log.read_to_string(&mut content).unwrap();
+        let lines: Vec<&str> = content.lines().collect();
+        assert!(lines[0].starts_with(&format!("# {} started at", script.display())));
+        assert_eq!(lines[1..3], ["out", "err"]);
+        assert!(lines[3].starts_with("# exit code 3 after"));
+    }
+
+    #[test]
+    fn duplicate_keys_are_rejected() {
+        let props = parse_new_module_prop(b"id=a1\nname=A\nversion=1\n".to_vec()).unwrap();
+        assert_eq!(props["id"], "a1");
+        let err = parse_new_module_prop(b"id=a1\nname=A\nid=../b\nname=B\n".to_vec())
+            .unwrap_err()
+            .to_string();
+        assert_eq!(err, "duplicate keys in module.prop: id, name");
+    }
 }
@@ -0,0 +1,459 @@
+use anyhow::{bail, Context, Result};
+use log::warn;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::SystemTime;
+
+use crate::output;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Eq,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Op {
+    fn as_str(self) -> &'static str {
+        match self {
+            Op::Eq => "=",
+            Op::Lt => "<",
+            Op::Le => "<=",
+            Op::Gt => ">",
+            Op::Ge => ">=",
+        }
+    }
+}
+
+/// A module reference in `requires=`, `conflicts=` or `after=`, e.g. `foo` or `foo>=100`.
+/// The version is compared with `versionCode` of the referenced module.
+#[derive(Debug, Clone)]
+pub struct Dependency {
+    pub id: String,
+    constraint: Option<(Op, i64)>,
+}
+
+impl FromStr for Dependency {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        let Some(pos) = s.find(['<', '>', '=']) else {
+            return Ok(Dependency {
+                id: s.to_string(),
+                constraint: None,
+            });
+        };
+        let (id, rest) = s.split_at(pos);
+        let (op, version) = if let Some(v) = rest.strip_prefix(">=") {
+            (Op::Ge, v)
+        } else if let Some(v) = rest.strip_prefix("<=") {
+            (Op::Le, v)
+        } else if let Some(v) = rest.strip_prefix("==") {
+            (Op::Eq, v)
+        } else if let Some(v) = rest.strip_prefix('=') {
+            (Op::Eq, v)
+        } else if let Some(v) = rest.strip_prefix('>') {
+            (Op::Gt, v)
+        } else if let Some(v) = rest.strip_prefix('<') {
+            (Op::Lt, v)
+        } else {
+            unreachable!()
+        };
+        let id = id.trim();
+        if id.is_empty() {
+            bail!("missing module id in {s}");
+        }
+        let version = version
+            .trim()
+            .parse::<i64>()
+            .with_context(|| format!("invalid version in {s}"))?;
+        Ok(Dependency {
+            id: id.to_string(),
+            constraint: Some((op, version)),
+        })
+    }
+}
+
+impl Display for Dependency {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.constraint {
+            Some((op, version)) => write!(f, "{}{}{version}", self.id, op.as_str()),
+            None => write!(f, "{}", self.id),
+        }
+    }
+}
+
+impl Dependency {
+    /// Whether <module> is the one referenced by this dependency
+    pub fn matches(&self, module: &ModuleInfo) -> bool {
+        if self.id != module.id {
+            return false;
+        }
+        let Some((op, expected)) = self.constraint else {
+            return true;
+        };
+        // a module without a numeric versionCode never satisfies a version constraint
+        let Some(version) = module.version_code else {
+            return false;
+        };
+        match op {
+            Op::Eq => version == expected,
+            Op::Lt => version < expected,
+            Op::Le => version <= expected,
+            Op::Gt => version > expected,
+            Op::Ge => version >= expected,
+        }
+    }
+}
+
+// entries are separated by comma or whitespace, invalid ones are ignored
+fn parse_dependencies(id: &str, key: &str, value: Option<&String>) -> Vec<Dependency> {
+    let Some(value) = value else {
+        return Vec::new();
+    };
+    value
+        .split([',', ' ', '\t'])
+        .filter(|s| !s.trim().is_empty())
+        .filter_map(|s| match s.parse() {
+            Ok(dep) => Some(dep),
+            Err(e) => {
+                warn!("{id}: ignore invalid {key} entry: {e}");
+                None
+            }
+        })
+        .collect()
+}
+
+/// Relationship of a module to others, declared in its module.prop
+#[derive(Debug, Clone)]
+pub struct ModuleInfo {
+    pub id: String,
+    pub version_code: Option<i64>,
+    pub path: PathBuf,
+    pub requires: Vec<Dependency>,
+    pub conflicts: Vec<Dependency>,
+    pub after: Vec<Dependency>,
+    /// when module.prop was written, i.e. the module was installed or updated last
+    pub installed: Option<SystemTime>,
+}
+
+impl ModuleInfo {
+    pub fn from_prop(id: &str, path: &Path, prop: &HashMap<String, String>) -> Self {
+        ModuleInfo {
+            id: id.to_string(),
+            version_code: prop.get("versionCode").and_then(|v| v.trim().parse().ok()),
+            path: path.to_path_buf(),
+            requires: parse_dependencies(id, "requires", prop.get("requires")),
+            conflicts: parse_dependencies(id, "conflicts", prop.get("conflicts")),
+            after: parse_dependencies(id, "after", prop.get("after")),
+            installed: path
+                .join("module.prop")
+                .metadata()
+                .and_then(|m| m.modified())
+                .ok(),
+        }
+    }
+
+    fn unmet_requires<'a>(&'a self, others: &[&ModuleInfo]) -> Option<&'a Dependency> {
+        self.requires
+            .iter()
+            .find(|dep| !others.iter().any(|m| dep.matches(m)))
+    }
+
+    // modules installed earlier come first, the id breaks ties
+    fn install_order(&self) -> (Option<SystemTime>, &str) {
+        (self.installed, &self.id)
+    }
+
+    fn conflicts_with<'a>(&'a self, others: &[&ModuleInfo]) -> Option<&'a Dependency> {
+        self.conflicts
+            .iter()
+            .find(|dep| others.iter().any(|m| m.id != self.id && dep.matches(m)))
+    }
+}
+
+/// Drop modules whose requirements are unmet or which conflict with another one,
+/// then order the rest so that every module comes after what it requires or is declared `after`.
+///
+/// Of two conflicting modules, the one installed first is kept, whichever declares the conflict.
+/// Modules without relationships keep the order of their ids.
+pub fn resolve(modules: Vec<ModuleInfo>) -> Vec<ModuleInfo> {
+    let mut modules: BTreeMap<String, ModuleInfo> =
+        modules.into_iter().map(|m| (m.id.clone(), m)).collect();
+
+    // dropping a module may break requirements of others, repeat until stable
+    loop {
+        let active: Vec<&ModuleInfo> = modules.values().collect();
+        let unmet = active.iter().find_map(|m| {
+            let dep = m.unmet_requires(&active)?;
+            warn!("{}: requirement {dep} is not met, skip it", m.id);
+            Some(m.id.clone())
+        });
+        let dropped = unmet.or_else(|| {
+            active.iter().find_map(|m| {
+                let dep = m.conflicts_with(&active)?;
+                let other = active.iter().find(|o| o.id != m.id && dep.matches(o))?;
+                let (kept, skipped) = if m.install_order() <= other.install_order() {
+                    (m, other)
+                } else {
+                    (other, m)
+                };
+                warn!(
+                    "{}: conflicts with {}, which was installed first, skip it",
+                    skipped.id, kept.id
+                );
+                Some(skipped.id.clone())
+            })
+        });
+        match dropped {
+            Some(id) => modules.remove(&id),
+            None => break,
+        };
+    }
+
+    // edges from a module to the ones it must be loaded after
+    let mut pending: BTreeMap<&str, BTreeSet<&str>> = BTreeMap::new();
+    for module in modules.values() {
+        let deps = module
+            .requires
+            .iter()
+            .chain(&module.after)
+            .filter(|dep| dep.id != module.id)
+            .filter(|dep| modules.get(&dep.id).is_some_and(|m| dep.matches(m)))
+            .map(|dep| dep.id.as_str())
+            .collect();
+        pending.insert(module.id.as_str(), deps);
+    }
+
+    let mut order = Vec::new();
+    while !pending.is_empty() {
+        let ready: Vec<&str> = pending
+            .iter()
+            .filter(|(_, deps)| deps.is_empty())
+            .map(|(id, _)| *id)
+            .collect();
+        let ready = if ready.is_empty() {
+            // break the cycle at the smallest id
+            let id = *pending.keys().next().unwrap();
+            warn!("{id}: circular module ordering, load it anyway");
+            vec![id]
+        } else {
+            ready
+        };
+        for id in ready {
+            pending.remove(id);
+            for deps in pending.values_mut() {
+                deps.remove(id);
+            }
+            order.push(id.to_string());
+        }
+    }
+
+    order
+        .into_iter()
+        .filter_map(|id| modules.remove(&id))
+        .collect()
+}
+
+/// Check <module> against the installed modules before installing it.
+/// Conflicts refuse the installation, unmet requirements only warn since they can be installed later.
+pub fn check_install(module: &ModuleInfo, installed: &[ModuleInfo]) -> Result<()> {
+    let others: Vec<&ModuleInfo> = installed.iter().filter(|m| m.id != module.id).collect();
+
+    if let Some(dep) = module.conflicts_with(&others) {
+        bail!("{} conflicts with installed module {dep}", module.id);
+    }
+    if let Some(other) = others
+        .iter()
+        .find(|m| m.conflicts.iter().any(|dep| dep.matches(module)))
+    {
+        bail!("installed module {} conflicts with {}", other.id, module.id);
+    }
+
+    for dep in &module.requires {
+        if !others.iter().any(|m| dep.matches(m)) {
+            output::progress(format!(
+                "Warning: requires {dep}, this module will not be loaded until it is installed"
+            ));
+        }
+    }
+    for other in &others {
+        let broken = other
+            .requires
+            .iter()
+            .find(|dep| dep.id == module.id && !dep.matches(module));
+        if let Some(dep) = broken {
+            output::progress(format!(
+                "Warning: {} requires {dep}, it will not be loaded",
+                other.id
+            ));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn module(id: &str, version: i64, prop: &[(&str, &str)]) -> ModuleInfo {
+        let mut prop: HashMap<String, String> = prop
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        prop.insert("versionCode".to_string(), version.to_string());
+        ModuleInfo::from_prop(id, Path::new("/nonexistent").join(id).as_path(), &prop)
+    }
+
+    fn installed_at(mut module: ModuleInfo, secs: u64) -> ModuleInfo {
+        module.installed = Some(SystemTime::UNIX_EPOCH + Duration::from_secs(secs));
+        module
+    }
+
+    fn ids(modules: &[ModuleInfo]) -> Vec<&str> {
+        modules.iter().map(|m| m.id.as_str()).collect()
+    }
+
+    #[test]
+    fn parse_dependency() {
+        let dep: Dependency = "foo".parse().unwrap();
+        assert_eq!(dep.id, "foo");
+        assert!(dep.constraint.is_none());
+
+        for (s, op, version) in [
+            ("foo>=100", Op::Ge, 100),
+            ("foo<=100", Op::Le, 100),
+            ("foo==100", Op::Eq, 100),
+            ("foo=100", Op::Eq, 100),
+            ("foo>100", Op::Gt, 100),
+            (" foo < -1 ", Op::Lt, -1),
+        ] {
+            let dep: Dependency = s.parse().unwrap();
+            assert_eq!(dep.id, "foo", "{s}");
+            assert_eq!(dep.constraint, Some((op, version)), "{s}");
+        }
+        assert_eq!(
+            "foo=100".parse::<Dependency>().unwrap().to_string(),
+            "foo=100"
+        );
+
+        assert!(">=100".parse::<Dependency>().is_err());
+        assert!("foo>=".parse::<Dependency>().is_err());
+        assert!("foo>=abc".parse::<Dependency>().is_err());
+    }
+
+    #[test]
+    fn parse_dependency_list() {
+        let m = module(
+            "a",
+            1,
+            &[("requires", "b>=2, c\td,,e=x"), ("conflicts", " f ")],
+        );
+        let requires: Vec<String> = m.requires.iter().map(Dependency::to_string).collect();
+        // the invalid entry is ignored
+        assert_eq!(requires, ["b>=2", "c", "d"]);
+        assert_eq!(m.conflicts.len(), 1);
+        assert_eq!(m.conflicts[0].id, "f");
+        assert!(m.after.is_empty());
+    }
+
+    #[test]
+    fn version_constraints() {
+        let dep: Dependency = "b>=2".parse().unwrap();
+        assert!(dep.matches(&module("b", 2, &[])));
+        assert!(!dep.matches(&module("b", 1, &[])));
+        assert!(!dep.matches(&module("c", 2, &[])));
+
+        let mut unversioned = module("b", 2, &[]);
+        unversioned.version_code = None;
+        assert!(!dep.matches(&unversioned));
+        assert!("b".parse::<Dependency>().unwrap().matches(&unversioned));
+    }
+
+    #[test]
+    fn resolve_order() {
+        let modules = vec![
+            module("a", 1, &[("requires", "c")]),
+            module("b", 1, &[("after", "a")]),
+            module("c", 1, &[("after", "missing")]),
+            module("d", 1, &[]),
+        ];
+        assert_eq!(ids(&resolve(modules)), ["c", "d", "a", "b"]);
+    }
+
+    #[test]
+    fn resolve_unmet_requirements() {
+        let modules = vec![
+            module("a", 1, &[("requires", "b")]),
+            module("b", 1, &[("requires", "c>=2")]),
+            module("c", 1, &[]),
+            module("d", 1, &[("requires", "c<2")]),
+        ];
+        // b is dropped, which drops a too
+        assert_eq!(ids(&resolve(modules)), ["c", "d"]);
+    }
+
+    #[test]
+    fn resolve_cycles() {
+        let modules = vec![
+            module("a", 1, &[("after", "b")]),
+            module("b", 1, &[("after", "a")]),
+            module("c", 1, &[("after", "b")]),
+            module("d", 1, &[]),
+        ];
+        // the cycle is broken at the smallest id, nothing is dropped
+        assert_eq!(ids(&resolve(modules)), ["d", "a", "b", "c"]);
+
+        let modules = vec![
+            module("a", 1, &[("requires", "b")]),
+            module("b", 1, &[("requires", "a")]),
+        ];
+        assert_eq!(ids(&resolve(modules)), ["a", "b"]);
+    }
+
+    #[test]
+    fn resolve_conflicts_keep_the_first_installed() {
+        // the declaring module is dropped if it was installed later
+        let modules = vec![
+            installed_at(module("a", 1, &[("conflicts", "z")]), 200),
+            installed_at(module("z", 1, &[]), 100),
+        ];
+        assert_eq!(ids(&resolve(modules)), ["z"]);
+
+        // and kept if it was there first, whatever the ids
+        let modules = vec![
+            installed_at(module("a", 1, &[("conflicts", "z")]), 100),
+            installed_at(module("z", 1, &[]), 200),
+        ];
+        assert_eq!(ids(&resolve(modules)), ["a"]);
+
+        let modules = vec![
+            installed_at(module("a", 1, &[("conflicts", "z")]), 200),
+            installed_at(module("z", 1, &[("conflicts", "a")]), 100),
+        ];
+        assert_eq!(ids(&resolve(modules)), ["z"]);
+
+        // a version constraint which doesn't match is no conflict
+        let modules = vec![
+            installed_at(module("a", 1, &[("conflicts", "z<2")]), 200),
+            installed_at(module("z", 2, &[]), 100),
+        ];
+        assert_eq!(ids(&resolve(modules)), ["a", "z"]);
+    }
+
+    #[test]
+    fn install_conflicts() {
+        let installed = vec![module("b", 1, &[("conflicts", "a")]), module("c", 1, &[])];
+        assert!(check_install(&module("a", 1, &[]), &installed).is_err());
+        assert!(check_install(&module("d", 1, &[("conflicts", "c")]), &installed).is_err());
+        assert!(check_install(&module("d", 1, &[("requires", "missing")]), &installed).is_ok());
+        // an update doesn't conflict with its old version
+        assert!(check_install(&module("c", 2, &[("conflicts", "c")]), &installed).is_ok());
+    }
+}
@@ -0,0 +1,299 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+use crate::defs;
+use crate::restorecon::{self, ADB_CON, SYSTEM_CON, UNLABEL_CON};
+
+// files of a module dir which are written after installation, they are not recorded
+const UNTRACKED: [&str; 6] = [
+    defs::MODULE_MANIFEST_FILE_NAME,
+    defs::DISABLE_FILE_NAME,
+    defs::INCOMPATIBLE_FILE_NAME,
+    defs::REMOVE_FILE_NAME,
+    defs::UPDATE_FILE_NAME,
+    defs::PREVIOUS_MODULE_PROP,
+];
+
+/// What a file of an installed module should look like
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileRecord {
+    /// st_mode, including the file type
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub context: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    /// target of a symlink
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+}
+
+/// A file which doesn't match its record, with what changed
+#[derive(Debug, Serialize)]
+pub struct Modified {
+    pub path: String,
+    pub changes: Vec<String>,
+}
+
+/// Differences between a module dir and its manifest
+#[derive(Debug, Default, Serialize)]
+pub struct Report {
+    pub modified: Vec<Modified>,
+    pub missing: Vec<String>,
+    pub extra: Vec<String>,
+}
+
+impl Report {
+    pub fn is_clean(&self) -> bool {
+        self.modified.is_empty() && self.missing.is_empty() && self.extra.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.modified.len() + self.missing.len() + self.extra.len()
+    }
+
+    /// One line for each difference
+    pub fn lines(&self) -> Vec<String> {
+        let modified = self
+            .modified
+            .iter()
+            .map(|file| format!("Modified: {} ({})", file.path, file.changes.join(", ")));
+        let missing = self.missing.iter().map(|path| format!("Missing: {path}"));
+        let extra = self.extra.iter().map(|path| format!("Extra: {path}"));
+        modified.chain(missing).chain(extra).collect()
+    }
+}
+
+// restorecon relabels these on every boot, record what it leaves behind
+fn effective_context(path: &Path) -> Option<String> {
+    let con = restorecon::lgetfilecon(path).ok()?;
+    let con = con.trim_end_matches('\0');
+    if con.is_empty() || con == ADB_CON || con == UNLABEL_CON {
+        Some(SYSTEM_CON.to_string())
+    } else {
+        Some(con.to_string())
+    }
+}
+
+fn record(path: &Path) -> Result<FileRecord> {
+    let metadata = fs::symlink_metadata(path)?;
+    let file_type = metadata.file_type();
+    let sha256 = if file_type.is_file() {
+        let mut hasher = Sha256::new();
+        std::io::copy(&mut File::open(path)?, &mut hasher)?;
+        Some(format!("{:x}", hasher.finalize()))
+    } else {
+        None
+    };
+    let target = if file_type.is_symlink() {
+        Some(fs::read_link(path)?.to_string_lossy().to_string())
+    } else {
+        None
+    };
+    Ok(FileRecord {
+        mode: metadata.mode(),
+        uid: metadata.uid(),
+        gid: metadata.gid(),
+        context: effective_context(path),
+        sha256,
+        target,
+    })
+}
+
+fn scan(module: &Path) -> Result<BTreeMap<String, FileRecord>> {
+    let mut files = BTreeMap::new();
+    for entry in jwalk::WalkDir::new(module)
+        .parallelism(jwalk::Parallelism::Serial)
+        .skip_hidden(false)
+        .sort(true)
+        .min_depth(1)
+        .process_read_dir(|_, _, _, children| {
+            children.retain(|child| {
+                !matches!(child, Ok(child) if child.depth == 1
+                    && UNTRACKED.contains(&child.file_name.to_string_lossy().as_ref()))
+            });
+        })
+    {
+        let path = entry?.path();
+        let name = path.strip_prefix(module)?.to_string_lossy().to_string();
+        let record = record(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+        files.insert(name, record);
+    }
+    Ok(files)
+}
+
+/// Record every file of <module>, done once it is installed
+pub fn write(module: &Path) -> Result<()> {
+    let files = scan(module)?;
+    let manifest = module.join(defs::MODULE_MANIFEST_FILE_NAME);
+    fs::write(&manifest, serde_json::to_string(&files)?)
+        .with_context(|| format!("Failed to write {}", manifest.display()))
+}
+
+fn describe(old: &FileRecord, new: &FileRecord) -> Vec<String> {
+    let mut changes = Vec::new();
+    if old.mode != new.mode {
+        changes.push(format!("mode {:o} -> {:o}", old.mode, new.mode));
+    }
+    if (old.uid, old.gid) != (new.uid, new.gid) {
+        changes.push(format!(
+            "owner {}:{} -> {}:{}",
+            old.uid, old.gid, new.uid, new.gid
+        ));
+    }
+    if old.context != new.context {
+        changes.push(format!(
+            "context {} -> {}",
+            old.context.as_deref().unwrap_or("none"),
+            new.context.as_deref().unwrap_or("none")
+        ));
+    }
+    if old.sha256 != new.sha256 {
+        changes.push("content".to_string());
+    }
+    if old.target != new.target {
+        changes.push(format!(
+            "target {} -> {}",
+            old.target.as_deref().unwrap_or("none"),
+            new.target.as_deref().unwrap_or("none")
+        ));
+    }
+    changes
+}
+
+/// Compare <module> with the manifest written when it was installed
+pub fn verify(module: &Path) -> Result<Report> {
+    let manifest = module.join(defs::MODULE_MANIFEST_FILE_NAME);
+    let content = fs::read_to_string(&manifest).with_context(|| {
+        format!(
+            "{} has no manifest, it was installed by an older ksud",
+            module.display()
+        )
+    })?;
+    let expected: BTreeMap<String, FileRecord> = serde_json::from_str(&content)
+        .with_context(|| format!("Invalid manifest {}", manifest.display()))?;
+    let mut actual = scan(module)?;
+    // module.prop is replaced by the one of a pending update, the running version is kept aside
+    let previous_prop = module.join(defs::PREVIOUS_MODULE_PROP);
+    if module.join(defs::UPDATE_FILE_NAME).exists() && previous_prop.exists() {
+        actual.insert("module.prop".to_string(), record(&previous_prop)?);
+    }
+
+    let mut report = Report::default();
+    for (path, old) in &expected {
+        match actual.remove(path) {
+            None => report.missing.push(path.clone()),
+            Some(new) if new != *old => report.modified.push(Modified {
+                path: path.clone(),
+                changes: describe(old, &new),
+            }),
+            Some(_) => {}
+        }
+    }
+    report.extra = actual.into_keys().collect();
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::{symlink, PermissionsExt};
+
+    fn module() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        let module = dir.path();
+        fs::create_dir_all(module.join("system/bin")).unwrap();
+        fs::create_dir_all(module.join("system/lib")).unwrap();
+        fs::write(module.join("module.prop"), "id=test\nversion=1\n").unwrap();
+        fs::write(module.join("service.sh"), "#!/bin/sh\n").unwrap();
+        fs::write(module.join("system/bin/tool"), "tool").unwrap();
+        symlink("../bin/tool", module.join("system/lib/tool")).unwrap();
+        write(module).unwrap();
+        dir
+    }
+
+    fn changes(report: &Report, path: &str) -> Vec<String> {
+        let file = report.modified.iter().find(|file| file.path == path);
+        file.map(|file| file.changes.clone()).unwrap_or_default()
+    }
+
+    #[test]
+    fn verify_changes() {
+        let dir = module();
+        let module = dir.path();
+        assert!(verify(module).unwrap().is_clean());
+
+        fs::write(module.join("system/bin/tool"), "patched").unwrap();
+        let report = verify(module).unwrap();
+        assert_eq!(changes(&report, "system/bin/tool"), ["content"]);
+        assert_eq!(report.len(), 1);
+
+        fs::remove_file(module.join("service.sh")).unwrap();
+        fs::write(module.join("system/bin/extra"), "extra").unwrap();
+        fs::remove_file(module.join("system/lib/tool")).unwrap();
+        symlink("/system/bin/sh", module.join("system/lib/tool")).unwrap();
+        fs::set_permissions(module.join("system/bin"), fs::Permissions::from_mode(0o700)).unwrap();
+        let report = verify(module).unwrap();
+        assert_eq!(report.missing, ["service.sh"]);
+        assert_eq!(report.extra, ["system/bin/extra"]);
+        assert_eq!(
+            changes(&report, "system/lib/tool"),
+            ["target ../bin/tool -> /system/bin/sh"]
+        );
+        assert!(changes(&report, "system/bin")[0].starts_with("mode "));
+        assert_eq!(report.len(), 5);
+        assert_eq!(report.lines().len(), 5);
+    }
+
+    #[test]
+    fn skip_untracked_files() {
+        let dir = module();
+        let module = dir.path();
+        for name in [
+            defs::DISABLE_FILE_NAME,
+            defs::REMOVE_FILE_NAME,
+            defs::INCOMPATIBLE_FILE_NAME,
+        ] {
+            fs::write(module.join(name), "").unwrap();
+        }
+        assert!(verify(module).unwrap().is_clean());
+        // only at the top of the module
+        fs::write(module.join("system/disable"), "").unwrap();
+        assert_eq!(verify(module).unwrap().extra, ["system/disable"]);
+    }
+
+    #[test]
+    fn pending_update() {
+        let dir = module();
+        let module = dir.path();
+        // what marking a module updated does, the running module.prop is kept aside
+        fs::copy(
+            module.join("module.prop"),
+            module.join(defs::PREVIOUS_MODULE_PROP),
+        )
+        .unwrap();
+        fs::write(module.join("module.prop"), "id=test\nversion=2\n").unwrap();
+        fs::write(module.join(defs::UPDATE_FILE_NAME), "").unwrap();
+        assert!(verify(module).unwrap().is_clean());
+
+        fs::remove_file(module.join(defs::UPDATE_FILE_NAME)).unwrap();
+        let report = verify(module).unwrap();
+        assert_eq!(changes(&report, "module.prop"), ["content"]);
+        assert!(report.extra.is_empty());
+    }
+
+    #[test]
+    fn verify_without_manifest() {
+        let dir = module();
+        let module = dir.path();
+        fs::remove_file(module.join(defs::MODULE_MANIFEST_FILE_NAME)).unwrap();
+        assert!(verify(module).is_err());
+    }
+}
@@ -0,0 +1,148 @@
+use anyhow::{anyhow, bail, ensure, Result};
+use log::warn;
+use regex_lite::Regex;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::OnceLock;
+
+use crate::module;
+
+// same as magisk, so that a module works on both
+const ID_PATTERN: &str = r"^[a-zA-Z][a-zA-Z0-9._-]+$";
+
+// keys which must be integers when present
+const NUMERIC_KEYS: [&str; 3] = ["minKsuVersion", "maxKsuVersion", "minKernelVersion"];
+
+/// Check a module id, it names the module directories so it must not be a path
+pub fn validate_id(id: &str) -> Result<()> {
+    if id.contains(['/', '\\']) {
+        bail!("module id must not contain path separators: {id}");
+    }
+    static ID_REGEX: OnceLock<Regex> = OnceLock::new();
+    let regex = ID_REGEX.get_or_init(|| Regex::new(ID_PATTERN).unwrap());
+    ensure!(
+        regex.is_match(id),
+        "invalid module id: {id}, it must match {ID_PATTERN}"
+    );
+    Ok(())
+}
+
+/// Problems found in a module.prop, a module with errors can't be installed
+#[derive(Debug, Default, Serialize)]
+pub struct Diagnostics {
+    pub errors: Vec<String>,
+    pub warnings: Vec<String>,
+}
+
+/// module.prop of a module
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ModuleProp {
+    pub id: String,
+    pub name: String,
+    pub version: String,
+    pub version_code: i64,
+    pub author: Option<String>,
+    pub description: Option<String>,
+    pub update_json: Option<String>,
+    /// all keys of the file, including those read by other parts of ksud
+    #[serde(skip)]
+    pub props: HashMap<String, String>,
+}
+
+impl ModuleProp {
+    /// Parse <props>, reporting every problem rather than only the first one
+    pub fn check(props: HashMap<String, String>) -> (Option<Self>, Diagnostics) {
+        let mut diagnostics = Diagnostics::default();
+        let get = |key: &str| {
+            props
+                .get(key)
+                .map(|v| v.trim().to_string())
+                .filter(|v| !v.is_empty())
+        };
+
+        let mut required = |key: &str| {
+            let value = get(key);
+            if value.is_none() {
+                diagnostics.errors.push(format!("{key} is missing"));
+            }
+            value
+        };
+        let id = required("id");
+        let name = required("name");
+        let version = required("version");
+        let version_code = required("versionCode");
+
+        if let Some(Err(e)) = id.as_deref().map(validate_id) {
+            diagnostics.errors.push(e.to_string());
+        }
+        let version_code = version_code.and_then(|v| match v.parse::<i64>() {
+            Ok(code) => Some(code),
+            Err(_) => {
+                diagnostics
+                    .errors
+                    .push(format!("versionCode must be an integer: {v}"));
+                None
+            }
+        });
+        for key in NUMERIC_KEYS {
+            if let Some(v) = get(key).filter(|v| v.parse::<i64>().is_err()) {
+                diagnostics
+                    .errors
+                    .push(format!("{key} must be an integer: {v}"));
+            }
+        }
+
+        let author = get("author");
+        let description = get("description");
+        for (key, value) in [("author", &author), ("description", &description)] {
+            if value.is_none() {
+                diagnostics.warnings.push(format!("{key} is missing"));
+            }
+        }
+        let update_json = get("updateJson");
+        if let Some(url) = update_json
+            .as_deref()
+            .filter(|url| !url.starts_with("https://"))
+        {
+            diagnostics
+                .warnings
+                .push(format!("updateJson is not a https url: {url}"));
+        }
+
+        let (Some(id), Some(name), Some(version), Some(version_code)) =
+            (id, name, version, version_code)
+        else {
+            return (None, diagnostics);
+        };
+        if !diagnostics.errors.is_empty() {
+            return (None, diagnostics);
+        }
+        let prop = ModuleProp {
+            id,
+            name,
+            version,
+            version_code,
+            author,
+            description,
+            update_json,
+            props,
+        };
+        (Some(prop), diagnostics)
+    }
+
+    /// Parse <props>, warnings are logged
+    pub fn parse(props: HashMap<String, String>) -> Result<Self> {
+        let (prop, diagnostics) = Self::check(props);
+        for warning in &diagnostics.warnings {
+            warn!("module.prop: {warning}");
+        }
+        prop.ok_or_else(|| anyhow!("invalid module.prop: {}", diagnostics.errors.join(", ")))
+    }
+
+    /// module.prop of the module in <dir>
+    pub fn load(dir: &Path) -> Result<Self> {
+        Self::parse(module::read_module_prop(dir)?)
+    }
+}
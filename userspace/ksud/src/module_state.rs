@@ -0,0 +1,524 @@
+use anyhow::{bail, Result};
+use log::info;
+use serde::Serialize;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fs::{remove_dir_all, remove_file, rename};
+use std::path::{Path, PathBuf};
+
+use crate::module_prop::{self, ModuleProp};
+use crate::{defs, module, output};
+
+/// State of a module, derived from its directories and flag files
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum State {
+    NotInstalled,
+    /// staged in modules_update, installed on next boot
+    PendingInstall,
+    Enabled,
+    Disabled,
+    /// skipped on boot until it supports this KernelSU again
+    Incompatible,
+    /// a new version is staged in modules_update, applied on next boot
+    PendingUpdate,
+    /// removed on next boot
+    PendingRemoval,
+    /// the module dir has no module.prop
+    Broken,
+}
+
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            State::NotInstalled => "not installed",
+            State::PendingInstall => "pending install",
+            State::Enabled => "enabled",
+            State::Disabled => "disabled",
+            State::Incompatible => "incompatible",
+            State::PendingUpdate => "pending update",
+            State::PendingRemoval => "pending removal",
+            State::Broken => "broken",
+        })
+    }
+}
+
+/// Inconsistent combinations of the directories and flag files of a module,
+/// in the order they are repaired
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Problem {
+    /// module.prop of a pending update was kept aside, but nothing is pending anymore
+    StalePreviousProp,
+    /// the module was moved away by an update which never finished
+    OrphanSnapshot,
+    /// the staged update is dropped on boot
+    InvalidUpdate(String),
+    /// both remove and update are set, the update would bring the module back
+    RemoveAndUpdate,
+    /// the staged update is applied on boot, but the module doesn't show it
+    UpdateWithoutStub,
+    /// the update flag is set but nothing is staged
+    StubWithoutUpdate,
+    /// the module dir has no module.prop
+    MissingProp,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Problem::StalePreviousProp => write!(
+                f,
+                "{} is left without a pending update",
+                defs::PREVIOUS_MODULE_PROP
+            ),
+            Problem::OrphanSnapshot => f.write_str("snapshot of an interrupted update"),
+            Problem::InvalidUpdate(reason) => write!(f, "invalid staged update: {reason}"),
+            Problem::RemoveAndUpdate => f.write_str("both remove and update are set"),
+            Problem::UpdateWithoutStub => f.write_str("staged update without update flag"),
+            Problem::StubWithoutUpdate => f.write_str("update flag without staged update"),
+            Problem::MissingProp => f.write_str("module.prop is missing"),
+        }
+    }
+}
+
+/// Where module <id> lives
+struct Module {
+    id: String,
+    dir: PathBuf,
+    update: PathBuf,
+    snapshot: PathBuf,
+}
+
+impl Module {
+    fn new(id: &str) -> Self {
+        Self::in_root(defs::root(), id)
+    }
+
+    fn in_root(root: &Path, id: &str) -> Self {
+        let resolve = |dir: &str| defs::resolve_in(root, Path::new(dir)).join(id);
+        Module {
+            id: id.to_string(),
+            dir: resolve(defs::MODULE_DIR),
+            update: resolve(defs::MODULE_UPDATE_DIR),
+            snapshot: resolve(defs::MODULE_SNAPSHOT_DIR),
+        }
+    }
+
+    fn has(&self, file: &str) -> bool {
+        self.dir.join(file).exists()
+    }
+
+    fn state(&self) -> State {
+        if !self.dir.is_dir() {
+            return if self.update.is_dir() {
+                State::PendingInstall
+            } else {
+                State::NotInstalled
+            };
+        }
+        if self.has(defs::REMOVE_FILE_NAME) {
+            State::PendingRemoval
+        } else if self.has(defs::UPDATE_FILE_NAME) && self.update.is_dir() {
+            if module::is_placeholder(&self.dir) {
+                State::PendingInstall
+            } else {
+                State::PendingUpdate
+            }
+        } else if !self.has("module.prop") {
+            State::Broken
+        } else if self.has(defs::DISABLE_FILE_NAME) {
+            State::Disabled
+        } else if self.has(defs::INCOMPATIBLE_FILE_NAME) {
+            State::Incompatible
+        } else {
+            State::Enabled
+        }
+    }
+
+    fn problems(&self) -> Vec<Problem> {
+        let installed = self.dir.is_dir();
+        let staged = self.update.is_dir();
+        let update = self.has(defs::UPDATE_FILE_NAME);
+        let mut problems = Vec::new();
+
+        if self.has(defs::PREVIOUS_MODULE_PROP) && !update {
+            problems.push(Problem::StalePreviousProp);
+        }
+        if !installed && !staged && self.snapshot.is_dir() {
+            problems.push(Problem::OrphanSnapshot);
+        }
+        if staged {
+            // same check as on boot, see module::handle_updated_modules
+            match ModuleProp::load(&self.update) {
+                Ok(prop) if prop.id == self.id => {}
+                Ok(prop) => problems.push(Problem::InvalidUpdate(format!(
+                    "id {} doesn't match the directory",
+                    prop.id
+                ))),
+                Err(e) => problems.push(Problem::InvalidUpdate(format!("{e:#}"))),
+            }
+            if self.has(defs::REMOVE_FILE_NAME) {
+                problems.push(Problem::RemoveAndUpdate);
+            } else if !update {
+                problems.push(Problem::UpdateWithoutStub);
+            }
+        } else if update {
+            problems.push(Problem::StubWithoutUpdate);
+        }
+        if installed && !self.has("module.prop") {
+            problems.push(Problem::MissingProp);
+        }
+        problems
+    }
+
+    // forget the pending update, the running version keeps its module.prop
+    fn drop_update(&self) -> Result<()> {
+        if self.update.exists() {
+            remove_dir_all(&self.update)?;
+        }
+        let previous_prop = self.dir.join(defs::PREVIOUS_MODULE_PROP);
+        if previous_prop.exists() {
+            rename(previous_prop, self.dir.join("module.prop"))?;
+        }
+        let flag = self.dir.join(defs::UPDATE_FILE_NAME);
+        if flag.exists() {
+            remove_file(flag)?;
+        }
+        // nothing is left of a fresh installation
+        if self.dir.is_dir() && module::is_placeholder(&self.dir) {
+            remove_dir_all(&self.dir)?;
+        }
+        Ok(())
+    }
+
+    /// Repair <problem>, None if it can't be repaired safely
+    fn repair(&self, problem: &Problem) -> Result<Option<&'static str>> {
+        let action = match problem {
+            Problem::StalePreviousProp => {
+                rename(
+                    self.dir.join(defs::PREVIOUS_MODULE_PROP),
+                    self.dir.join("module.prop"),
+                )?;
+                "restored module.prop of the running version"
+            }
+            Problem::OrphanSnapshot => {
+                rename(&self.snapshot, &self.dir)?;
+                "restored the snapshot"
+            }
+            Problem::InvalidUpdate(_) => {
+                self.drop_update()?;
+                "dropped the staged update"
+            }
+            Problem::RemoveAndUpdate => {
+                self.drop_update()?;
+                "dropped the staged update, the module is still removed"
+            }
+            Problem::UpdateWithoutStub => {
+                module::mark_updated(&self.dir, &self.update)?;
+                "marked the module as updated"
+            }
+            Problem::StubWithoutUpdate => {
+                self.drop_update()?;
+                "cleared the update flag"
+            }
+            Problem::MissingProp if module::is_placeholder(&self.dir) => {
+                remove_dir_all(&self.dir)?;
+                "removed the empty module dir"
+            }
+            Problem::MissingProp => return Ok(None),
+        };
+        Ok(Some(action))
+    }
+}
+
+/// A repair done by fsck
+#[derive(Debug, Serialize)]
+pub struct Transition {
+    pub id: String,
+    pub from: State,
+    pub to: State,
+    pub action: &'static str,
+}
+
+impl fmt::Display for Transition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} -> {}, {}",
+            self.id, self.from, self.to, self.action
+        )
+    }
+}
+
+/// Status of module <id> and what is inconsistent about it
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Status {
+    pub id: String,
+    pub state: State,
+    pub skip_mount: bool,
+    /// a previous version can be restored with `ksud module rollback`
+    pub rollback: bool,
+    pub problems: Vec<String>,
+}
+
+fn status_of(module: &Module) -> Status {
+    Status {
+        id: module.id.clone(),
+        state: module.state(),
+        skip_mount: module.has(defs::SKIP_MOUNT_FILE_NAME),
+        rollback: module.snapshot.is_dir(),
+        problems: module.problems().iter().map(Problem::to_string).collect(),
+    }
+}
+
+pub fn status(id: &str) -> Result<()> {
+    module_prop::validate_id(id)?;
+    let status = status_of(&Module::new(id));
+    output::result(&status, |status| {
+        println!("state: {}", status.state);
+        println!("skip mount: {}", status.skip_mount);
+        println!("rollback: {}", status.rollback);
+        for problem in &status.problems {
+            println!("problem: {problem}");
+        }
+        Ok(())
+    })
+}
+
+// ids of every module dir, staged update and snapshot
+fn module_ids() -> BTreeSet<String> {
+    [
+        defs::MODULE_DIR,
+        defs::MODULE_UPDATE_DIR,
+        defs::MODULE_SNAPSHOT_DIR,
+    ]
+    .into_iter()
+    .filter_map(|dir| std::fs::read_dir(defs::resolve(dir)).ok())
+    .flat_map(|dir| dir.flatten())
+    .filter(|entry| entry.path().is_dir())
+    .filter_map(|entry| entry.file_name().into_string().ok())
+    .collect()
+}
+
+// one problem at a time, a repair may solve others
+fn repair_module(module: &Module) -> Result<Vec<Transition>> {
+    let mut transitions = Vec::new();
+    let mut unrepairable = Vec::new();
+    loop {
+        let Some(problem) = module
+            .problems()
+            .into_iter()
+            .find(|problem| !unrepairable.contains(problem))
+        else {
+            return Ok(transitions);
+        };
+        let from = module.state();
+        let Some(action) = module.repair(&problem)? else {
+            unrepairable.push(problem);
+            continue;
+        };
+        transitions.push(Transition {
+            id: module.id.clone(),
+            from,
+            to: module.state(),
+            action,
+        });
+        if module.problems().contains(&problem) {
+            unrepairable.push(problem);
+        }
+    }
+}
+
+/// Repair the modules before they are loaded on boot
+pub fn repair_all() -> Result<()> {
+    for id in module_ids() {
+        for transition in repair_module(&Module::new(&id))? {
+            info!("{transition}");
+        }
+    }
+    Ok(())
+}
+
+/// Check every module for inconsistent states, they are repaired with <repair>
+pub fn fsck(repair: bool) -> Result<()> {
+    let mut transitions = Vec::new();
+    let mut problems = 0;
+    for id in module_ids() {
+        let module = Module::new(&id);
+        if repair {
+            for transition in repair_module(&module)? {
+                output::progress(&transition);
+                transitions.push(transition);
+            }
+        }
+        let state = module.state();
+        for problem in module.problems() {
+            output::progress(format!("{id}: {state}, {problem}"));
+            problems += 1;
+        }
+    }
+
+    if problems > 0 && repair {
+        bail!("{problems} problems can't be repaired, reinstall or uninstall these modules");
+    } else if problems > 0 {
+        bail!("{problems} problems found, run `ksud module fsck --repair` to repair them");
+    }
+    output::result(&transitions, |transitions| {
+        output::progress(format!(
+            "All modules are consistent, {} repairs made",
+            transitions.len()
+        ));
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn write_prop(dir: &Path, id: &str, version: &str) {
+        fs::create_dir_all(dir).unwrap();
+        let prop = format!("id={id}\nname=Test\nversion={version}\nversionCode=1\n");
+        fs::write(dir.join("module.prop"), prop).unwrap();
+    }
+
+    fn touch(dir: &Path, file: &str) {
+        fs::create_dir_all(dir).unwrap();
+        fs::write(dir.join(file), "").unwrap();
+    }
+
+    fn version(dir: &Path, file: &str) -> String {
+        let prop = fs::read_to_string(dir.join(file)).unwrap();
+        prop.lines()
+            .find_map(|line| line.strip_prefix("version="))
+            .unwrap()
+            .to_string()
+    }
+
+    // the problems before, the transitions made and the problems left
+    fn repair(module: &Module) -> (Vec<Problem>, Vec<(State, State)>, Vec<Problem>) {
+        let before = module.problems();
+        let transitions = repair_module(module)
+            .unwrap()
+            .into_iter()
+            .map(|t| (t.from, t.to))
+            .collect();
+        (before, transitions, module.problems())
+    }
+
+    #[test]
+    fn consistent_module() {
+        let root = tempfile::tempdir().unwrap();
+        let module = Module::in_root(root.path(), "demo");
+        assert_eq!(module.state(), State::NotInstalled);
+        write_prop(&module.dir, "demo", "v1");
+        assert_eq!(module.state(), State::Enabled);
+        assert_eq!(repair(&module), (vec![], vec![], vec![]));
+    }
+
+    #[test]
+    fn remove_and_update() {
+        let root = tempfile::tempdir().unwrap();
+        let module = Module::in_root(root.path(), "demo");
+        write_prop(&module.dir, "demo", "v1");
+        touch(&module.dir, "service.sh");
+        // updated, then uninstalled
+        write_prop(&module.update, "demo", "v2");
+        module::mark_updated(&module.dir, &module.update).unwrap();
+        touch(&module.dir, defs::REMOVE_FILE_NAME);
+
+        let (before, transitions, after) = repair(&module);
+        assert_eq!(before, [Problem::RemoveAndUpdate]);
+        assert_eq!(
+            transitions,
+            [(State::PendingRemoval, State::PendingRemoval)]
+        );
+        assert!(after.is_empty());
+        assert!(!module.update.exists());
+        assert!(!module.has(defs::UPDATE_FILE_NAME));
+        assert!(module.has(defs::REMOVE_FILE_NAME));
+        assert_eq!(version(&module.dir, "module.prop"), "v1");
+    }
+
+    #[test]
+    fn update_without_stub() {
+        let root = tempfile::tempdir().unwrap();
+        let module = Module::in_root(root.path(), "demo");
+        write_prop(&module.dir, "demo", "v1");
+        touch(&module.dir, "service.sh");
+        write_prop(&module.update, "demo", "v2");
+
+        let (before, transitions, after) = repair(&module);
+        assert_eq!(before, [Problem::UpdateWithoutStub]);
+        assert_eq!(transitions, [(State::Enabled, State::PendingUpdate)]);
+        assert!(after.is_empty());
+        assert!(module.has(defs::UPDATE_FILE_NAME));
+        assert_eq!(version(&module.dir, "module.prop"), "v2");
+        assert_eq!(version(&module.dir, defs::PREVIOUS_MODULE_PROP), "v1");
+    }
+
+    #[test]
+    fn stub_without_update() {
+        let root = tempfile::tempdir().unwrap();
+        // an installed module keeps running
+        let module = Module::in_root(root.path(), "installed");
+        write_prop(&module.dir, "installed", "v1");
+        touch(&module.dir, "service.sh");
+        touch(&module.dir, defs::UPDATE_FILE_NAME);
+        let (before, transitions, after) = repair(&module);
+        assert_eq!(before, [Problem::StubWithoutUpdate]);
+        assert_eq!(transitions, [(State::Enabled, State::Enabled)]);
+        assert!(after.is_empty());
+        assert!(!module.has(defs::UPDATE_FILE_NAME));
+
+        // nothing is left of a fresh installation
+        let module = Module::in_root(root.path(), "fresh");
+        write_prop(&module.dir, "fresh", "v1");
+        touch(&module.dir, defs::UPDATE_FILE_NAME);
+        let (before, transitions, after) = repair(&module);
+        assert_eq!(before, [Problem::StubWithoutUpdate]);
+        assert_eq!(transitions, [(State::Enabled, State::NotInstalled)]);
+        assert!(after.is_empty());
+        assert!(!module.dir.exists());
+    }
+
+    #[test]
+    fn orphan_snapshot() {
+        let root = tempfile::tempdir().unwrap();
+        let module = Module::in_root(root.path(), "demo");
+        fs::create_dir_all(module.dir.parent().unwrap()).unwrap();
+        write_prop(&module.snapshot, "demo", "v1");
+
+        let (before, transitions, after) = repair(&module);
+        assert_eq!(before, [Problem::OrphanSnapshot]);
+        assert_eq!(transitions, [(State::NotInstalled, State::Enabled)]);
+        assert!(after.is_empty());
+        assert!(!module.snapshot.exists());
+        assert_eq!(version(&module.dir, "module.prop"), "v1");
+    }
+
+    #[test]
+    fn missing_prop() {
+        let root = tempfile::tempdir().unwrap();
+        // a placeholder without module.prop is removed
+        let module = Module::in_root(root.path(), "empty");
+        touch(&module.dir, defs::DISABLE_FILE_NAME);
+        let (before, transitions, after) = repair(&module);
+        assert_eq!(before, [Problem::MissingProp]);
+        assert_eq!(transitions, [(State::Broken, State::NotInstalled)]);
+        assert!(after.is_empty());
+        assert!(!module.dir.exists());
+
+        // the files of a module are never removed
+        let module = Module::in_root(root.path(), "broken");
+        touch(&module.dir, "service.sh");
+        let (before, transitions, after) = repair(&module);
+        assert_eq!(before, [Problem::MissingProp]);
+        assert!(transitions.is_empty());
+        assert_eq!(after, [Problem::MissingProp]);
+        assert_eq!(module.state(), State::Broken);
+        assert!(module.has("service.sh"));
+    }
+}
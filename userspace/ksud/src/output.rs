@@ -0,0 +1,96 @@
+use anyhow::Result;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::fmt::Display;
+use std::io::{BufRead, BufReader, Write};
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// `--format` of the cli
+#[derive(clap::ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Format {
+    /// human readable text
+    #[default]
+    Text,
+    /// one json object per line: progress events, then a result or an error
+    Json,
+}
+
+static JSON: AtomicBool = AtomicBool::new(false);
+// the command printed its result itself
+static RESULT_PRINTED: AtomicBool = AtomicBool::new(false);
+
+pub fn set_format(format: Format) {
+    JSON.store(format == Format::Json, Ordering::Relaxed);
+}
+
+pub fn is_json() -> bool {
+    JSON.load(Ordering::Relaxed)
+}
+
+fn print_event(event: &Value) {
+    let mut stdout = std::io::stdout().lock();
+    // nothing to report a broken stdout to
+    let _ = writeln!(stdout, "{event}");
+    let _ = stdout.flush();
+}
+
+/// A step of a running command, `- <message>` in text format
+pub fn progress(message: impl Display) {
+    if is_json() {
+        print_event(&json!({ "type": "progress", "message": message.to_string() }));
+    } else {
+        println!("- {message}");
+    }
+}
+
+/// Print the result of a command, <text> prints it in text format
+pub fn result<T: Serialize + ?Sized>(data: &T, text: impl FnOnce(&T) -> Result<()>) -> Result<()> {
+    if !is_json() {
+        return text(data);
+    }
+    let data = serde_json::to_value(data)?;
+    print_event(&json!({ "type": "result", "ok": true, "data": data }));
+    RESULT_PRINTED.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+/// End a command, in json format every command ends with a result or an error event
+pub fn finish(result: Result<()>) -> Result<()> {
+    if !is_json() {
+        return result;
+    }
+    match &result {
+        Ok(()) if !RESULT_PRINTED.load(Ordering::Relaxed) => {
+            print_event(&json!({ "type": "result", "ok": true }));
+        }
+        Ok(()) => {}
+        Err(e) => {
+            let causes: Vec<String> = e.chain().skip(1).map(|c| c.to_string()).collect();
+            print_event(&json!({
+                "type": "error",
+                "ok": false,
+                "message": e.to_string(),
+                "causes": causes,
+            }));
+        }
+    }
+    result
+}
+
+/// Run <command> until it exits, its output lines become progress events in json format
+pub fn run_command(command: &mut Command) -> std::io::Result<ExitStatus> {
+    if !is_json() {
+        return command.status();
+    }
+    let mut child = command.stdout(Stdio::piped()).spawn()?;
+    if let Some(stdout) = child.stdout.take() {
+        for line in BufReader::new(stdout)
+            .lines()
+            .map_while(std::io::Result::ok)
+        {
+            progress(line);
+        }
+    }
+    child.wait()
+}
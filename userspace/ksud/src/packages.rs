@@ -0,0 +1,153 @@
+use anyhow::{bail, Context, Result};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use crate::{defs, output};
+
+// keep in sync with android.os.UserHandle
+pub const PER_USER_RANGE: i32 = 100000;
+
+pub fn app_id(uid: i32) -> i32 {
+    uid % PER_USER_RANGE
+}
+
+pub fn uid_of_user(user_id: i32, app_id: i32) -> i32 {
+    user_id * PER_USER_RANGE + app_id
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Package {
+    pub name: String,
+    /// app id, i.e. uid of user 0
+    pub uid: i32,
+}
+
+/// Installed packages parsed from `/data/system/packages.list`.
+///
+/// packages.list only has uids of user 0, uids of other users are derived from [`PER_USER_RANGE`].
+#[derive(Debug, Default)]
+pub struct Packages {
+    packages: Vec<Package>,
+}
+
+impl Packages {
+    pub fn load() -> Result<Self> {
+        Self::load_in(defs::root())
+    }
+
+    /// Same as [`Packages::load`], against <root> instead of the root prefix
+    pub fn load_in(root: &Path) -> Result<Self> {
+        let path = defs::resolve_in(root, Path::new(defs::PACKAGES_LIST_PATH));
+        let content = std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        Ok(Self::parse(&content))
+    }
+
+    // <name> <uid> <debuggable> <data dir> <seinfo> <gids> ..., only the first two are used
+    pub fn parse(content: &str) -> Self {
+        let packages = content
+            .lines()
+            .filter_map(|line| {
+                let mut fields = line.split_whitespace();
+                let name = fields.next()?;
+                let Ok(uid) = fields.next()?.parse::<i32>() else {
+                    log::warn!("invalid packages.list line: {line}");
+                    return None;
+                };
+                Some(Package {
+                    name: name.to_string(),
+                    uid,
+                })
+            })
+            .collect();
+        Self { packages }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Package> {
+        self.packages.iter()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Package> {
+        self.packages.iter().find(|p| p.name == name)
+    }
+
+    /// uid of <name> for <user_id>
+    pub fn uid_of(&self, name: &str, user_id: i32) -> Result<i32> {
+        let Some(package) = self.get(name) else {
+            bail!("package {name} is not installed");
+        };
+        Ok(uid_of_user(user_id, package.uid))
+    }
+
+    /// packages owning <uid> of any user, more than one for shared uid
+    pub fn packages_of(&self, uid: i32) -> Vec<&str> {
+        let app_id = app_id(uid);
+        self.packages
+            .iter()
+            .filter(|p| p.uid == app_id)
+            .map(|p| p.name.as_str())
+            .collect()
+    }
+
+    pub fn is_uid_installed(&self, uid: i32) -> bool {
+        let app_id = app_id(uid);
+        self.packages.iter().any(|p| p.uid == app_id)
+    }
+
+    /// app ids shared by more than one package
+    pub fn shared_uid_groups(&self) -> BTreeMap<i32, Vec<&str>> {
+        let mut groups: BTreeMap<i32, Vec<&str>> = BTreeMap::new();
+        for package in &self.packages {
+            groups
+                .entry(package.uid)
+                .or_default()
+                .push(package.name.as_str());
+        }
+        groups.retain(|_, names| names.len() > 1);
+        groups
+    }
+
+    /// Resolve <uid>, <package> or <package>@<user_id> to package name and uid.
+    pub fn resolve(&self, target: &str) -> Result<(String, i32)> {
+        if let Ok(uid) = target.parse::<i32>() {
+            let mut names = self.packages_of(uid);
+            if names.is_empty() {
+                bail!("no package with uid {uid} is installed");
+            }
+            names.sort_unstable();
+            return Ok((names[0].to_string(), uid));
+        }
+
+        let (name, user_id) = match target.rsplit_once('@') {
+            Some((name, user_id)) => (
+                name,
+                user_id
+                    .parse::<i32>()
+                    .with_context(|| format!("invalid user id: {user_id}"))?,
+            ),
+            None => (target, 0),
+        };
+        Ok((name.to_string(), self.uid_of(name, user_id)?))
+    }
+}
+
+pub fn list_packages(shared_only: bool) -> Result<()> {
+    let packages = Packages::load()?;
+    if shared_only {
+        output::result(&packages.shared_uid_groups(), |groups| {
+            for (uid, names) in groups {
+                println!("{uid}\t{}", names.join(","));
+            }
+            Ok(())
+        })
+    } else {
+        let packages: Vec<&Package> = packages.iter().collect();
+        output::result(&packages, |packages| {
+            for package in packages {
+                println!("{}\t{}", package.uid, package.name);
+            }
+            Ok(())
+        })
+    }
+}
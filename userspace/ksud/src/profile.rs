@@ -1,61 +1,144 @@
+use crate::allowlist;
+use crate::ksucalls::{self, AppProfile, KernelInterface, DEFAULT_SELINUX_DOMAIN};
+use crate::output;
+use crate::packages::Packages;
 use crate::utils::ensure_dir_exists;
 use crate::{defs, sepolicy};
-use anyhow::{Context, Result};
+use anyhow::{bail, ensure, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
 use std::path::Path;
 
 pub fn set_sepolicy(pkg: String, policy: String) -> Result<()> {
-    ensure_dir_exists(defs::PROFILE_SELINUX_DIR)?;
-    let policy_file = Path::new(defs::PROFILE_SELINUX_DIR).join(pkg);
+    let selinux_dir = defs::resolve(defs::PROFILE_SELINUX_DIR);
+    ensure_dir_exists(&selinux_dir)?;
+    let policy_file = selinux_dir.join(pkg);
     std::fs::write(&policy_file, policy)?;
     sepolicy::apply_file(&policy_file)?;
     Ok(())
 }
 
 pub fn get_sepolicy(pkg: String) -> Result<()> {
-    let policy_file = Path::new(defs::PROFILE_SELINUX_DIR).join(pkg);
+    let policy_file = defs::resolve(defs::PROFILE_SELINUX_DIR).join(pkg);
     let policy = std::fs::read_to_string(policy_file)?;
-    println!("{policy}");
-    Ok(())
+    output::result(&policy, |policy| {
+        println!("{policy}");
+        Ok(())
+    })
 }
 
 // ksud doesn't guarteen the correctness of template, it just save
 pub fn set_template(id: String, template: String) -> Result<()> {
-    ensure_dir_exists(defs::PROFILE_TEMPLATE_DIR)?;
-    let template_file = Path::new(defs::PROFILE_TEMPLATE_DIR).join(id);
+    let template_dir = defs::resolve(defs::PROFILE_TEMPLATE_DIR);
+    ensure_dir_exists(&template_dir)?;
+    let template_file = template_dir.join(id);
     std::fs::write(template_file, template)?;
     Ok(())
 }
 
 pub fn get_template(id: String) -> Result<()> {
-    let template_file = Path::new(defs::PROFILE_TEMPLATE_DIR).join(id);
+    let template_file = defs::resolve(defs::PROFILE_TEMPLATE_DIR).join(id);
     let template = std::fs::read_to_string(template_file)?;
-    println!("{template}");
-    Ok(())
+    output::result(&template, |template| {
+        println!("{template}");
+        Ok(())
+    })
 }
 
 pub fn delete_template(id: String) -> Result<()> {
-    let template_file = Path::new(defs::PROFILE_TEMPLATE_DIR).join(id);
+    let template_file = defs::resolve(defs::PROFILE_TEMPLATE_DIR).join(id);
     std::fs::remove_file(template_file)?;
     Ok(())
 }
 
 pub fn list_templates() -> Result<()> {
-    let templates = std::fs::read_dir(defs::PROFILE_TEMPLATE_DIR);
-    let Ok(templates) = templates else {
-        return Ok(());
-    };
-    for template in templates {
-        let template = template?;
-        let template = template.file_name();
-        if let Some(template) = template.to_str() {
-            println!("{template}");
+    let templates = std::fs::read_dir(defs::resolve(defs::PROFILE_TEMPLATE_DIR));
+    let mut names = Vec::new();
+    if let Ok(templates) = templates {
+        for template in templates {
+            let template = template?;
+            if let Some(template) = template.file_name().to_str() {
+                names.push(template.to_string());
+            };
+        }
+    }
+    output::result(&names, |names| {
+        names.iter().for_each(|name| println!("{name}"));
+        Ok(())
+    })
+}
+
+// ids of the templates, the manager saves their sepolicy rules under the template id
+fn template_ids(root: &Path) -> Result<HashSet<String>> {
+    let mut ids = HashSet::new();
+    let template_dir = defs::resolve_in(root, Path::new(defs::PROFILE_TEMPLATE_DIR));
+    if template_dir.exists() {
+        for entry in std::fs::read_dir(template_dir)? {
+            if let Some(id) = entry?.file_name().to_str() {
+                ids.insert(id.to_string());
+            }
+        }
+    }
+    let allowlist = defs::resolve_in(root, Path::new(defs::ALLOWLIST_PATH));
+    if allowlist.exists() {
+        for app in allowlist::load_allowlist(&allowlist)? {
+            let template = app.allow_su.then(|| app.root_config().template_name());
+            ids.extend(template.filter(|template| !template.is_empty()));
+        }
+    }
+    Ok(ids)
+}
+
+/// Remove sepolicy rules of uninstalled packages, returns the removed packages.
+/// Rules of templates are kept, whether a profile uses them or not.
+pub fn prune_sepolicies(dry_run: bool) -> Result<Vec<String>> {
+    prune_sepolicies_in(defs::root(), dry_run)
+}
+
+fn prune_sepolicies_in(root: &Path, dry_run: bool) -> Result<Vec<String>> {
+    let path = defs::resolve_in(root, Path::new(defs::PROFILE_SELINUX_DIR));
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+
+    // never treat everything as uninstalled because of a broken packages.list
+    let packages = Packages::load_in(root)?;
+    ensure!(packages.iter().next().is_some(), "packages.list is empty");
+    let templates = template_ids(root)?;
+
+    let mut pruned = Vec::new();
+    for entry in std::fs::read_dir(&path)? {
+        let entry = entry?;
+        let Some(pkg) = entry.file_name().to_str().map(str::to_string) else {
+            continue;
         };
+        if packages.get(&pkg).is_some() || templates.contains(&pkg) {
+            continue;
+        }
+        if !dry_run {
+            std::fs::remove_file(entry.path())
+                .with_context(|| format!("Failed to remove sepolicy of {pkg}"))?;
+        }
+        log::info!("prune sepolicy of uninstalled package: {pkg}");
+        pruned.push(pkg);
+    }
+    Ok(pruned)
+}
+
+pub fn prune(dry_run: bool) -> Result<()> {
+    let pruned = prune_sepolicies(dry_run)?;
+    let action = if dry_run { "Would remove" } else { "Removed" };
+    for pkg in &pruned {
+        output::progress(format!("{action} sepolicy of {pkg}"));
+    }
+    if pruned.is_empty() {
+        output::progress("Nothing to prune");
     }
     Ok(())
 }
 
 pub fn apply_sepolies() -> Result<()> {
-    let path = Path::new(defs::PROFILE_SELINUX_DIR);
+    let path = defs::resolve(defs::PROFILE_SELINUX_DIR);
     if !path.exists() {
         log::info!("profile sepolicy dir not exists.");
         return Ok(());
@@ -77,3 +160,533 @@ pub fn apply_sepolies() -> Result<()> {
     }
     Ok(())
 }
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Namespace {
+    Inherited,
+    Global,
+    Individual,
+}
+
+impl From<i32> for Namespace {
+    fn from(value: i32) -> Self {
+        match value {
+            1 => Namespace::Global,
+            2 => Namespace::Individual,
+            _ => Namespace::Inherited,
+        }
+    }
+}
+
+/// App Profile as the manager sees it, the root fields are only meaningful if `allow_su` is set,
+/// and the non-root fields only if it isn't.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Profile {
+    pub key: String,
+    pub current_uid: i32,
+    pub allow_su: bool,
+
+    pub root_use_default: bool,
+    pub root_template: Option<String>,
+    pub uid: i32,
+    pub gid: i32,
+    pub groups: Vec<i32>,
+    pub capabilities: Vec<u32>,
+    pub selinux_domain: String,
+    pub namespace: Namespace,
+
+    pub non_root_use_default: bool,
+    pub umount_modules: bool,
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Profile {
+            key: String::new(),
+            current_uid: 0,
+            allow_su: false,
+            root_use_default: true,
+            root_template: None,
+            uid: 0,
+            gid: 0,
+            groups: Vec::new(),
+            capabilities: Vec::new(),
+            selinux_domain: DEFAULT_SELINUX_DOMAIN.to_string(),
+            namespace: Namespace::Inherited,
+            non_root_use_default: true,
+            umount_modules: true,
+        }
+    }
+}
+
+impl From<&AppProfile> for Profile {
+    fn from(app: &AppProfile) -> Self {
+        let mut profile = Profile {
+            key: app.key(),
+            current_uid: app.current_uid,
+            allow_su: app.allow_su,
+            ..Default::default()
+        };
+        if app.allow_su {
+            let config = app.root_config();
+            let template = config.template_name();
+            profile.root_use_default = config.use_default;
+            profile.root_template = (!template.is_empty()).then_some(template);
+            profile.uid = config.profile.uid;
+            profile.gid = config.profile.gid;
+            profile.groups = config.profile.groups().to_vec();
+            let effective = config.profile.capabilities.effective;
+            profile.capabilities = (0..64).filter(|i| effective & (1 << i) != 0).collect();
+            profile.selinux_domain = config.profile.selinux_domain();
+            profile.namespace = config.profile.namespaces.into();
+        } else {
+            let config = app.non_root_config();
+            profile.non_root_use_default = config.use_default;
+            profile.umount_modules = config.profile.umount_modules;
+        }
+        profile
+    }
+}
+
+impl Profile {
+    pub fn to_app_profile(&self) -> Result<AppProfile> {
+        let mut app = AppProfile::new(&self.key, self.current_uid)?;
+        app.allow_su = self.allow_su;
+        if self.allow_su {
+            let config = app.root_config_mut();
+            config.use_default = self.root_use_default;
+            config.set_template_name(self.root_template.as_deref().unwrap_or_default())?;
+            config.profile.uid = self.uid;
+            config.profile.gid = self.gid;
+            config.profile.set_groups(&self.groups)?;
+            for cap in &self.capabilities {
+                if *cap >= 64 {
+                    bail!("invalid capability: {cap}");
+                }
+                config.profile.capabilities.effective |= 1 << cap;
+            }
+            config.profile.set_selinux_domain(&self.selinux_domain)?;
+            config.profile.namespaces = self.namespace as i32;
+        } else {
+            let config = app.non_root_config_mut();
+            config.use_default = self.non_root_use_default;
+            config.profile.umount_modules = self.umount_modules;
+        }
+        Ok(app)
+    }
+
+    fn print(&self) {
+        let access = if self.allow_su { "allow su" } else { "deny su" };
+        println!("{} (uid {}): {access}", self.key, self.current_uid);
+        if self.allow_su {
+            if self.root_use_default {
+                println!("  use default root profile");
+                return;
+            }
+            if let Some(template) = &self.root_template {
+                println!("  template: {template}");
+            }
+            println!("  uid: {}, gid: {}", self.uid, self.gid);
+            println!("  groups: {:?}", self.groups);
+            println!("  capabilities: {:?}", self.capabilities);
+            println!("  domain: {}", self.selinux_domain);
+            println!("  namespace: {:?}", self.namespace);
+        } else if self.non_root_use_default {
+            println!("  use default non-root profile");
+        } else {
+            println!("  umount modules: {}", self.umount_modules);
+        }
+    }
+}
+
+/// Fields of a profile to change, None means unchanged
+#[derive(Debug, Default)]
+pub struct ProfileUpdate {
+    pub allow_su: Option<bool>,
+    pub use_default: Option<bool>,
+    pub template: Option<String>,
+    pub uid: Option<i32>,
+    pub gid: Option<i32>,
+    pub groups: Option<Vec<i32>>,
+    pub capabilities: Option<Vec<u32>>,
+    pub selinux_domain: Option<String>,
+    pub namespace: Option<Namespace>,
+    pub umount_modules: Option<bool>,
+}
+
+impl ProfileUpdate {
+    fn apply(self, profile: &mut Profile) {
+        if let Some(allow_su) = self.allow_su {
+            profile.allow_su = allow_su;
+        }
+        if let Some(use_default) = self.use_default {
+            if profile.allow_su {
+                profile.root_use_default = use_default;
+            } else {
+                profile.non_root_use_default = use_default;
+            }
+        }
+        // customizing a field implies not using the default profile
+        let customize_root = self.template.is_some()
+            || self.uid.is_some()
+            || self.gid.is_some()
+            || self.groups.is_some()
+            || self.capabilities.is_some()
+            || self.selinux_domain.is_some()
+            || self.namespace.is_some();
+        if customize_root && self.use_default.is_none() {
+            profile.root_use_default = false;
+        }
+        if self.umount_modules.is_some() && self.use_default.is_none() {
+            profile.non_root_use_default = false;
+        }
+        if let Some(template) = self.template {
+            profile.root_template = (!template.is_empty()).then_some(template);
+        }
+        if let Some(uid) = self.uid {
+            profile.uid = uid;
+        }
+        if let Some(gid) = self.gid {
+            profile.gid = gid;
+        }
+        if let Some(groups) = self.groups {
+            profile.groups = groups;
+        }
+        if let Some(capabilities) = self.capabilities {
+            profile.capabilities = capabilities;
+        }
+        if let Some(domain) = self.selinux_domain {
+            profile.selinux_domain = domain;
+        }
+        if let Some(namespace) = self.namespace {
+            profile.namespace = namespace;
+        }
+        if let Some(umount_modules) = self.umount_modules {
+            profile.umount_modules = umount_modules;
+        }
+    }
+}
+
+// <target> is either a package name or an uid
+fn load_profile(
+    kernel: &dyn KernelInterface,
+    target: &str,
+    packages: impl FnOnce() -> Result<Packages>,
+) -> Result<Profile> {
+    // existing profiles can be managed even if the package is gone
+    if let Ok(uid) = target.parse::<i32>() {
+        if let Some(app) = kernel.get_app_profile(uid)? {
+            return Ok(Profile::from(&app));
+        }
+    }
+
+    let (key, uid) = packages()?.resolve(target)?;
+    match kernel.get_app_profile(uid)? {
+        // shared uid packages have one profile
+        Some(app) => Ok(Profile::from(&app)),
+        None => Ok(Profile {
+            key,
+            current_uid: uid,
+            ..Default::default()
+        }),
+    }
+}
+
+pub fn get_profile(target: String) -> Result<()> {
+    let kernel = ksucalls::kernel();
+    let profile = load_profile(kernel, &target, Packages::load)?;
+    output::result(&profile, |profile| {
+        profile.print();
+        println!(
+            "  granted root: {}, umount modules: {}",
+            kernel.uid_granted_root(profile.current_uid)?,
+            kernel.uid_should_umount(profile.current_uid)?
+        );
+        Ok(())
+    })
+}
+
+pub fn set_profile(target: String, update: ProfileUpdate) -> Result<()> {
+    let profile = update_profile(ksucalls::kernel(), &target, update, Packages::load)?;
+    output::result(&profile, |profile| {
+        profile.print();
+        Ok(())
+    })
+}
+
+fn update_profile(
+    kernel: &dyn KernelInterface,
+    target: &str,
+    update: ProfileUpdate,
+    packages: impl FnOnce() -> Result<Packages>,
+) -> Result<Profile> {
+    let mut profile = load_profile(kernel, target, packages)?;
+    update.apply(&mut profile);
+    kernel.set_app_profile(&profile.to_app_profile()?)?;
+    Ok(profile)
+}
+
+pub fn allow_su(target: String) -> Result<()> {
+    set_profile(
+        target,
+        ProfileUpdate {
+            allow_su: Some(true),
+            ..Default::default()
+        },
+    )
+}
+
+pub fn deny_su(target: String) -> Result<()> {
+    set_profile(
+        target,
+        ProfileUpdate {
+            allow_su: Some(false),
+            ..Default::default()
+        },
+    )
+}
+
+pub fn list_profiles() -> Result<()> {
+    print_profiles(&kernel_profiles(ksucalls::kernel())?)
+}
+
+fn kernel_profiles(kernel: &dyn KernelInterface) -> Result<Vec<Profile>> {
+    let mut profiles = Vec::new();
+    for uid in kernel
+        .get_allow_list()?
+        .into_iter()
+        .chain(kernel.get_deny_list()?)
+    {
+        match kernel.get_app_profile(uid)? {
+            Some(app) => profiles.push(Profile::from(&app)),
+            None => log::warn!("profile of uid {uid} disappeared"),
+        }
+    }
+    Ok(profiles)
+}
+
+/// Print profiles in `profile list` format, marking those whose package is uninstalled
+pub fn print_profiles(profiles: &[Profile]) -> Result<()> {
+    output::result(profiles, |profiles| {
+        let packages = Packages::load().inspect_err(|e| log::warn!("{e:?}")).ok();
+        for profile in profiles {
+            let access = if profile.allow_su { "allow" } else { "deny" };
+            let installed = match &packages {
+                Some(packages) => packages.is_uid_installed(profile.current_uid),
+                None => true,
+            };
+            let state = if installed { "" } else { "\tuninstalled" };
+            println!("{}\t{access}\t{}{state}", profile.current_uid, profile.key);
+        }
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ksucalls::MockKernel;
+    use std::path::PathBuf;
+
+    fn packages() -> Result<Packages> {
+        Ok(Packages::parse(
+            "com.example.a 10100 0 /data/user/0/com.example.a default none\n\
+             com.example.b 10101 0 /data/user/0/com.example.b default none\n",
+        ))
+    }
+
+    fn no_packages() -> Result<Packages> {
+        bail!("packages.list should not be read")
+    }
+
+    fn allow(allow_su: bool) -> ProfileUpdate {
+        ProfileUpdate {
+            allow_su: Some(allow_su),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn get_new_profile() {
+        let kernel = MockKernel::new(0);
+        let profile = load_profile(&kernel, "com.example.a@10", packages).unwrap();
+        assert_eq!(profile.key, "com.example.a");
+        assert_eq!(profile.current_uid, 1_010_100);
+        assert!(!profile.allow_su);
+        assert!(profile.non_root_use_default);
+        assert!(load_profile(&kernel, "com.example.c", packages).is_err());
+    }
+
+    #[test]
+    fn allow_and_deny() {
+        let kernel = MockKernel::new(0);
+        let profile = update_profile(&kernel, "com.example.a", allow(true), packages).unwrap();
+        assert!(profile.allow_su && profile.root_use_default);
+        assert!(kernel.uid_granted_root(10100).unwrap());
+        assert_eq!(kernel.get_allow_list().unwrap(), [10100]);
+
+        // an existing profile is found by uid without packages.list
+        let profile = update_profile(&kernel, "10100", allow(false), no_packages).unwrap();
+        assert_eq!(profile.key, "com.example.a");
+        assert!(!kernel.uid_granted_root(10100).unwrap());
+        assert!(kernel.get_allow_list().unwrap().is_empty());
+        assert_eq!(kernel.get_deny_list().unwrap(), [10100]);
+
+        // unknown uids are resolved through packages.list
+        assert!(update_profile(&kernel, "10101", allow(true), no_packages).is_err());
+        update_profile(&kernel, "10101", allow(true), packages).unwrap();
+        let keys: Vec<_> = kernel_profiles(&kernel)
+            .unwrap()
+            .into_iter()
+            .map(|p| (p.key, p.allow_su))
+            .collect();
+        assert_eq!(
+            keys,
+            [
+                ("com.example.b".to_string(), true),
+                ("com.example.a".to_string(), false)
+            ]
+        );
+    }
+
+    #[test]
+    fn set_root_profile() {
+        let kernel = MockKernel::new(0);
+        let update = ProfileUpdate {
+            allow_su: Some(true),
+            uid: Some(2000),
+            gid: Some(2000),
+            groups: Some(vec![3003, 1004]),
+            capabilities: Some(vec![0, 21]),
+            selinux_domain: Some("u:r:ksu_test:s0".to_string()),
+            ..Default::default()
+        };
+        update_profile(&kernel, "com.example.a", update, packages).unwrap();
+
+        let profile = load_profile(&kernel, "com.example.a", no_packages);
+        assert!(profile.is_err(), "package names always need packages.list");
+        let profile = load_profile(&kernel, "10100", no_packages).unwrap();
+        assert!(profile.allow_su);
+        assert!(!profile.root_use_default);
+        assert_eq!((profile.uid, profile.gid), (2000, 2000));
+        assert_eq!(profile.groups, [3003, 1004]);
+        assert_eq!(profile.capabilities, [0, 21]);
+        assert_eq!(profile.selinux_domain, "u:r:ksu_test:s0");
+
+        // the kernel rejects it, nothing is changed
+        let update = ProfileUpdate {
+            capabilities: Some(vec![64]),
+            ..Default::default()
+        };
+        assert!(update_profile(&kernel, "10100", update, no_packages).is_err());
+        let profile = load_profile(&kernel, "10100", no_packages).unwrap();
+        assert_eq!(profile.capabilities, [0, 21]);
+    }
+
+    #[test]
+    fn set_non_root_profile() {
+        let kernel = MockKernel::new(0);
+        assert!(kernel.uid_should_umount(10100).unwrap());
+        let update = ProfileUpdate {
+            umount_modules: Some(false),
+            ..Default::default()
+        };
+        let profile = update_profile(&kernel, "com.example.a", update, packages).unwrap();
+        assert!(!profile.non_root_use_default);
+        assert!(!kernel.uid_should_umount(10100).unwrap());
+        assert!(kernel.uid_should_umount(10101).unwrap());
+    }
+
+    fn write(root: &Path, path: &str, content: &str) -> PathBuf {
+        let path = defs::resolve_in(root, Path::new(path));
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    fn sepolicies(root: &Path) -> Vec<String> {
+        let dir = defs::resolve_in(root, Path::new(defs::PROFILE_SELINUX_DIR));
+        let mut names: Vec<_> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        names
+    }
+
+    // tmpl.a is saved by the manager, tmpl.b is only used by a profile
+    fn with_templates(root: &Path) {
+        write(root, &format!("{}tmpl.a", defs::PROFILE_TEMPLATE_DIR), "{}");
+        let mut used = AppProfile::new("com.example.a", 10100).unwrap();
+        used.allow_su = true;
+        let config = used.root_config_mut();
+        config.set_template_name("tmpl.b").unwrap();
+        config
+            .profile
+            .set_selinux_domain(DEFAULT_SELINUX_DOMAIN)
+            .unwrap();
+        let mut denied = AppProfile::new("com.example.b", 10101).unwrap();
+        denied.non_root_config_mut().use_default = true;
+        let allowlist = defs::resolve_in(root, Path::new(defs::ALLOWLIST_PATH));
+        allowlist::save_allowlist(&allowlist, &[used, denied]).unwrap();
+    }
+
+    #[test]
+    fn find_template_ids() {
+        let root = tempfile::tempdir().unwrap();
+        assert!(template_ids(root.path()).unwrap().is_empty());
+        with_templates(root.path());
+        let mut ids: Vec<_> = template_ids(root.path()).unwrap().into_iter().collect();
+        ids.sort();
+        assert_eq!(ids, ["tmpl.a", "tmpl.b"]);
+    }
+
+    #[test]
+    fn prune_uninstalled_packages() {
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path();
+        assert!(prune_sepolicies_in(root, false).unwrap().is_empty());
+
+        with_templates(root);
+        write(
+            root,
+            defs::PACKAGES_LIST_PATH,
+            "com.example.a 10100 0 /data/user/0/com.example.a default none\n",
+        );
+        for name in ["com.example.a", "com.example.gone", "tmpl.a", "tmpl.b"] {
+            write(
+                root,
+                &format!("{}{name}", defs::PROFILE_SELINUX_DIR),
+                "allow su * * *",
+            );
+        }
+
+        assert_eq!(
+            prune_sepolicies_in(root, true).unwrap(),
+            ["com.example.gone"]
+        );
+        assert_eq!(sepolicies(root).len(), 4);
+        assert_eq!(
+            prune_sepolicies_in(root, false).unwrap(),
+            ["com.example.gone"]
+        );
+        assert_eq!(sepolicies(root), ["com.example.a", "tmpl.a", "tmpl.b"]);
+    }
+
+    #[test]
+    fn keep_sepolicies_without_packages() {
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path();
+        write(
+            root,
+            &format!("{}com.example.a", defs::PROFILE_SELINUX_DIR),
+            "allow su * * *",
+        );
+        assert!(prune_sepolicies_in(root, false).is_err());
+        write(root, defs::PACKAGES_LIST_PATH, "");
+        assert!(prune_sepolicies_in(root, false).is_err());
+        assert_eq!(sepolicies(root), ["com.example.a"]);
+    }
+}
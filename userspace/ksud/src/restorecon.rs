@@ -75,7 +75,7 @@ fn restore_modules_con<P: AsRef<Path>>(dir: P) -> Result<()> {
 }
 
 pub fn restorecon() -> Result<()> {
-    lsetfilecon(defs::DAEMON_PATH, ADB_CON)?;
-    restore_modules_con(defs::MODULE_DIR)?;
+    lsetfilecon(defs::resolve(defs::DAEMON_PATH), ADB_CON)?;
+    restore_modules_con(defs::resolve(defs::MODULE_DIR))?;
     Ok(())
 }
@@ -0,0 +1,343 @@
+use anyhow::{Context, Result};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::utils::ensure_file_exists;
+use crate::{config, defs, output};
+
+// keep the last few actions for `safemode status`
+const MAX_ACTIONS: usize = 10;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Action {
+    time: String,
+    reason: String,
+    disabled: Vec<String>,
+}
+
+/// Boot counter of the userspace boot-loop protection
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct State {
+    /// boots since the last one that completed
+    boot_count: u32,
+    /// modules installed or updated since the last completed boot, newest last
+    recent_updates: Vec<String>,
+    /// recent updates were disabled already, disable everything next time
+    escalated: bool,
+    /// disable all modules on next boot regardless of the counter
+    armed: bool,
+    actions: Vec<Action>,
+}
+
+fn state_path(root: &Path) -> PathBuf {
+    defs::resolve_in(root, Path::new(defs::SAFEMODE_STATE_PATH))
+}
+
+impl State {
+    fn load(root: &Path) -> Self {
+        let path = state_path(root);
+        std::fs::read_to_string(&path)
+            .ok()
+            .and_then(|content| match serde_json::from_str(&content) {
+                Ok(state) => Some(state),
+                Err(e) => {
+                    warn!("invalid {}: {}", path.display(), e);
+                    None
+                }
+            })
+            .unwrap_or_default()
+    }
+
+    // the counter must survive a sudden reboot, write it atomically
+    fn save(&self, root: &Path) -> Result<()> {
+        let path = state_path(root);
+        let tmp = path.with_extension("tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(serde_json::to_string_pretty(self)?.as_bytes())?;
+        // the data must reach the disk before the rename does
+        file.sync_all()?;
+        std::fs::rename(&tmp, &path)
+            .with_context(|| format!("Failed to save {}", path.display()))?;
+        if let Some(dir) = path.parent() {
+            File::open(dir)?.sync_all()?;
+        }
+        Ok(())
+    }
+
+    fn record(&mut self, reason: String, disabled: Vec<String>) {
+        warn!("{reason}, disabled modules: {disabled:?}");
+        self.actions.push(Action {
+            time: chrono::Local::now().to_rfc3339(),
+            reason,
+            disabled,
+        });
+        if self.actions.len() > MAX_ACTIONS {
+            self.actions.remove(0);
+        }
+    }
+
+    // modules of <root> are disabled by the checks of this boot
+    fn count_boot(&mut self, root: &Path, threshold: u32) {
+        self.boot_count += 1;
+        info!("boot count: {}", self.boot_count);
+
+        if self.armed {
+            self.armed = false;
+            self.record("armed for this boot".to_string(), enabled_modules(root));
+            disable_modules(root, &module_ids(root));
+        } else if threshold > 0 && self.boot_count > threshold {
+            let recent: Vec<String> = self
+                .recent_updates
+                .iter()
+                .rev()
+                .filter(|id| is_enabled(root, id))
+                .cloned()
+                .collect();
+            let reason = format!("{} boots in a row did not complete", self.boot_count - 1);
+            if !self.escalated && !recent.is_empty() {
+                disable_modules(root, &recent);
+                self.record(reason, recent);
+                self.escalated = true;
+            } else {
+                self.record(reason, enabled_modules(root));
+                disable_modules(root, &module_ids(root));
+            }
+            // give the remaining modules a new round
+            self.boot_count = 1;
+        }
+    }
+
+    fn boot_completed(&mut self) {
+        self.boot_count = 0;
+        self.recent_updates.clear();
+        self.escalated = false;
+    }
+}
+
+fn module_ids(root: &Path) -> Vec<String> {
+    let modules_dir = defs::resolve_in(root, Path::new(defs::MODULE_DIR));
+    let Ok(dir) = std::fs::read_dir(modules_dir) else {
+        return Vec::new();
+    };
+    let mut ids: Vec<String> = dir
+        .flatten()
+        .filter(|entry| entry.path().is_dir())
+        .map(|entry| entry.file_name().to_string_lossy().to_string())
+        .collect();
+    ids.sort();
+    ids
+}
+
+fn is_enabled(root: &Path, id: &str) -> bool {
+    let module = defs::resolve_in(root, Path::new(defs::MODULE_DIR)).join(id);
+    module.is_dir()
+        && !module.join(defs::DISABLE_FILE_NAME).exists()
+        && !module.join(defs::INCOMPATIBLE_FILE_NAME).exists()
+}
+
+fn enabled_modules(root: &Path) -> Vec<String> {
+    let mut ids = module_ids(root);
+    ids.retain(|id| is_enabled(root, id));
+    ids
+}
+
+// same as module::disable_module, for each of <ids> in <root>
+fn disable_modules(root: &Path, ids: &[String]) {
+    let modules_dir = defs::resolve_in(root, Path::new(defs::MODULE_DIR));
+    for id in ids {
+        if let Err(e) = ensure_file_exists(modules_dir.join(id).join(defs::DISABLE_FILE_NAME)) {
+            warn!("Failed to disable module {id}: {e}");
+        }
+    }
+}
+
+/// Remember that module <id> was installed or updated on this boot
+pub fn record_update(id: &str) {
+    let mut state = State::load(defs::root());
+    state.recent_updates.retain(|m| m != id);
+    state.recent_updates.push(id.to_string());
+    if let Err(e) = state.save(defs::root()) {
+        warn!("Failed to record update of {id}: {e:#}");
+    }
+}
+
+/// Count this boot and disable modules if the last boots never completed.
+/// Recently installed or updated modules are disabled first, then all modules.
+pub fn check_bootloop() -> Result<()> {
+    let root = defs::root();
+    let mut state = State::load(root);
+    state.count_boot(root, config::get().safemode.bootloop_threshold);
+    state.save(root)
+}
+
+/// Boot completed, reset the counter
+pub fn boot_completed() -> Result<()> {
+    let mut state = State::load(defs::root());
+    state.boot_completed();
+    state.save(defs::root())
+}
+
+pub fn status() -> Result<()> {
+    #[derive(Serialize)]
+    struct Status {
+        threshold: u32,
+        #[serde(flatten)]
+        state: State,
+    }
+    let status = Status {
+        threshold: config::get().safemode.bootloop_threshold,
+        state: State::load(defs::root()),
+    };
+    output::result(&status, |Status { threshold, state }| {
+        println!("boot count: {}/{}", state.boot_count, threshold);
+        println!("armed: {}", state.armed);
+        println!("escalated: {}", state.escalated);
+        println!("recent updates: {}", state.recent_updates.join(", "));
+        for action in &state.actions {
+            println!(
+                "{}: {}, disabled: {}",
+                action.time,
+                action.reason,
+                action.disabled.join(", ")
+            );
+        }
+        Ok(())
+    })
+}
+
+pub fn arm_next_boot() -> Result<()> {
+    let mut state = State::load(defs::root());
+    state.armed = true;
+    state.save(defs::root())?;
+    output::progress("All modules will be disabled on next boot");
+    Ok(())
+}
+
+pub fn clear() -> Result<()> {
+    let mut state = State::load(defs::root());
+    // keep the history, modules disabled by it have to be enabled by hand
+    state.boot_completed();
+    state.armed = false;
+    state.save(defs::root())?;
+    if let Some(action) = state.actions.last() {
+        output::progress(format!(
+            "Last disabled modules: {}",
+            action.disabled.join(", ")
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn module(root: &Path, id: &str) {
+        let dir = defs::resolve_in(root, Path::new(defs::MODULE_DIR)).join(id);
+        std::fs::create_dir_all(dir).unwrap();
+    }
+
+    fn disabled(root: &Path) -> Vec<String> {
+        let mut ids = module_ids(root);
+        ids.retain(|id| !is_enabled(root, id));
+        ids
+    }
+
+    // state is saved and loaded on every boot
+    fn boot(root: &Path, threshold: u32) -> State {
+        let mut state = State::load(root);
+        state.count_boot(root, threshold);
+        state.save(root).unwrap();
+        state
+    }
+
+    #[test]
+    fn escalate_boot_loops() {
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path();
+        std::fs::create_dir_all(state_path(root).parent().unwrap()).unwrap();
+        for id in ["mod_a", "mod_b", "mod_c"] {
+            module(root, id);
+        }
+        let mut state = State::load(root);
+        state.recent_updates = vec!["mod_b".to_string(), "mod_gone".to_string()];
+        state.save(root).unwrap();
+
+        assert_eq!(boot(root, 2).boot_count, 1);
+        assert_eq!(boot(root, 2).boot_count, 2);
+        assert!(disabled(root).is_empty());
+
+        // recent updates first
+        let state = boot(root, 2);
+        assert_eq!(state.boot_count, 1);
+        assert!(state.escalated);
+        assert_eq!(disabled(root), ["mod_b"]);
+        assert_eq!(state.actions.len(), 1);
+        assert_eq!(state.actions[0].disabled, ["mod_b"]);
+
+        // then everything
+        boot(root, 2);
+        let state = boot(root, 2);
+        assert_eq!(state.boot_count, 1);
+        assert_eq!(disabled(root), ["mod_a", "mod_b", "mod_c"]);
+        assert_eq!(state.actions.len(), 2);
+        assert_eq!(state.actions[1].disabled, ["mod_a", "mod_c"]);
+    }
+
+    #[test]
+    fn reset_on_boot_completed() {
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path();
+        module(root, "mod_a");
+        let mut state = State {
+            recent_updates: vec!["mod_a".to_string()],
+            ..Default::default()
+        };
+        state.count_boot(root, 1);
+        state.count_boot(root, 1);
+        assert!(state.escalated);
+        assert_eq!(disabled(root), ["mod_a"]);
+
+        state.boot_completed();
+        assert_eq!(state.boot_count, 0);
+        assert!(state.recent_updates.is_empty());
+        assert!(!state.escalated);
+        // the counter starts over
+        state.count_boot(root, 1);
+        assert_eq!(state.boot_count, 1);
+        assert_eq!(state.actions.len(), 1);
+    }
+
+    #[test]
+    fn disable_all_if_armed() {
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path();
+        module(root, "mod_a");
+        module(root, "mod_b");
+        // never escalates with threshold 0
+        let mut state = State::default();
+        for _ in 0..5 {
+            state.count_boot(root, 0);
+        }
+        assert!(disabled(root).is_empty());
+
+        state.armed = true;
+        state.count_boot(root, 0);
+        assert!(!state.armed);
+        assert_eq!(disabled(root), ["mod_a", "mod_b"]);
+        assert_eq!(state.actions[0].reason, "armed for this boot");
+    }
+
+    #[test]
+    fn keep_recent_actions() {
+        let mut state = State::default();
+        for i in 0..MAX_ACTIONS + 2 {
+            state.record(format!("action {i}"), Vec::new());
+        }
+        assert_eq!(state.actions.len(), MAX_ACTIONS);
+        assert_eq!(state.actions[0].reason, "action 2");
+    }
+}
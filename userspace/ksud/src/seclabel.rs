@@ -0,0 +1,116 @@
+use anyhow::{bail, Result};
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::sepolicy;
+use crate::utils::PreparedWrite;
+
+/// Domain of module scripts with `seclabel=default`, less privileged than ksud itself
+const MODULE_DOMAIN: &str = "u:r:ksu_module:s0";
+/// Logs of module scripts, opened by ksud and only written through the inherited fd
+pub const MODULE_LOG_CON: &str = "u:object_r:ksu_module_log:s0";
+
+// loaded before sepolicy.rule of modules, so that modules can extend it
+const MODULE_DOMAIN_RULES: &str = r#"
+type ksu_module domain
+typeattribute ksu_module mlstrustedsubject
+allow su ksu_module process { transition siginh rlimitinh noatsecure }
+allow ksu_module su process sigchld
+allow ksu_module su fd use
+allow ksu_module su fifo_file { read write getattr ioctl }
+type ksu_module_log file_type
+typeattribute ksu_module_log mlstrustedobject
+allow ksu_module ksu_module_log file { write append getattr }
+allow ksu_module ksu_module process { fork sigchld signal sigkill getpgid setpgid getsched }
+allow ksu_module ksu_module { fifo_file unix_stream_socket } *
+allow ksu_module ksu_module { dir file lnk_file } { search read open getattr }
+allow ksu_module { adb_data_file system_file } dir { search read open getattr }
+allow ksu_module { adb_data_file system_file } file { read open getattr execute execute_no_trans map entrypoint }
+allow ksu_module { adb_data_file system_file } lnk_file { read getattr }
+allow ksu_module { devpts null_device zero_device } chr_file { read write open getattr ioctl }
+allow ksu_module { proc sysfs } { dir file lnk_file } { search read open getattr }
+allow ksu_module property_socket sock_file write
+allow ksu_module init unix_stream_socket connectto
+allow ksu_module { default_prop system_prop } file { read open getattr map }
+"#;
+
+/// Define the default domain of module scripts
+pub fn define_module_domain() -> Result<()> {
+    sepolicy::live_patch(MODULE_DOMAIN_RULES)
+}
+
+/// SELinux context of scripts of a module, from `seclabel` in its module.prop.
+/// Scripts without it keep running in the domain of ksud.
+pub fn of_module(module_prop: &HashMap<String, String>) -> Result<Option<String>> {
+    let Some(value) = module_prop.get("seclabel").map(|v| v.trim()) else {
+        return Ok(None);
+    };
+    if value.is_empty() {
+        return Ok(None);
+    }
+    if value == "default" {
+        return Ok(Some(MODULE_DOMAIN.to_string()));
+    }
+
+    // user:role:type:level, the level may contain ':' itself
+    let parts: Vec<&str> = value.splitn(4, ':').collect();
+    let valid_type =
+        |t: &str| !t.is_empty() && t.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    match parts.as_slice() {
+        [user, "r", domain, level]
+            if !user.is_empty() && valid_type(domain) && level.starts_with('s') =>
+        {
+            Ok(Some(value.to_string()))
+        }
+        _ => bail!("invalid seclabel: {value}"),
+    }
+}
+
+/// Prepare running the exec of a child in <context>, run it between fork and exec.
+/// It can't be opened before fork, a process can only set its own attributes.
+pub fn exec_context(context: &str) -> std::io::Result<PreparedWrite> {
+    PreparedWrite::new(Path::new("/proc/self/attr/exec"), context.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn module_seclabels() {
+        let valid = [
+            ("default", Some(MODULE_DOMAIN)),
+            ("", None),
+            ("  ", None),
+            (" u:r:ksu_test:s0 ", Some("u:r:ksu_test:s0")),
+            // the level may contain ':' itself
+            (
+                "u:r:ksu_test:s0:c512,c768",
+                Some("u:r:ksu_test:s0:c512,c768"),
+            ),
+        ];
+        for (value, expected) in valid {
+            let module_prop = HashMap::from([("seclabel".to_string(), value.to_string())]);
+            let label = of_module(&module_prop).unwrap();
+            assert_eq!(label.as_deref(), expected, "{value:?}");
+        }
+        assert_eq!(of_module(&HashMap::new()).unwrap(), None);
+
+        let invalid = [
+            // no role
+            "u:ksu_test:s0",
+            // a file context
+            "u:object_r:ksu_test:s0",
+            "u:r:ksu-test:s0",
+            "u:r:ksu_test/..:s0",
+            "u:r::s0",
+            ":r:ksu_test:s0",
+            "u:r:ksu_test:c0",
+            "u:r:ksu_test",
+        ];
+        for value in invalid {
+            let module_prop = HashMap::from([("seclabel".to_string(), value.to_string())]);
+            assert!(of_module(&module_prop).is_err(), "{value:?}");
+        }
+    }
+}
@@ -13,6 +13,8 @@ use nom::{
 };
 use std::{ffi, path::Path, vec};
 
+use crate::ksucalls::KernelInterface;
+
 type SeObject<'a> = Vec<&'a str>;
 
 fn is_sepolicy_char(c: char) -> bool {
@@ -692,12 +694,17 @@ impl From<AtomicStatement> for FfiPolicy {
     }
 }
 
-#[cfg(any(target_os = "linux", target_os = "android"))]
-fn apply_one_rule<'a>(statement: &'a PolicyStatement<'a>, strict: bool) -> Result<()> {
+fn apply_one_rule<'a>(
+    kernel: &dyn KernelInterface,
+    statement: &'a PolicyStatement<'a>,
+    strict: bool,
+) -> Result<()> {
     let policies: Vec<AtomicStatement> = statement.try_into()?;
 
     for policy in policies {
-        if !rustix::process::ksu_set_policy(&FfiPolicy::from(policy)) {
+        let policy = FfiPolicy::from(policy);
+        let policy: *const FfiPolicy = &policy;
+        if !kernel.set_sepolicy(policy.cast()) {
             log::warn!("apply rule: {:?} failed.", statement);
             if strict {
                 return Err(anyhow::anyhow!("apply rule {:?} failed.", statement));
@@ -708,16 +715,15 @@ fn apply_one_rule<'a>(statement: &'a PolicyStatement<'a>, strict: bool) -> Resul
     Ok(())
 }
 
-#[cfg(not(any(target_os = "linux", target_os = "android")))]
-fn apply_one_rule<'a>(_statement: &'a PolicyStatement<'a>, _strict: bool) -> Result<()> {
-    unimplemented!()
+pub fn live_patch(policy: &str) -> Result<()> {
+    apply_rules(crate::ksucalls::kernel(), policy)
 }
 
-pub fn live_patch(policy: &str) -> Result<()> {
+fn apply_rules(kernel: &dyn KernelInterface, policy: &str) -> Result<()> {
     let result = parse_sepolicy(policy.trim(), false)?;
     for statement in result {
         println!("{statement:?}");
-        apply_one_rule(&statement, false)?;
+        apply_one_rule(kernel, &statement, false)?;
     }
     Ok(())
 }
@@ -737,3 +743,46 @@ pub fn check_rule(policy: &str) -> Result<()> {
     parse_sepolicy(policy.trim(), true)?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ksucalls::MockKernel;
+
+    #[test]
+    fn apply_expanded_rules() {
+        let kernel = MockKernel::new(0);
+        apply_rules(
+            &kernel,
+            "allow { ksu_a ksu_b } ksu_c file { read write }\n\
+             type ksu_d file_type\n\
+             typeattribute ksu_d mlstrustedobject",
+        )
+        .unwrap();
+        assert_eq!(
+            kernel.sepolicies(),
+            [
+                "1 1 ksu_a ksu_c file read",
+                "1 1 ksu_a ksu_c file write",
+                "1 1 ksu_b ksu_c file read",
+                "1 1 ksu_b ksu_c file write",
+                "4 0 ksu_d file_type",
+                "5 0 ksu_d mlstrustedobject",
+            ]
+        );
+    }
+
+    #[test]
+    fn apply_wildcards() {
+        let kernel = MockKernel::new(0);
+        apply_rules(&kernel, "allow ksu_a * file *").unwrap();
+        assert_eq!(kernel.sepolicies(), ["1 1 ksu_a file"]);
+    }
+
+    #[test]
+    fn skip_invalid_rules() {
+        let kernel = MockKernel::new(0);
+        apply_rules(&kernel, "allow ksu_a\nallow ksu_a ksu_b file read").unwrap();
+        assert_eq!(kernel.sepolicies(), ["1 1 ksu_a ksu_b file read"]);
+    }
+}
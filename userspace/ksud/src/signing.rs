@@ -0,0 +1,663 @@
+use anyhow::{ensure, Context, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use blake2::{digest::consts::U32, Blake2b, Blake2b512, Digest};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use log::warn;
+use serde::Serialize;
+use sha2::Sha256;
+use std::fs::{self, File};
+use std::io::{Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use zip::{write::SimpleFileOptions, CompressionMethod, ZipArchive, ZipWriter};
+
+use crate::config::{self, SignaturePolicy};
+use crate::{defs, output};
+
+// signature files in a module zip, they are not part of the manifest
+const SIGNATURE_DIR: &str = "META-INF/ksu/";
+const MANIFEST: &str = "META-INF/ksu/MANIFEST";
+const MANIFEST_SIGNATURE: &str = "META-INF/ksu/MANIFEST.minisig";
+
+// algorithms of minisign: plain ed25519, and ed25519 of the blake2b-512 hash which is its default
+const ALG_ED25519: [u8; 2] = *b"Ed";
+const ALG_HASHED_ED25519: [u8; 2] = *b"ED";
+const ALG_CHECKSUM: [u8; 2] = *b"B2";
+
+type KeyId = [u8; 8];
+
+// as minisign prints it
+fn key_id_hex(id: &KeyId) -> String {
+    format!("{:016X}", u64::from_le_bytes(*id))
+}
+
+fn decode_line(line: Option<&str>, what: &str) -> Result<Vec<u8>> {
+    let line = line.with_context(|| format!("{what} is truncated"))?;
+    STANDARD
+        .decode(line.trim())
+        .with_context(|| format!("invalid {what}"))
+}
+
+// minisign files start with an untrusted comment followed by the base64 payload
+fn decode_payload(text: &str, what: &str) -> Result<Vec<u8>> {
+    let mut lines = text.lines();
+    ensure!(
+        lines
+            .next()
+            .is_some_and(|line| line.starts_with("untrusted comment:")),
+        "invalid {what}"
+    );
+    decode_line(lines.next(), what)
+}
+
+/// A minisign public key
+struct PublicKey {
+    id: KeyId,
+    key: VerifyingKey,
+}
+
+impl PublicKey {
+    fn parse(text: &str) -> Result<Self> {
+        let data = decode_payload(text, "public key")?;
+        ensure!(
+            data.len() == 42 && data[..2] == ALG_ED25519,
+            "invalid public key"
+        );
+        Ok(PublicKey {
+            id: data[2..10].try_into()?,
+            key: VerifyingKey::from_bytes(data[10..].try_into()?)?,
+        })
+    }
+
+    fn load(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        Self::parse(&text).with_context(|| format!("Invalid key {}", path.display()))
+    }
+
+    fn to_text(&self) -> String {
+        let mut data = ALG_ED25519.to_vec();
+        data.extend(self.id);
+        data.extend(self.key.as_bytes());
+        format!(
+            "untrusted comment: minisign public key {}\n{}\n",
+            key_id_hex(&self.id),
+            STANDARD.encode(data)
+        )
+    }
+}
+
+/// An unencrypted minisign secret key, as created by `minisign -G -W`
+struct SecretKey {
+    id: KeyId,
+    key: SigningKey,
+}
+
+impl SecretKey {
+    fn checksum(id: &KeyId, key: &SigningKey) -> [u8; 32] {
+        let mut hasher = Blake2b::<U32>::new();
+        hasher.update(ALG_ED25519);
+        hasher.update(id);
+        hasher.update(key.to_keypair_bytes());
+        hasher.finalize().into()
+    }
+
+    // algorithm, kdf, checksum algorithm, kdf salt and limits, key id, key pair, checksum
+    fn parse(text: &str) -> Result<Self> {
+        let data = decode_payload(text, "secret key")?;
+        ensure!(
+            data.len() == 158 && data[..2] == ALG_ED25519 && data[4..6] == ALG_CHECKSUM,
+            "invalid secret key"
+        );
+        ensure!(
+            data[2..4] == [0, 0],
+            "encrypted secret keys are not supported, create one with `ksud module keygen` or `minisign -G -W`"
+        );
+        let id: KeyId = data[54..62].try_into()?;
+        let key = SigningKey::from_keypair_bytes(data[62..126].try_into()?)
+            .context("invalid secret key")?;
+        ensure!(
+            data[126..] == Self::checksum(&id, &key),
+            "checksum of secret key mismatch"
+        );
+        Ok(SecretKey { id, key })
+    }
+
+    fn generate() -> Result<Self> {
+        let mut random = [0u8; 40];
+        File::open("/dev/urandom")?.read_exact(&mut random)?;
+        Ok(SecretKey {
+            id: random[..8].try_into()?,
+            key: SigningKey::from_bytes(random[8..].try_into()?),
+        })
+    }
+
+    fn public_key(&self) -> PublicKey {
+        PublicKey {
+            id: self.id,
+            key: self.key.verifying_key(),
+        }
+    }
+
+    fn to_text(&self) -> String {
+        let mut data = Vec::with_capacity(158);
+        data.extend(ALG_ED25519);
+        // no kdf, so salt and limits are unused
+        data.extend([0, 0]);
+        data.extend(ALG_CHECKSUM);
+        data.extend([0; 48]);
+        data.extend(self.id);
+        data.extend(self.key.to_keypair_bytes());
+        data.extend(Self::checksum(&self.id, &self.key));
+        format!(
+            "untrusted comment: minisign secret key {}\n{}\n",
+            key_id_hex(&self.id),
+            STANDARD.encode(data)
+        )
+    }
+}
+
+/// A minisign signature, the trusted comment is signed too
+struct Minisig {
+    algorithm: [u8; 2],
+    id: KeyId,
+    signature: Signature,
+    trusted_comment: String,
+    global_signature: Signature,
+}
+
+impl Minisig {
+    fn signed_data(algorithm: [u8; 2], message: &[u8]) -> Vec<u8> {
+        if algorithm == ALG_HASHED_ED25519 {
+            Blake2b512::digest(message).to_vec()
+        } else {
+            message.to_vec()
+        }
+    }
+
+    fn global_data(signature: &Signature, trusted_comment: &str) -> Vec<u8> {
+        let mut data = signature.to_bytes().to_vec();
+        data.extend(trusted_comment.as_bytes());
+        data
+    }
+
+    fn parse(text: &str) -> Result<Self> {
+        let data = decode_payload(text, "signature")?;
+        ensure!(
+            data.len() == 74 && (data[..2] == ALG_ED25519 || data[..2] == ALG_HASHED_ED25519),
+            "invalid signature"
+        );
+        let mut lines = text.lines().skip(2);
+        let trusted_comment = lines
+            .next()
+            .and_then(|line| line.strip_prefix("trusted comment: "))
+            .context("signature has no trusted comment")?
+            .to_string();
+        let global_signature = decode_line(lines.next(), "signature")?;
+        Ok(Minisig {
+            algorithm: data[..2].try_into()?,
+            id: data[2..10].try_into()?,
+            signature: Signature::from_bytes(data[10..].try_into()?),
+            trusted_comment,
+            global_signature: Signature::from_bytes(global_signature.as_slice().try_into()?),
+        })
+    }
+
+    fn sign(key: &SecretKey, message: &[u8], trusted_comment: String) -> Self {
+        let signature = key
+            .key
+            .sign(&Self::signed_data(ALG_HASHED_ED25519, message));
+        let global_signature = key
+            .key
+            .sign(&Self::global_data(&signature, &trusted_comment));
+        Minisig {
+            algorithm: ALG_HASHED_ED25519,
+            id: key.id,
+            signature,
+            trusted_comment,
+            global_signature,
+        }
+    }
+
+    fn verify(&self, key: &PublicKey, message: &[u8]) -> Result<()> {
+        key.key
+            .verify(&Self::signed_data(self.algorithm, message), &self.signature)
+            .context("signature mismatch")?;
+        key.key
+            .verify(
+                &Self::global_data(&self.signature, &self.trusted_comment),
+                &self.global_signature,
+            )
+            .context("signature of trusted comment mismatch")?;
+        Ok(())
+    }
+
+    fn to_text(&self) -> String {
+        let mut data = self.algorithm.to_vec();
+        data.extend(self.id);
+        data.extend(self.signature.to_bytes());
+        format!(
+            "untrusted comment: signature from minisign secret key {}\n{}\ntrusted comment: {}\n{}\n",
+            key_id_hex(&self.id),
+            STANDARD.encode(data),
+            self.trusted_comment,
+            STANDARD.encode(self.global_signature.to_bytes())
+        )
+    }
+}
+
+const LOCAL_HEADER: [u8; 4] = *b"PK\x03\x04";
+const DATA_DESCRIPTOR: [u8; 4] = *b"PK\x07\x08";
+// sizes and crc follow the data
+const FLAG_DATA_DESCRIPTOR: u16 = 1 << 3;
+
+fn le16(data: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([data[at], data[at + 1]])
+}
+
+fn le32(data: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(data[at..at + 4].try_into().unwrap())
+}
+
+// the manifest covers the central directory, but a streaming unzip reads the local headers.
+// they must describe the same entries, one after another with nothing hidden in between.
+fn check_local_headers<R: Read + Seek, S: Read + Seek>(
+    archive: &mut ZipArchive<R>,
+    raw: &mut S,
+) -> Result<()> {
+    let mut entries = Vec::with_capacity(archive.len());
+    for index in 0..archive.len() {
+        let file = archive.by_index_raw(index)?;
+        entries.push((
+            file.header_start(),
+            file.data_start(),
+            file.name_raw().to_vec(),
+            file.crc32(),
+            file.compressed_size(),
+            file.size(),
+        ));
+    }
+    entries.sort();
+
+    let mut expected = archive.offset();
+    for (header_start, data_start, name, crc, compressed_size, size) in entries {
+        let display = String::from_utf8_lossy(&name);
+        ensure!(
+            header_start == expected,
+            "unexpected data before the local header of {display}"
+        );
+        let mut header = vec![0u8; 30 + name.len()];
+        raw.seek(std::io::SeekFrom::Start(header_start))?;
+        raw.read_exact(&mut header)?;
+        ensure!(
+            header[..4] == LOCAL_HEADER && header[30..] == name,
+            "local header of {display} doesn't match the central directory"
+        );
+        let data_end = data_start + compressed_size;
+        expected = data_end;
+        let (local_crc, local_compressed, local_size) =
+            if le16(&header, 6) & FLAG_DATA_DESCRIPTOR == 0 {
+                (le32(&header, 14), le32(&header, 18), le32(&header, 22))
+            } else {
+                let mut descriptor = [0u8; 16];
+                raw.seek(std::io::SeekFrom::Start(data_end))?;
+                raw.read_exact(&mut descriptor)?;
+                // the signature of the descriptor is optional
+                let at = if descriptor[..4] == DATA_DESCRIPTOR {
+                    4
+                } else {
+                    0
+                };
+                expected += at as u64 + 12;
+                (
+                    le32(&descriptor, at),
+                    le32(&descriptor, at + 4),
+                    le32(&descriptor, at + 8),
+                )
+            };
+        // zip64 sizes are only in the central directory
+        let matches = |local: u32, central: u64| local == u32::MAX || u64::from(local) == central;
+        ensure!(
+            local_crc == crc
+                && matches(local_compressed, compressed_size)
+                && matches(local_size, size),
+            "local header of {display} doesn't match the central directory"
+        );
+    }
+    ensure!(
+        expected == archive.central_directory_start(),
+        "unexpected data before the central directory"
+    );
+    Ok(())
+}
+
+// a zip whose local headers agree with its central directory
+fn open(zip: &Path) -> Result<ZipArchive<File>> {
+    let file = File::open(zip).with_context(|| format!("Failed to open {}", zip.display()))?;
+    let mut raw = file.try_clone()?;
+    let mut archive = ZipArchive::new(file)?;
+    check_local_headers(&mut archive, &mut raw)?;
+    Ok(archive)
+}
+
+// "<sha256> <mode> <name>" of every entry but the signature files, sorted by name
+fn manifest<R: Read + Seek>(archive: &mut ZipArchive<R>) -> Result<String> {
+    let mut entries = Vec::new();
+    for index in 0..archive.len() {
+        let mut file = archive.by_index(index)?;
+        let name = file.name().to_string();
+        if name.starts_with(SIGNATURE_DIR) {
+            continue;
+        }
+        ensure!(!name.contains('\n'), "invalid entry name {name:?}");
+        let mut hasher = Sha256::new();
+        std::io::copy(&mut file, &mut hasher)?;
+        let mode = file.unix_mode().unwrap_or(0);
+        entries.push((name, format!("{:x} {mode:o}", hasher.finalize())));
+    }
+    entries.sort();
+    Ok(entries
+        .into_iter()
+        .map(|(name, hash)| format!("{hash} {name}\n"))
+        .collect())
+}
+
+fn read_entry<R: Read + Seek>(archive: &mut ZipArchive<R>, name: &str) -> Result<Vec<u8>> {
+    let index = archive
+        .index_for_name(name)
+        .context("module is not signed")?;
+    let mut file = archive.by_index(index)?;
+    let mut content = Vec::new();
+    file.read_to_end(&mut content)?;
+    Ok(content)
+}
+
+fn trusted_keys() -> Vec<PublicKey> {
+    let Ok(dir) = fs::read_dir(defs::resolve(defs::TRUST_DIR)) else {
+        return Vec::new();
+    };
+    dir.flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "pub"))
+        .filter_map(|path| PublicKey::load(&path).inspect_err(|e| warn!("{e:#}")).ok())
+        .collect()
+}
+
+/// Who signed a module zip
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SignedBy {
+    key_id: String,
+    trusted_comment: String,
+}
+
+fn verify_archive<R: Read + Seek>(
+    archive: &mut ZipArchive<R>,
+    keys: &[PublicKey],
+) -> Result<SignedBy> {
+    let signed_manifest = read_entry(archive, MANIFEST)?;
+    let signature = read_entry(archive, MANIFEST_SIGNATURE)?;
+    let signature = Minisig::parse(&String::from_utf8_lossy(&signature))?;
+    let key = keys
+        .iter()
+        .find(|key| key.id == signature.id)
+        .with_context(|| {
+            format!(
+                "module is signed by key {}, which is not trusted",
+                key_id_hex(&signature.id)
+            )
+        })?;
+    signature.verify(key, &signed_manifest)?;
+    ensure!(
+        manifest(archive)?.as_bytes() == signed_manifest,
+        "files of the module don't match its signed manifest"
+    );
+    Ok(SignedBy {
+        key_id: key_id_hex(&key.id),
+        trusted_comment: signature.trusted_comment,
+    })
+}
+
+/// Check the signature of a module zip as `modules.signature_policy` requires
+pub fn check_policy(zip: &Path) -> Result<()> {
+    let policy = config::get().modules.signature_policy;
+    if policy == SignaturePolicy::Off {
+        return Ok(());
+    }
+    match open(zip).and_then(|mut archive| verify_archive(&mut archive, &trusted_keys())) {
+        Ok(signer) => output::progress(format!("Signed by key {}", signer.key_id)),
+        Err(e) if policy == SignaturePolicy::Warn => {
+            output::progress(format!("Warning: {e:#}"));
+        }
+        Err(e) => return Err(e.context("Only modules signed by a trusted key can be installed")),
+    }
+    Ok(())
+}
+
+/// Create an unencrypted minisign key pair, <path> and <path>.pub
+pub fn keygen(path: &Path) -> Result<()> {
+    let public_path = PathBuf::from(format!("{}.pub", path.display()));
+    let key = SecretKey::generate()?;
+
+    let mut options = File::options();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options
+        .open(path)
+        .and_then(|mut file| file.write_all(key.to_text().as_bytes()))
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+    fs::write(&public_path, key.public_key().to_text())
+        .with_context(|| format!("Failed to write {}", public_path.display()))?;
+
+    output::progress(format!("Key {} created", key_id_hex(&key.id)));
+    output::progress(format!(
+        "Copy {} to {} of devices to trust it",
+        public_path.display(),
+        defs::TRUST_DIR
+    ));
+    Ok(())
+}
+
+/// Sign a module zip with <key>, the signature is stored in the zip under META-INF
+pub fn sign(zip: &Path, key: &Path, out: Option<&Path>) -> Result<()> {
+    let key = SecretKey::parse(
+        &fs::read_to_string(key).with_context(|| format!("Failed to read {}", key.display()))?,
+    )?;
+    let mut archive = open(zip)?;
+    // signature files must not be covered by another signature
+    ensure!(
+        !archive
+            .file_names()
+            .any(|name| name.starts_with(SIGNATURE_DIR)),
+        "{} is already signed",
+        zip.display()
+    );
+    let manifest = manifest(&mut archive)?;
+    let trusted_comment = format!(
+        "timestamp:{}\tfile:MANIFEST\thashed",
+        chrono::Utc::now().timestamp()
+    );
+    let signature = Minisig::sign(&key, manifest.as_bytes(), trusted_comment);
+
+    // written next to the output and renamed, so that signing in place is safe
+    let out = out.unwrap_or(zip);
+    let dir = out
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let mut tmp = tempfile::NamedTempFile::new_in(dir)?;
+    // entries are copied as they are, so that their data and modes stay the same.
+    // appending would leave the old central directory between the entries
+    let mut writer = ZipWriter::new(tmp.as_file_mut());
+    for index in 0..archive.len() {
+        writer.raw_copy_file(archive.by_index_raw(index)?)?;
+    }
+    writer.set_raw_comment(archive.comment().into());
+    let options = SimpleFileOptions::default()
+        .compression_method(CompressionMethod::Stored)
+        .unix_permissions(0o644);
+    writer.start_file(MANIFEST, options)?;
+    writer.write_all(manifest.as_bytes())?;
+    writer.start_file(MANIFEST_SIGNATURE, options)?;
+    writer.write_all(signature.to_text().as_bytes())?;
+    writer.finish()?;
+    tmp.persist(out)
+        .with_context(|| format!("Failed to write {}", out.display()))?;
+
+    output::progress(format!(
+        "Signed {} with key {}",
+        out.display(),
+        key_id_hex(&key.id)
+    ));
+    Ok(())
+}
+
+/// Verify the signature of a module zip against <key>, or the trusted keys
+pub fn verify(zip: &Path, key: Option<&Path>) -> Result<()> {
+    let keys = match key {
+        Some(key) => vec![PublicKey::load(key)?],
+        None => trusted_keys(),
+    };
+    ensure!(
+        !keys.is_empty(),
+        "No trusted keys in {}",
+        defs::resolve(defs::TRUST_DIR).display()
+    );
+    let mut archive = open(zip)?;
+    let signer = verify_archive(&mut archive, &keys)?;
+    output::result(&signer, |signer| {
+        output::progress(format!(
+            "Signature is valid, signed by key {} ({})",
+            signer.key_id, signer.trusted_comment
+        ));
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // produced by an independent implementation of the minisign format
+    const FIXTURE_PUBLIC_KEY: &str = "untrusted comment: minisign public key E6B93CDC29C6BE8C
+RWSMvsYp3Dy55tTjlFAhyvxFNNT/WX/Y7U2tknQME4IpQhlpERhWG/Ls
+";
+    const FIXTURE_SIGNATURE: &str =
+        "untrusted comment: signature from minisign secret key E6B93CDC29C6BE8C
+RUSMvsYp3Dy55sUMmMEYIst+cYwO62tEmgY97U9oNTzoTupcL62swYxHIwAYM67qOhfIqjk3MKBQVlxX7JGrSuQ76EXGyDCFww8=
+trusted comment: timestamp:1760000000\tfile:MANIFEST\thashed
+RsjsbJfoN5+gWdeLiTgIzYWV3vXW6K5K8bpZyhKbQgEIOiPlbbEEzqu0fbMGMBSJfzWXSMjQWiIfgPS3ceR0Bg==
+";
+    const FIXTURE_MESSAGE: &[u8] = b"0123abcd 100644 module.prop\n";
+
+    fn write_module(path: &Path, prop: &str) {
+        let mut zip = ZipWriter::new(File::create(path).unwrap());
+        let options = SimpleFileOptions::default().unix_permissions(0o644);
+        zip.start_file("module.prop", options).unwrap();
+        zip.write_all(prop.as_bytes()).unwrap();
+        zip.start_file("service.sh", options.unix_permissions(0o755))
+            .unwrap();
+        zip.write_all(b"echo hello\n").unwrap();
+        zip.finish().unwrap();
+    }
+
+    // a signed module and the public key it's signed with
+    fn signed_module(dir: &Path) -> (PathBuf, PathBuf) {
+        let key = dir.join("key");
+        keygen(&key).unwrap();
+        let zip = dir.join("module.zip");
+        write_module(&zip, "id=demo\n");
+        sign(&zip, &key, None).unwrap();
+        (zip, dir.join("key.pub"))
+    }
+
+    #[test]
+    fn minisign_fixture() {
+        let key = PublicKey::parse(FIXTURE_PUBLIC_KEY).unwrap();
+        assert_eq!(key_id_hex(&key.id), "E6B93CDC29C6BE8C");
+        assert_eq!(key.to_text(), FIXTURE_PUBLIC_KEY);
+        let signature = Minisig::parse(FIXTURE_SIGNATURE).unwrap();
+        assert_eq!(signature.to_text(), FIXTURE_SIGNATURE);
+        signature.verify(&key, FIXTURE_MESSAGE).unwrap();
+        assert!(signature
+            .verify(&key, b"0123abcd 100755 module.prop\n")
+            .is_err());
+
+        let mut comment = Minisig::parse(FIXTURE_SIGNATURE).unwrap();
+        comment.trusted_comment.push_str("\tforged");
+        assert!(comment.verify(&key, FIXTURE_MESSAGE).is_err());
+    }
+
+    #[test]
+    fn key_round_trip() {
+        let key = SecretKey::generate().unwrap();
+        let parsed = SecretKey::parse(&key.to_text()).unwrap();
+        assert_eq!(parsed.id, key.id);
+        assert_eq!(parsed.key.to_bytes(), key.key.to_bytes());
+        let public = PublicKey::parse(&key.public_key().to_text()).unwrap();
+        let signature = Minisig::sign(&key, b"message", "comment".to_string());
+        let signature = Minisig::parse(&signature.to_text()).unwrap();
+        signature.verify(&public, b"message").unwrap();
+
+        let mut corrupted = key.to_text().into_bytes();
+        corrupted[60] ^= 1;
+        assert!(SecretKey::parse(&String::from_utf8_lossy(&corrupted)).is_err());
+    }
+
+    #[test]
+    fn sign_and_verify() {
+        let dir = tempfile::tempdir().unwrap();
+        let (zip, public) = signed_module(dir.path());
+        verify(&zip, Some(&public)).unwrap();
+        // signing twice would leave the first signature in place
+        assert!(sign(&zip, &dir.path().join("key"), None).is_err());
+
+        let other = dir.path().join("other");
+        keygen(&other).unwrap();
+        let err = verify(&zip, Some(&dir.path().join("other.pub"))).unwrap_err();
+        assert!(err.to_string().contains("which is not trusted"), "{err}");
+    }
+
+    #[test]
+    fn reject_changed_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let (zip, public) = signed_module(dir.path());
+
+        // the signature files of the signed zip with another module.prop
+        let mut signed = ZipArchive::new(File::open(&zip).unwrap()).unwrap();
+        let tampered = dir.path().join("tampered.zip");
+        let mut writer = ZipWriter::new(File::create(&tampered).unwrap());
+        for index in 0..signed.len() {
+            let file = signed.by_index_raw(index).unwrap();
+            if file.name() == "module.prop" {
+                let options = SimpleFileOptions::default().unix_permissions(0o644);
+                writer.start_file("module.prop", options).unwrap();
+                writer.write_all(b"id=evil\n").unwrap();
+            } else {
+                writer.raw_copy_file(file).unwrap();
+            }
+        }
+        writer.finish().unwrap();
+
+        let err = verify(&tampered, Some(&public)).unwrap_err();
+        assert!(err.to_string().contains("don't match"), "{err}");
+    }
+
+    #[test]
+    fn reject_mismatched_local_headers() {
+        let dir = tempfile::tempdir().unwrap();
+        let (zip, public) = signed_module(dir.path());
+        let mut data = fs::read(&zip).unwrap();
+        // a streaming unzip would extract "Module.prop" instead
+        let header = data
+            .windows(4)
+            .position(|window| window == LOCAL_HEADER)
+            .unwrap();
+        assert_eq!(&data[header + 30..header + 41], b"module.prop");
+        data[header + 30] = b'M';
+        fs::write(&zip, data).unwrap();
+
+        let err = verify(&zip, Some(&public)).unwrap_err();
+        assert!(err.to_string().contains("local header"), "{err}");
+    }
+}
@@ -252,10 +252,11 @@ pub fn root_shell() -> Result<()> {
     // escape from the current cgroup and become session leader
     // WARNING!!! This cause some root shell hang forever!
     // command = command.process_group(0);
+    let cgroups = utils::CgroupSwitch::prepare();
     command = unsafe {
         command.pre_exec(move || {
             umask(0o22);
-            utils::switch_cgroups();
+            cgroups.run();
 
             // switch to global mount namespace
             #[cfg(any(target_os = "linux", target_os = "android"))]
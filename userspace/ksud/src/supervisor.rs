@@ -0,0 +1,374 @@
+use anyhow::{Context, Result};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::{cgroup, defs, module, output, utils};
+
+// at most this many service scripts are starting at the same time
+const MAX_CONCURRENT_STARTS: usize = 4;
+// a script counts as starting until it exits or has run this long
+const START_GRACE: Duration = Duration::from_secs(5);
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+// a script which ran this long before failing is restarted without backoff
+const STABLE_RUN: Duration = Duration::from_secs(60);
+
+/// `service.restart` in module.prop
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RestartPolicy {
+    No,
+    OnFailure,
+    Always,
+}
+
+impl RestartPolicy {
+    pub fn of(module_prop: &HashMap<String, String>) -> Self {
+        match module_prop.get("service.restart").map(|v| v.trim()) {
+            None | Some("") | Some("no") => RestartPolicy::No,
+            Some("on-failure") => RestartPolicy::OnFailure,
+            Some("always") => RestartPolicy::Always,
+            Some(other) => {
+                warn!("unknown service.restart: {other}, not supervised");
+                RestartPolicy::No
+            }
+        }
+    }
+}
+
+/// State of a supervised service.sh, shared with `ksud module services`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ServiceState {
+    pub pid: Option<u32>,
+    pub state: String,
+    pub restarts: u32,
+    pub last_exit: Option<String>,
+}
+
+fn state_path() -> PathBuf {
+    defs::resolve(defs::SERVICE_STATE_PATH)
+}
+
+fn load_states() -> Result<BTreeMap<String, ServiceState>> {
+    let path = state_path();
+    let content = std::fs::read_to_string(&path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    Ok(serde_json::from_str(&content)?)
+}
+
+fn describe(status: ExitStatus) -> String {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        if let Some(signal) = status.signal() {
+            return format!("killed by signal {signal}");
+        }
+    }
+    match status.code() {
+        Some(code) => format!("exit code {code}"),
+        None => "unknown".to_string(),
+    }
+}
+
+// leftovers of a dead service would be started twice when it is restarted
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn kill_leftovers(pid: u32) {
+    use rustix::process::{kill_process_group, Pid, Signal};
+    if let Some(pid) = Pid::from_raw(pid as i32) {
+        kill_process_group(pid, Signal::Kill).ok();
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn kill_leftovers(_pid: u32) {
+    unimplemented!()
+}
+
+struct Supervisor {
+    states: Mutex<BTreeMap<String, ServiceState>>,
+    starting: Mutex<usize>,
+    start_done: Condvar,
+}
+
+impl Supervisor {
+    fn update(&self, id: &str, f: impl FnOnce(&mut ServiceState)) {
+        let mut states = self.states.lock().unwrap();
+        f(states.entry(id.to_string()).or_default());
+        let result = serde_json::to_string_pretty(&*states)
+            .map_err(anyhow::Error::from)
+            .and_then(|content| Ok(std::fs::write(state_path(), content)?));
+        if let Err(e) = result {
+            warn!("Failed to save service state: {e:#}");
+        }
+    }
+
+    fn begin_start(&self) {
+        let mut starting = self.starting.lock().unwrap();
+        while *starting >= MAX_CONCURRENT_STARTS {
+            starting = self.start_done.wait(starting).unwrap();
+        }
+        *starting += 1;
+    }
+
+    fn end_start(&self) {
+        *self.starting.lock().unwrap() -= 1;
+        self.start_done.notify_one();
+    }
+
+    // the start slot is released once the script has run for START_GRACE
+    fn wait(&self, child: &mut Child) -> std::io::Result<ExitStatus> {
+        let started = Instant::now();
+        let status = loop {
+            match child.try_wait() {
+                Ok(None) if started.elapsed() < START_GRACE => {
+                    std::thread::sleep(Duration::from_millis(100))
+                }
+                result => break result,
+            }
+        };
+        self.end_start();
+        match status? {
+            Some(status) => Ok(status),
+            None => child.wait(),
+        }
+    }
+
+    // a module disabled or removed after boot isn't restarted anymore
+    fn stopped(&self, id: &str, module: &Path) -> bool {
+        if module::is_active(module) {
+            return false;
+        }
+        info!("{id} is disabled or removed, stop supervising it");
+        self.update(id, |s| s.state = "stopped".to_string());
+        true
+    }
+
+    fn supervise(&self, id: &str, module: &Path, policy: RestartPolicy) {
+        let mut backoff = MIN_BACKOFF;
+        loop {
+            self.begin_start();
+            let started = Instant::now();
+            let result = match module::spawn_service_script(module) {
+                Ok(mut child) => {
+                    let pid = child.id();
+                    self.update(id, |s| {
+                        s.pid = Some(pid);
+                        s.state = "running".to_string();
+                    });
+                    self.wait(&mut child)
+                        .map(|status| (pid, status))
+                        .map_err(anyhow::Error::from)
+                }
+                Err(e) => {
+                    self.end_start();
+                    Err(e)
+                }
+            };
+
+            let (pid, failed) = match result {
+                Ok((pid, status)) => {
+                    info!("service of {id} exited: {}", describe(status));
+                    self.update(id, |s| {
+                        s.pid = None;
+                        s.last_exit = Some(describe(status));
+                    });
+                    (Some(pid), !status.success())
+                }
+                Err(e) => {
+                    warn!("Failed to run service of {id}: {e:#}");
+                    self.update(id, |s| {
+                        s.pid = None;
+                        s.last_exit = Some(format!("{e:#}"));
+                    });
+                    (None, true)
+                }
+            };
+
+            // what a script leaves running is only killed when it is restarted
+            if !failed && policy == RestartPolicy::OnFailure {
+                self.update(id, |s| s.state = "exited".to_string());
+                return;
+            }
+            if self.stopped(id, module) {
+                return;
+            }
+            if let Some(pid) = pid {
+                kill_leftovers(pid);
+            }
+
+            if started.elapsed() >= STABLE_RUN {
+                backoff = MIN_BACKOFF;
+            }
+            self.update(id, |s| s.state = "backoff".to_string());
+            std::thread::sleep(backoff);
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+            // the module may be disabled during the backoff
+            if self.stopped(id, module) {
+                return;
+            }
+            self.update(id, |s| s.restarts += 1);
+        }
+    }
+}
+
+// active modules whose service.sh should be supervised
+fn supervised_modules() -> Result<Vec<(String, PathBuf, RestartPolicy)>> {
+    let mut modules = Vec::new();
+    for module in module::sorted_active_modules()? {
+        if !module.join("service.sh").exists() {
+            continue;
+        }
+        let Ok(module_prop) = module::read_module_prop(&module) else {
+            continue;
+        };
+        let policy = RestartPolicy::of(&module_prop);
+        if policy == RestartPolicy::No {
+            continue;
+        }
+        let Some(id) = module.file_name() else {
+            continue;
+        };
+        modules.push((id.to_string_lossy().to_string(), module, policy));
+    }
+    Ok(modules)
+}
+
+/// Start the supervisor of service.sh in background, ksud of the service stage exits before it.
+pub fn start() -> Result<()> {
+    if supervised_modules()?.is_empty() {
+        return Ok(());
+    }
+
+    let exe = std::env::current_exe()?;
+    let mut command = Command::new(exe);
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        command.process_group(0);
+        let cgroups = utils::CgroupSwitch::prepare();
+        unsafe {
+            command.pre_exec(move || {
+                cgroups.run();
+                Ok(())
+            });
+        }
+    }
+    if defs::has_custom_root() {
+        command.env("KSU_ROOT", defs::root());
+    }
+    command
+        .arg("service-supervisor")
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .context("Failed to start service supervisor")?;
+    Ok(())
+}
+
+/// Run service.sh of modules with `service.restart`, restarting them with backoff.
+pub fn run() -> Result<()> {
+    let modules = supervised_modules()?;
+    if let Some(parent) = state_path().parent() {
+        utils::ensure_dir_exists(parent)?;
+    }
+    let supervisor = Arc::new(Supervisor {
+        states: Mutex::new(BTreeMap::new()),
+        starting: Mutex::new(0),
+        start_done: Condvar::new(),
+    });
+
+    let handles: Vec<_> = modules
+        .into_iter()
+        .map(|(id, module, policy)| {
+            supervisor.update(&id, |s| s.state = "starting".to_string());
+            let supervisor = supervisor.clone();
+            std::thread::spawn(move || supervisor.supervise(&id, &module, policy))
+        })
+        .collect();
+    for handle in handles {
+        handle.join().ok();
+    }
+    Ok(())
+}
+
+fn format_usage(usage: Option<&cgroup::Usage>) -> (String, String, String) {
+    let Some(usage) = usage else {
+        return ("-".to_string(), "-".to_string(), "-".to_string());
+    };
+    (
+        usage
+            .cpu
+            .map_or_else(|| "-".to_string(), |us| format!("{:.1}s", us as f64 / 1e6)),
+        usage.memory.map_or_else(
+            || "-".to_string(),
+            |bytes| humansize::format_size(bytes, humansize::BINARY),
+        ),
+        usage
+            .pids
+            .map_or_else(|| "-".to_string(), |pids| pids.to_string()),
+    )
+}
+
+/// List supervised services of this boot, and the resource usage of module cgroups
+pub fn list_services() -> Result<()> {
+    #[derive(Serialize)]
+    struct Service {
+        id: String,
+        #[serde(flatten)]
+        state: ServiceState,
+        usage: Option<cgroup::Usage>,
+    }
+
+    let mut states = load_states().unwrap_or_default();
+    // scripts of other modules run in their cgroups too
+    for id in cgroup::modules() {
+        states.entry(id).or_default();
+    }
+    let services: Vec<Service> = states
+        .into_iter()
+        .map(|(id, mut state)| {
+            // the supervisor itself may be killed
+            let alive = state
+                .pid
+                .is_some_and(|pid| Path::new(&format!("/proc/{pid}")).exists());
+            if state.pid.is_some() && !alive {
+                state.state = "dead".to_string();
+            }
+            let usage = cgroup::usage(&id);
+            Service { id, state, usage }
+        })
+        .collect();
+
+    output::result(&services, |services| {
+        println!(
+            "{:<32}{:>8}  {:<10}{:>9}{:>10}{:>12}{:>6}  LAST EXIT",
+            "ID", "PID", "STATE", "RESTARTS", "CPU", "MEM", "PIDS"
+        );
+        for Service { id, state, usage } in services {
+            let (cpu, memory, pids) = format_usage(usage.as_ref());
+            println!(
+                "{:<32}{:>8}  {:<10}{:>9}{:>10}{:>12}{:>6}  {}",
+                id,
+                state
+                    .pid
+                    .map_or_else(|| "-".to_string(), |pid| pid.to_string()),
+                if state.state.is_empty() {
+                    "-"
+                } else {
+                    state.state.as_str()
+                },
+                state.restarts,
+                cpu,
+                memory,
+                pids,
+                state.last_exit.as_deref().unwrap_or("-")
+            );
+        }
+        Ok(())
+    })
+}
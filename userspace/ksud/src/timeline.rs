@@ -0,0 +1,316 @@
+use anyhow::{Context, Result};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use crate::{defs, output, utils};
+
+/// A step of the boot, recorded by each ksud invocation of the boot stages
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Step {
+    pub stage: String,
+    pub name: String,
+    /// milliseconds since boot when the step started
+    pub start: u64,
+    /// milliseconds, unknown for background scripts until they finish
+    pub duration: Option<u64>,
+    pub result: String,
+    /// log of a script, where the result of background scripts comes from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub log: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Timeline {
+    pub steps: Vec<Step>,
+}
+
+const RESULT_OK: &str = "ok";
+const RESULT_RUNNING: &str = "running";
+
+fn timeline_path() -> PathBuf {
+    defs::resolve(defs::BOOT_TIMELINE_PATH)
+}
+
+fn previous_timeline_path() -> PathBuf {
+    timeline_path().with_extension("old.json")
+}
+
+// steps of this process, written by flush
+static PENDING: Mutex<Vec<Step>> = Mutex::new(Vec::new());
+
+/// Exclusive access to the timeline files, the boot stages run in separate ksud processes
+struct Lock {
+    _file: File,
+}
+
+impl Lock {
+    fn acquire() -> Result<Self> {
+        let path = timeline_path().with_extension("lock");
+        if let Some(parent) = path.parent() {
+            utils::ensure_dir_exists(parent)?;
+        }
+        let file =
+            File::create(&path).with_context(|| format!("Failed to create {}", path.display()))?;
+        #[cfg(unix)]
+        {
+            use std::os::fd::AsRawFd;
+            // released when the file is closed
+            if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+                return Err(std::io::Error::last_os_error())
+                    .with_context(|| format!("Failed to lock {}", path.display()));
+            }
+        }
+        Ok(Lock { _file: file })
+    }
+}
+
+// milliseconds since boot, including deep sleep
+fn uptime() -> u64 {
+    std::fs::read_to_string("/proc/uptime")
+        .ok()
+        .and_then(|s| s.split_whitespace().next()?.parse::<f64>().ok())
+        .map_or(0, |secs| (secs * 1000.0) as u64)
+}
+
+// the last line of a script log is written by the wrapper when the script ends,
+// see module::SCRIPT_LOG_WRAPPER
+fn script_result(log: &Path) -> Option<(u64, String)> {
+    let content = std::fs::read_to_string(log).ok()?;
+    let last = content.lines().last()?;
+    if let Some(rest) = last.strip_prefix("# exit code ") {
+        let (code, secs) = rest.split_once(" after ")?;
+        let secs = secs.strip_suffix('s')?.parse::<u64>().ok()?;
+        let result = if code == "0" {
+            RESULT_OK.to_string()
+        } else {
+            format!("exit code {code}")
+        };
+        return Some((secs * 1000, result));
+    }
+    let secs = last
+        .strip_prefix("# killed after timeout of ")?
+        .strip_suffix('s')?
+        .parse::<u64>()
+        .ok()?;
+    Some((secs * 1000, format!("killed after {secs}s")))
+}
+
+impl Timeline {
+    fn load(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        serde_json::from_str(&content).with_context(|| format!("Invalid {}", path.display()))
+    }
+
+    // a timeline which can't be read is never replaced, the file is kept as it is
+    fn load_or_default(path: &Path) -> Result<Self> {
+        if path.exists() {
+            Self::load(path)
+        } else {
+            Ok(Self::default())
+        }
+    }
+
+    // written aside and renamed, readers never see a partial file
+    fn save(&self, path: &Path, _lock: &Lock) -> Result<()> {
+        let dir = path.parent().unwrap_or(Path::new("."));
+        utils::ensure_dir_exists(dir)?;
+        let mut tmp = tempfile::NamedTempFile::new_in(dir)?;
+        serde_json::to_writer_pretty(&mut tmp, self)?;
+        tmp.persist(path)
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+        Ok(())
+    }
+
+    // fill in the result of scripts which were still running when recorded
+    fn settle(&mut self) -> bool {
+        let mut changed = false;
+        for step in &mut self.steps {
+            if step.result != RESULT_RUNNING {
+                continue;
+            }
+            if let Some((duration, result)) = step.log.as_deref().and_then(script_result) {
+                step.duration = Some(duration);
+                step.result = result;
+                changed = true;
+            }
+        }
+        changed
+    }
+}
+
+fn record(step: Step) {
+    PENDING.lock().unwrap_or_else(|e| e.into_inner()).push(step);
+}
+
+/// Append the steps recorded by this process to the timeline, once it is done
+pub fn flush() {
+    let steps = std::mem::take(&mut *PENDING.lock().unwrap_or_else(|e| e.into_inner()));
+    if steps.is_empty() {
+        return;
+    }
+    let result = Lock::acquire().and_then(|lock| {
+        let path = timeline_path();
+        let mut timeline = Timeline::load_or_default(&path)?;
+        timeline.steps.extend(steps);
+        timeline.save(&path, &lock)
+    });
+    if let Err(e) = result {
+        warn!("Failed to save boot timeline: {e:#}");
+    }
+}
+
+/// Start the timeline of a new boot, the last one is kept for comparison
+pub fn begin_boot() {
+    let result = Lock::acquire().and_then(|lock| {
+        let path = timeline_path();
+        if !path.exists() {
+            return Ok(());
+        }
+        match Timeline::load(&path) {
+            Ok(mut timeline) => {
+                // logs of background scripts are rotated when they run again
+                timeline.settle();
+                timeline.save(&previous_timeline_path(), &lock)?;
+                std::fs::remove_file(&path)?;
+            }
+            // keep it for inspection, the new boot starts from scratch anyway
+            Err(e) => {
+                warn!("{e:#}, keep it as the last boot timeline");
+                std::fs::rename(&path, previous_timeline_path())?;
+            }
+        }
+        Ok(())
+    });
+    if let Err(e) = result {
+        warn!("Failed to keep last boot timeline: {e:#}");
+    }
+}
+
+/// Run <f> as step <name> of <stage> and record how long it took
+pub fn step<T>(stage: &str, name: &str, f: impl FnOnce() -> Result<T>) -> Result<T> {
+    let start = uptime();
+    let timer = Instant::now();
+    let result = f();
+    record(Step {
+        stage: stage.to_string(),
+        name: name.to_string(),
+        start,
+        duration: Some(timer.elapsed().as_millis() as u64),
+        result: match &result {
+            Ok(_) => RESULT_OK.to_string(),
+            Err(e) => format!("{e:#}"),
+        },
+        log: None,
+    });
+    result
+}
+
+/// Run a script of <stage> by <f>, its result comes from the exit code in <log> if any.
+pub fn script(
+    stage: &str,
+    name: &str,
+    log: Option<PathBuf>,
+    f: impl FnOnce() -> Result<()>,
+) -> Result<()> {
+    let start = uptime();
+    let timer = Instant::now();
+    let result = f();
+    let elapsed = timer.elapsed().as_millis() as u64;
+    // blocking scripts have ended here, background ones are settled later
+    let (duration, status) = match &result {
+        Err(e) => (Some(elapsed), format!("{e:#}")),
+        Ok(()) => match log.as_deref().and_then(script_result) {
+            Some((_, status)) => (Some(elapsed), status),
+            None => (None, RESULT_RUNNING.to_string()),
+        },
+    };
+    record(Step {
+        stage: stage.to_string(),
+        name: name.to_string(),
+        start,
+        duration,
+        result: status,
+        log,
+    });
+    result
+}
+
+fn format_ms(ms: Option<u64>) -> String {
+    ms.map_or_else(
+        || "-".to_string(),
+        |ms| format!("{:.3}s", ms as f64 / 1000.0),
+    )
+}
+
+fn format_diff(current: Option<u64>, previous: Option<u64>) -> String {
+    match (current, previous) {
+        (Some(current), Some(previous)) => {
+            let diff = current as i64 - previous as i64;
+            format!(
+                "{}{:.3}s",
+                if diff >= 0 { "+" } else { "-" },
+                diff.unsigned_abs() as f64 / 1000.0
+            )
+        }
+        _ => "-".to_string(),
+    }
+}
+
+/// Print the timeline of this boot, with the duration of each step in the last boot
+pub fn show() -> Result<()> {
+    let path = timeline_path();
+    let lock = Lock::acquire()?;
+    let mut current = Timeline::load(&path)?;
+    if current.settle() {
+        current.save(&path, &lock)?;
+    }
+    drop(lock);
+    let previous = Timeline::load(&previous_timeline_path()).ok();
+
+    let data = serde_json::json!({
+        "current": current,
+        "previous": previous,
+    });
+    output::result(&data, |_| {
+        print_table(&current, previous.as_ref());
+        Ok(())
+    })
+}
+
+fn print_table(current: &Timeline, previous: Option<&Timeline>) {
+    let previous: HashMap<(&str, &str), Option<u64>> = previous
+        .map(|t| {
+            t.steps
+                .iter()
+                .map(|s| ((s.stage.as_str(), s.name.as_str()), s.duration))
+                .collect()
+        })
+        .unwrap_or_default();
+
+    println!(
+        "{:>9}  {:<16}{:<40}{:>10}{:>10}{:>10}  RESULT",
+        "START", "STAGE", "STEP", "TIME", "LAST", "DIFF"
+    );
+    for step in &current.steps {
+        let last = previous
+            .get(&(step.stage.as_str(), step.name.as_str()))
+            .copied()
+            .flatten();
+        println!(
+            "{:>9}  {:<16}{:<40}{:>10}{:>10}{:>10}  {}",
+            format_ms(Some(step.start)),
+            step.stage,
+            step.name,
+            format_ms(step.duration),
+            format_ms(last),
+            format_diff(step.duration, last),
+            step.result
+        );
+    }
+}
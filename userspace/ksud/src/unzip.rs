@@ -0,0 +1,528 @@
+use anyhow::{bail, Context, Result};
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{Read, Seek};
+use std::path::{Component, Path, PathBuf};
+use zip::ZipArchive;
+
+use crate::{output, utils};
+
+/// At most this many entries in a module zip
+const MAX_ENTRIES: usize = 20_000;
+/// At most this many bytes extracted from a module zip, 2 GiB
+const MAX_SIZE: u64 = 2 << 30;
+
+const S_IFMT: u32 = 0o170000;
+const S_IFREG: u32 = 0o100000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFLNK: u32 = 0o120000;
+
+#[derive(Clone)]
+enum Kind {
+    Dir,
+    File,
+    Symlink(PathBuf),
+}
+
+#[derive(Clone)]
+struct Entry {
+    index: usize,
+    path: PathBuf,
+    kind: Kind,
+    /// permission bits, without setuid and setgid
+    mode: Option<u32>,
+}
+
+/// Entries of a zip which passed [`check`]
+pub struct Checked {
+    entries: Vec<Entry>,
+    /// uncompressed size in bytes
+    pub size: u64,
+}
+
+impl Checked {
+    /// Entries under the directory <prefix>, relative to it.
+    /// Symlinks must not point outside of it either.
+    pub fn subdir(&self, prefix: &str) -> Result<Checked> {
+        let mut entries = Vec::new();
+        for entry in &self.entries {
+            let Ok(path) = entry.path.strip_prefix(prefix) else {
+                continue;
+            };
+            if path.as_os_str().is_empty() {
+                continue;
+            }
+            if let Kind::Symlink(target) = &entry.kind {
+                let parent = path.parent().unwrap_or(Path::new(""));
+                if normalize(&parent.join(target)).is_none() {
+                    bail!("symlink {} points outside {prefix}", entry.path.display());
+                }
+            }
+            entries.push(Entry {
+                path: path.to_path_buf(),
+                ..entry.clone()
+            });
+        }
+        Ok(Checked {
+            entries,
+            size: self.size,
+        })
+    }
+}
+
+// resolve `.` and `..` without touching the filesystem, None if <path> leaves the root
+fn normalize(path: &Path) -> Option<PathBuf> {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => normalized.push(name),
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if !normalized.pop() {
+                    return None;
+                }
+            }
+            Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+    Some(normalized)
+}
+
+// the symlink of <symlinks> which <entry> or its target goes through, the checks above
+// resolve `..` lexically which is wrong after a symlink, e.g. `q -> d/l/..` with `d/l -> ..`
+fn through_symlink<'a>(entry: &Entry, symlinks: &'a HashSet<PathBuf>) -> Option<&'a PathBuf> {
+    if let Some(link) = entry.path.ancestors().skip(1).find_map(|p| symlinks.get(p)) {
+        return Some(link);
+    }
+    let Kind::Symlink(target) = &entry.kind else {
+        return None;
+    };
+    let mut path = entry.path.parent().unwrap_or(Path::new("")).to_path_buf();
+    let mut components = target.components().peekable();
+    while let Some(component) = components.next() {
+        match component {
+            Component::Normal(name) => path.push(name),
+            Component::ParentDir => {
+                path.pop();
+            }
+            _ => {}
+        }
+        // the target itself may be a symlink, it is checked on its own
+        if components.peek().is_some() {
+            if let Some(link) = symlinks.get(&path) {
+                return Some(link);
+            }
+        }
+    }
+    None
+}
+
+fn check_entry<R: Read + Seek>(archive: &mut ZipArchive<R>, index: usize) -> Result<Entry> {
+    let mut file = archive.by_index(index)?;
+    let name = file.name().to_string();
+    if name.contains('\0') {
+        bail!("contains NUL");
+    }
+    let raw = Path::new(&name);
+    if raw.is_absolute() {
+        bail!("absolute path");
+    }
+    if raw.components().any(|c| c == Component::ParentDir) {
+        bail!("contains '..'");
+    }
+    let Some(path) = normalize(raw).filter(|p| p.components().next().is_some()) else {
+        bail!("empty path");
+    };
+
+    let mode = file.unix_mode();
+    let kind = match mode.map(|m| m & S_IFMT) {
+        _ if file.is_dir() => Kind::Dir,
+        None | Some(0) | Some(S_IFREG) => Kind::File,
+        Some(S_IFDIR) => Kind::Dir,
+        Some(S_IFLNK) => {
+            let mut target = String::new();
+            file.read_to_string(&mut target)
+                .context("unreadable symlink target")?;
+            let target = PathBuf::from(target);
+            // relative to the directory of the link
+            let parent = path.parent().unwrap_or(Path::new(""));
+            if target.is_absolute() || normalize(&parent.join(&target)).is_none() {
+                bail!("symlink to {} points outside the module", target.display());
+            }
+            Kind::Symlink(target)
+        }
+        Some(0o020000) => bail!("character device"),
+        Some(0o060000) => bail!("block device"),
+        Some(0o010000) => bail!("fifo"),
+        Some(other) => bail!("unsupported file type {:o}", other),
+    };
+    Ok(Entry {
+        index,
+        path,
+        kind,
+        mode: mode.map(|m| m & 0o1777),
+    })
+}
+
+/// Check every entry of <archive>, all offending ones are reported before it fails
+pub fn check<R: Read + Seek>(archive: &mut ZipArchive<R>) -> Result<Checked> {
+    if archive.len() > MAX_ENTRIES {
+        bail!(
+            "zip has {} entries, at most {MAX_ENTRIES} are allowed",
+            archive.len()
+        );
+    }
+
+    let mut entries = Vec::with_capacity(archive.len());
+    let mut size: u64 = 0;
+    let mut rejected = 0;
+    for index in 0..archive.len() {
+        match check_entry(archive, index) {
+            Ok(entry) => entries.push(entry),
+            Err(e) => {
+                let name = archive
+                    .name_for_index(index)
+                    .unwrap_or_default()
+                    .to_string();
+                output::progress(format!("Rejected zip entry {name:?}: {e:#}"));
+                rejected += 1;
+            }
+        }
+        size = size.saturating_add(archive.by_index_raw(index).map_or(0, |f| f.size()));
+    }
+    let symlinks: HashSet<PathBuf> = entries
+        .iter()
+        .filter(|e| matches!(e.kind, Kind::Symlink(_)))
+        .map(|e| e.path.clone())
+        .collect();
+    for entry in &entries {
+        if let Some(link) = through_symlink(entry, &symlinks) {
+            output::progress(format!(
+                "Rejected zip entry {:?}: goes through symlink {}",
+                entry.path,
+                link.display()
+            ));
+            rejected += 1;
+        }
+    }
+    if rejected > 0 {
+        bail!("{rejected} unsafe entries in zip");
+    }
+    if size > MAX_SIZE {
+        bail!(
+            "zip extracts to {}, at most {} are allowed",
+            humansize::format_size(size, humansize::BINARY),
+            humansize::format_size(MAX_SIZE, humansize::BINARY)
+        );
+    }
+    Ok(Checked { entries, size })
+}
+
+// the directories leading to <path> must be real ones, a symlink could lead anywhere
+fn ensure_parent(root: &Path, path: &Path) -> Result<()> {
+    let mut dir = root.to_path_buf();
+    for component in path.parent().into_iter().flat_map(Path::components) {
+        dir.push(component);
+        match fs::symlink_metadata(&dir) {
+            Ok(metadata) if metadata.is_dir() => {}
+            Ok(_) => bail!("{} is not a directory", dir.display()),
+            Err(_) => fs::create_dir(&dir)?,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: Option<u32>) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    if let Some(mode) = mode {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_mode(_path: &Path, _mode: Option<u32>) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn symlink(target: &Path, link: &Path) -> Result<()> {
+    std::os::unix::fs::symlink(target, link)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn symlink(_target: &Path, _link: &Path) -> Result<()> {
+    unimplemented!()
+}
+
+/// Extract the checked entries of <archive> into <dest>, which should be empty.
+/// Symlinks are created last, so that no entry is written through them.
+pub fn extract<R: Read + Seek>(
+    archive: &mut ZipArchive<R>,
+    checked: &Checked,
+    dest: &Path,
+) -> Result<()> {
+    utils::ensure_dir_exists(dest)?;
+    // sizes in the headers may lie, count what is really written
+    let mut remaining = checked.size;
+    for entry in &checked.entries {
+        let path = dest.join(&entry.path);
+        ensure_parent(dest, &entry.path)
+            .with_context(|| format!("Failed to extract {}", entry.path.display()))?;
+        match entry.kind {
+            Kind::Dir => {
+                if fs::symlink_metadata(&path).is_err() {
+                    fs::create_dir(&path)?;
+                }
+                // a read-only directory would refuse its own entries
+                continue;
+            }
+            Kind::File => {
+                let mut file = archive.by_index(entry.index)?;
+                // never follow or overwrite what an earlier entry created
+                let mut out = File::options()
+                    .write(true)
+                    .create_new(true)
+                    .open(&path)
+                    .with_context(|| format!("Failed to create {}", path.display()))?;
+                let written = std::io::copy(&mut (&mut file).take(remaining + 1), &mut out)?;
+                if written > remaining {
+                    bail!("{} is larger than declared", entry.path.display());
+                }
+                remaining -= written;
+            }
+            Kind::Symlink(_) => continue,
+        }
+        set_mode(&path, entry.mode)?;
+    }
+
+    for entry in &checked.entries {
+        if let Kind::Symlink(target) = &entry.kind {
+            let path = dest.join(&entry.path);
+            ensure_parent(dest, &entry.path)?;
+            symlink(target, &path)
+                .with_context(|| format!("Failed to create symlink {}", path.display()))?;
+        }
+    }
+    for entry in &checked.entries {
+        if let Kind::Dir = entry.kind {
+            set_mode(&dest.join(&entry.path), entry.mode)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, Write};
+    use zip::write::SimpleFileOptions;
+    use zip::{CompressionMethod, ZipWriter};
+
+    type Writer = ZipWriter<Cursor<Vec<u8>>>;
+
+    fn options() -> SimpleFileOptions {
+        SimpleFileOptions::default().compression_method(CompressionMethod::Stored)
+    }
+
+    fn build(entries: impl FnOnce(&mut Writer)) -> Vec<u8> {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        entries(&mut zip);
+        zip.finish().unwrap().into_inner()
+    }
+
+    fn add_file(zip: &mut Writer, name: &str) {
+        zip.start_file(name, options()).unwrap();
+        zip.write_all(b"content").unwrap();
+    }
+
+    // rewrite the central directory header of <name>, what ZipWriter can't write
+    fn patch_header(zip: &mut [u8], name: &str, patch: impl Fn(&mut [u8])) {
+        let start = (0..zip.len() - 46)
+            .find(|&i| {
+                let name_len = u16::from_le_bytes([zip[i + 28], zip[i + 29]]) as usize;
+                zip[i..].starts_with(b"PK\x01\x02")
+                    && zip.get(i + 46..i + 46 + name_len) == Some(name.as_bytes())
+            })
+            .unwrap();
+        patch(&mut zip[start..start + 46]);
+    }
+
+    fn check_zip(zip: Vec<u8>) -> Result<Checked> {
+        check(&mut ZipArchive::new(Cursor::new(zip))?)
+    }
+
+    #[test]
+    fn accept_module() {
+        let zip = build(|zip| {
+            add_file(zip, "module.prop");
+            zip.add_directory("system/bin", options()).unwrap();
+            add_file(zip, "system/bin/tool");
+            zip.add_symlink("system/bin/alias", "tool", options())
+                .unwrap();
+            zip.add_symlink("system/lib/tool", "../bin/./tool", options())
+                .unwrap();
+        });
+        let checked = check_zip(zip).unwrap();
+        assert_eq!(checked.entries.len(), 5);
+        assert_eq!(checked.size, 2 * "content".len() as u64 + 4 + 13);
+        let system = checked.subdir("system").unwrap();
+        assert_eq!(system.entries[1].path, Path::new("bin/tool"));
+        // fine in the module, but leaves system/lib
+        assert!(checked.subdir("system/lib").is_err());
+    }
+
+    #[test]
+    fn reject_paths_outside() {
+        for name in ["../evil", "system/../../evil", "/system/bin/evil", "./"] {
+            let zip = build(|zip| {
+                add_file(zip, "module.prop");
+                add_file(zip, name);
+            });
+            assert!(check_zip(zip).is_err(), "{name} is accepted");
+        }
+    }
+
+    #[test]
+    fn reject_symlinks_outside() {
+        for target in ["/system/bin/sh", "../../..", "a/../../.."] {
+            let zip = build(|zip| zip.add_symlink("bin/link", target, options()).unwrap());
+            assert!(check_zip(zip).is_err(), "link to {target} is accepted");
+        }
+    }
+
+    #[test]
+    fn reject_chained_symlinks() {
+        let cases: [&[(&str, &str)]; 3] = [
+            &[("d1/l", ".."), ("q", "d1/l/..")],
+            &[("d1/l", ".."), ("d2/q", "../d1/l/../x")],
+            &[("l", "d"), ("l/q", "x")],
+        ];
+        for links in cases {
+            let zip = build(|zip| {
+                zip.add_directory("d", options()).unwrap();
+                for (link, target) in links {
+                    zip.add_symlink(*link, *target, options()).unwrap();
+                }
+            });
+            assert!(check_zip(zip).is_err(), "{links:?} is accepted");
+        }
+        // a link to a link is fine, the kernel resolves each from where it is
+        let zip = build(|zip| {
+            zip.add_symlink("d1/l", "..", options()).unwrap();
+            zip.add_symlink("q", "d1/l", options()).unwrap();
+        });
+        assert!(check_zip(zip).is_ok());
+    }
+
+    #[test]
+    fn reject_files_through_symlinks() {
+        let zip = build(|zip| {
+            zip.add_directory("d", options()).unwrap();
+            zip.add_symlink("l", "d", options()).unwrap();
+            add_file(zip, "l/x");
+        });
+        assert!(check_zip(zip).is_err());
+    }
+
+    #[test]
+    fn extract_module() {
+        let zip = build(|zip| {
+            add_file(zip, "module.prop");
+            zip.add_directory("system/bin", options().unix_permissions(0o750))
+                .unwrap();
+            zip.start_file("system/bin/tool", options().unix_permissions(0o4755))
+                .unwrap();
+            zip.write_all(b"#!/bin/sh\n").unwrap();
+            zip.add_symlink("system/bin/alias", "tool", options())
+                .unwrap();
+            zip.add_symlink("system/lib/tool", "../bin/tool", options())
+                .unwrap();
+        });
+        let mut archive = ZipArchive::new(Cursor::new(zip)).unwrap();
+        let checked = check(&mut archive).unwrap();
+        let dest = tempfile::tempdir().unwrap();
+        let dest = dest.path();
+        extract(&mut archive, &checked, dest).unwrap();
+
+        let mode = |path: &str| {
+            use std::os::unix::fs::PermissionsExt;
+            fs::symlink_metadata(dest.join(path))
+                .unwrap()
+                .permissions()
+                .mode()
+                & 0o7777
+        };
+        assert_eq!(
+            fs::read_to_string(dest.join("module.prop")).unwrap(),
+            "content"
+        );
+        assert_eq!(
+            fs::read_to_string(dest.join("system/lib/tool")).unwrap(),
+            "#!/bin/sh\n"
+        );
+        // setuid is dropped
+        assert_eq!(mode("system/bin/tool"), 0o755);
+        assert_eq!(mode("system/bin"), 0o750);
+        assert_eq!(
+            fs::read_link(dest.join("system/bin/alias")).unwrap(),
+            Path::new("tool")
+        );
+
+        let subdir = checked.subdir("system/bin").unwrap();
+        let bin = tempfile::tempdir().unwrap();
+        extract(&mut archive, &subdir, bin.path()).unwrap();
+        let mut names: Vec<_> = fs::read_dir(bin.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(names, ["alias", "tool"]);
+
+        // nothing is written into what exists already
+        assert!(extract(&mut archive, &checked, dest).is_err());
+    }
+
+    #[test]
+    fn reject_special_files() {
+        for file_type in [0o020000u32, 0o060000, 0o010000] {
+            let mut zip = build(|zip| add_file(zip, "dev"));
+            patch_header(&mut zip, "dev", |header| {
+                let mode = (file_type | 0o644) << 16;
+                header[38..42].copy_from_slice(&mode.to_le_bytes());
+            });
+            assert!(
+                check_zip(zip).is_err(),
+                "file type {file_type:o} is accepted"
+            );
+        }
+    }
+
+    #[test]
+    fn reject_too_many_entries() {
+        let zip = build(|zip| {
+            for i in 0..=MAX_ENTRIES {
+                zip.add_directory(format!("{i}"), options()).unwrap();
+            }
+        });
+        assert!(check_zip(zip).is_err());
+    }
+
+    #[test]
+    fn reject_too_large() {
+        let mut zip = build(|zip| {
+            add_file(zip, "a");
+            add_file(zip, "b");
+        });
+        // the declared sizes are counted, the data isn't read
+        for name in ["a", "b"] {
+            patch_header(&mut zip, name, |header| {
+                header[24..28].copy_from_slice(&(MAX_SIZE as u32 / 2 + 1).to_le_bytes());
+            });
+        }
+        let err = check_zip(zip).err().unwrap().to_string();
+        assert!(err.starts_with("zip extracts to"), "{err}");
+    }
+}
@@ -1,20 +1,19 @@
 use anyhow::{bail, Context, Error, Ok, Result};
 use std::{
-    fs::{create_dir_all, remove_file, write, File, OpenOptions},
-    io::{
-        ErrorKind::{AlreadyExists, NotFound},
-        Write,
-    },
+    fs::{create_dir_all, remove_file, write, File},
+    io::ErrorKind::{AlreadyExists, NotFound},
     path::Path,
     process::Command,
 };
 
-use crate::{assets, boot_patch, defs, ksucalls, module, restorecon};
+use crate::{assets, boot_patch, defs, ksucalls, module, output, restorecon};
 #[allow(unused_imports)]
 use std::fs::{set_permissions, Permissions};
 #[cfg(unix)]
 use std::os::unix::prelude::PermissionsExt;
 
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
 use std::path::PathBuf;
 
 #[cfg(any(target_os = "linux", target_os = "android"))]
@@ -58,6 +57,58 @@ pub fn ensure_dir_exists<T: AsRef<Path>>(dir: T) -> Result<()> {
     }
 }
 
+/// Space of the filesystem of <path> in bytes, (available, total)
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn disk_space(path: &Path) -> Result<(u64, u64)> {
+    let stat = rustix::fs::statvfs(path)
+        .with_context(|| format!("Failed to statvfs {}", path.display()))?;
+    Ok((stat.f_bavail * stat.f_frsize, stat.f_blocks * stat.f_frsize))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn disk_space(_path: &Path) -> Result<(u64, u64)> {
+    unimplemented!()
+}
+
+/// Size of the files under <dir>, symlinks are not followed
+pub fn dir_size(dir: &Path) -> u64 {
+    jwalk::WalkDir::new(dir)
+        .parallelism(jwalk::Parallelism::Serial)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.metadata().ok())
+        .filter(|metadata| metadata.is_file())
+        .map(|metadata| metadata.len())
+        .sum()
+}
+
+/// Move <log> away before a new one is written, keeping <keep> previous logs
+/// as <name>.old.log, <name>.old.2.log and so on.
+pub fn rotate_log(log: &Path, keep: u32) -> Result<()> {
+    let old = |n: u32| match n {
+        1 => log.with_extension("old.log"),
+        n => log.with_extension(format!("old.{n}.log")),
+    };
+    if keep == 0 {
+        if log.exists() {
+            std::fs::remove_file(log)?;
+        }
+        return Ok(());
+    }
+
+    // drop the oldest one, then shift the others
+    let _ = std::fs::remove_file(old(keep));
+    for n in (1..keep).rev() {
+        if old(n).exists() {
+            std::fs::rename(old(n), old(n + 1))?;
+        }
+    }
+    if log.exists() {
+        std::fs::rename(log, old(1))?;
+    }
+    Ok(())
+}
+
 pub fn ensure_binary<T: AsRef<Path>>(
     path: T,
     contents: &[u8],
@@ -113,14 +164,6 @@ pub fn is_safe_mode() -> bool {
     safemode
 }
 
-pub fn get_zip_uncompressed_size(zip_path: &str) -> Result<u64> {
-    let mut zip = zip::ZipArchive::new(std::fs::File::open(zip_path)?)?;
-    let total: u64 = (0..zip.len())
-        .map(|i| zip.by_index(i).unwrap().size())
-        .sum();
-    Ok(total)
-}
-
 #[cfg(any(target_os = "linux", target_os = "android"))]
 pub fn switch_mnt_ns(pid: i32) -> Result<()> {
     use rustix::{
@@ -143,29 +186,67 @@ pub fn unshare_mnt_ns() -> Result<()> {
     Ok(())
 }
 
-fn switch_cgroup(grp: &str, pid: u32) {
-    let path = Path::new(grp).join("cgroup.procs");
-    if !path.exists() {
-        return;
+/// A write to a file which is prepared before fork and done between fork and exec.
+/// A multithreaded process may only make async-signal-safe calls there, no allocations.
+pub struct PreparedWrite {
+    path: CString,
+    data: Vec<u8>,
+}
+
+impl PreparedWrite {
+    pub fn new(path: &Path, data: &[u8]) -> std::io::Result<Self> {
+        std::result::Result::Ok(Self {
+            path: CString::new(path.as_os_str().as_bytes())?,
+            data: data.to_vec(),
+        })
     }
 
-    let fp = OpenOptions::new().append(true).open(path);
-    if let std::result::Result::Ok(mut fp) = fp {
-        let _ = writeln!(fp, "{pid}");
+    /// Nothing but open(2), write(2) and close(2)
+    pub fn run(&self) -> std::io::Result<()> {
+        let fd = unsafe { libc::open(self.path.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC) };
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let written = unsafe { libc::write(fd, self.data.as_ptr().cast(), self.data.len()) };
+        let result = if written < 0 {
+            Err(std::io::Error::last_os_error())
+        } else if written as usize != self.data.len() {
+            Err(std::io::ErrorKind::WriteZero.into())
+        } else {
+            std::result::Result::Ok(())
+        };
+        unsafe { libc::close(fd) };
+        result
     }
 }
 
-pub fn switch_cgroups() {
-    let pid = std::process::id();
-    switch_cgroup("/acct", pid);
-    switch_cgroup("/dev/cg2_bpf", pid);
-    switch_cgroup("/sys/fs/cgroup", pid);
+/// Moves a child out of the cgroups of ksud, see [`CgroupSwitch::run`]
+pub struct CgroupSwitch(Vec<PreparedWrite>);
+
+impl CgroupSwitch {
+    pub fn prepare() -> Self {
+        let mut groups = vec!["/acct", "/dev/cg2_bpf", "/sys/fs/cgroup"];
+        if getprop("ro.config.per_app_memcg")
+            .filter(|prop| prop == "false")
+            .is_none()
+        {
+            groups.push("/dev/memcg/apps");
+        }
+        let writes = groups
+            .into_iter()
+            .map(|group| Path::new(group).join("cgroup.procs"))
+            .filter(|procs| procs.exists())
+            // 0 is the writing process, the pid of the child is unknown before fork
+            .filter_map(|procs| PreparedWrite::new(&procs, b"0").ok())
+            .collect();
+        Self(writes)
+    }
 
-    if getprop("ro.config.per_app_memcg")
-        .filter(|prop| prop == "false")
-        .is_none()
-    {
-        switch_cgroup("/dev/memcg/apps", pid);
+    /// Called between fork and exec, errors are ignored
+    pub fn run(&self) {
+        for write in &self.0 {
+            let _ = write.run();
+        }
     }
 }
 
@@ -185,8 +266,8 @@ pub fn has_magisk() -> bool {
 
 #[cfg(target_os = "android")]
 fn link_ksud_to_bin() -> Result<()> {
-    let ksu_bin = PathBuf::from(defs::DAEMON_PATH);
-    let ksu_bin_link = PathBuf::from(defs::DAEMON_LINK_PATH);
+    let ksu_bin = defs::resolve(defs::DAEMON_PATH);
+    let ksu_bin_link = defs::resolve(defs::DAEMON_LINK_PATH);
     if ksu_bin.exists() && !ksu_bin_link.exists() {
         std::os::unix::fs::symlink(&ksu_bin, &ksu_bin_link)?;
     }
@@ -194,9 +275,10 @@ fn link_ksud_to_bin() -> Result<()> {
 }
 
 pub fn install(magiskboot: Option<PathBuf>) -> Result<()> {
-    ensure_dir_exists(defs::ADB_DIR)?;
-    std::fs::copy("/proc/self/exe", defs::DAEMON_PATH)?;
-    restorecon::lsetfilecon(defs::DAEMON_PATH, restorecon::ADB_CON)?;
+    let daemon_path = defs::resolve(defs::DAEMON_PATH);
+    ensure_dir_exists(defs::resolve(defs::ADB_DIR))?;
+    std::fs::copy("/proc/self/exe", &daemon_path)?;
+    restorecon::lsetfilecon(&daemon_path, restorecon::ADB_CON)?;
     // install binary assets
     assets::ensure_binaries(false).with_context(|| "Failed to extract assets")?;
 
@@ -204,31 +286,66 @@ pub fn install(magiskboot: Option<PathBuf>) -> Result<()> {
     link_ksud_to_bin()?;
 
     if let Some(magiskboot) = magiskboot {
-        ensure_dir_exists(defs::BINARY_DIR)?;
-        let _ = std::fs::copy(magiskboot, defs::MAGISKBOOT_PATH);
+        ensure_dir_exists(defs::resolve(defs::BINARY_DIR))?;
+        let _ = std::fs::copy(magiskboot, defs::resolve(defs::MAGISKBOOT_PATH));
     }
 
     Ok(())
 }
 
 pub fn uninstall(magiskboot_path: Option<PathBuf>) -> Result<()> {
-    if Path::new(defs::MODULE_DIR).exists() {
-        println!("- Uninstall modules..");
+    if defs::resolve(defs::MODULE_DIR).exists() {
+        output::progress("Uninstall modules..");
         module::uninstall_all_modules()?;
         module::prune_modules()?;
     }
-    println!("- Removing directories..");
-    std::fs::remove_dir_all(defs::WORKING_DIR).ok();
-    std::fs::remove_file(defs::DAEMON_PATH).ok();
-    std::fs::remove_dir_all(defs::MODULE_DIR).ok();
-    println!("- Restore boot image..");
+    output::progress("Removing directories..");
+    std::fs::remove_dir_all(defs::resolve(defs::WORKING_DIR)).ok();
+    std::fs::remove_file(defs::resolve(defs::DAEMON_PATH)).ok();
+    std::fs::remove_dir_all(defs::resolve(defs::MODULE_DIR)).ok();
+    if defs::has_custom_root() {
+        // the rest acts on the running device, not on the relocated tree
+        output::progress("Skip restoring boot image and rebooting with a custom root");
+        return Ok(());
+    }
+    output::progress("Restore boot image..");
     boot_patch::restore(None, magiskboot_path, true)?;
-    println!("- Uninstall KernelSU manager..");
+    output::progress("Uninstall KernelSU manager..");
     Command::new("pm")
         .args(["uninstall", "me.weishu.kernelsu"])
         .spawn()?;
-    println!("- Rebooting in 5 seconds..");
+    output::progress("Rebooting in 5 seconds..");
     std::thread::sleep(std::time::Duration::from_secs(5));
     Command::new("reboot").spawn()?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::process::CommandExt;
+
+    #[test]
+    fn prepared_write_between_fork_and_exec() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("cgroup.procs");
+        std::fs::write(&file, "").unwrap();
+        let prepared = PreparedWrite::new(&file, b"0").unwrap();
+        let status = unsafe {
+            Command::new("true")
+                .pre_exec(move || prepared.run())
+                .status()
+        };
+        assert!(status.unwrap().success());
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), "0");
+
+        // the spawn fails with the error of the write
+        let prepared = PreparedWrite::new(&dir.path().join("missing/cgroup.procs"), b"0").unwrap();
+        let status = unsafe {
+            Command::new("true")
+                .pre_exec(move || prepared.run())
+                .status()
+        };
+        assert_eq!(status.unwrap_err().kind(), NotFound);
+    }
+}